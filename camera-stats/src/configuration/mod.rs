@@ -2,26 +2,71 @@
 // that need to be read and/or written to by this application.
 use std::{path::PathBuf, str::FromStr};
 
-use common_utils::date_utils::DateStamp;
+use common_utils::{date_utils::DateStamp, report_utils::ReportFormat};
 
-pub(crate) struct DailyCameraBestAvgPicsFilesConfig {}
+use crate::ranking::SessionMetric;
+
+/// Configuration describing filenames of the files containing the top 100 average number of pics by each camera
+/// over the last seven days.
+pub struct DailyCameraBestAvgPicsFilesConfig {}
 
 impl DailyCameraBestAvgPicsFilesConfig {
-    pub(crate) const FILE_PREFIX: &'static str = "camera_top100_";
-    pub(crate) const FILE_EXTENSION: &'static str = ".txt";
+    pub const FILE_PREFIX: &'static str = "camera_top100_";
+    pub const FILE_EXTENSION: &'static str = ".txt";
+    pub const JSONL_FILE_EXTENSION: &'static str = ".jsonl";
+    pub const PARQUET_FILE_EXTENSION: &'static str = ".parquet";
+}
+
+/// Configuration describing filenames of the files containing summary metrics (trips, sessions, pics) of each camera
+/// over the last seven days.
+pub struct DailyCameraSummaryFilesConfig;
+
+impl DailyCameraSummaryFilesConfig {
+    pub const FILE_PREFIX: &'static str = "camera_summary_";
+    pub const FILE_EXTENSION: &'static str = ".txt";
+    pub const JSONL_FILE_EXTENSION: &'static str = ".jsonl";
+    pub const PARQUET_FILE_EXTENSION: &'static str = ".parquet";
+}
+
+/// Configuration describing filenames of the files containing the top sessions with the most pics over all participants
+/// over the last seven days. The number of sessions (see --top-sessions) is part of the filename: session_top_K_YYYYMMDD.txt
+pub struct DailySessionTopFilesConfig;
+
+impl DailySessionTopFilesConfig {
+    pub const FILE_PREFIX: &'static str = "session_top_";
+    pub const FILE_EXTENSION: &'static str = ".txt";
+    pub const JSONL_FILE_EXTENSION: &'static str = ".jsonl";
+    pub const PARQUET_FILE_EXTENSION: &'static str = ".parquet";
+}
+
+/// Configuration describing filenames of the run manifests, which describe the inputs and outputs of a run (see common_utils::manifest).
+pub struct RunManifestFilesConfig;
+
+impl RunManifestFilesConfig {
+    pub const FILE_PREFIX: &'static str = "camera_stats_manifest_";
+    pub const FILE_EXTENSION: &'static str = ".json";
 }
 
-/// Configuration for serialization of the top 100 average number of pics per camera on a given date.
+/// Configuration for serialization of the top 100 average number of pics and the summary metrics per camera on a given date.
 pub struct SerializationFilesConfig;
 
 impl SerializationFilesConfig {
     /// The path to the directory where we serialize daily camera and user stats.
     /// The files in this directory are not supposed to be viewed by anyone or anything apart from this program.
-    /// The exception is files dating more than seven days back. One could/should set up a cronjob that deletes those.
+    /// The exception is files dating more than seven days back, which can be removed with `stats-admin gc`.
     pub const SERIALIZATION_DIRECTORY_PATH: &'static str = "./serialized_camera_stats";
 
-    /// The prefix for the serialized camera stats. Their suffix will be a date of the form YYYYMMDD.
-    pub const SERIALIZATION_OPERATOR_PREFIX: &'static str = "camera-top-100-pics-average-";
+    /// The prefix for the serialized camera stats, i.e. the best sessions of every camera for each number of trips
+    /// from which the top 100 are ranked. It is followed by the metric (see --metric) and the minimum number of trips
+    /// (see --min-trips) the stats were computed with, i.e. "avg-min-trips-N-", and their suffix will be a date of the form YYYYMMDD.
+    pub const SERIALIZATION_OPERATOR_PREFIX: &'static str = "camera-top-100-candidates-v3-";
+
+    /// The prefix for the serialized camera summaries. Their suffix will be a date of the form YYYYMMDD.
+    pub const SUMMARY_SERIALIZATION_PREFIX: &'static str = "camera-summary-";
+
+    /// The prefix for the serialized top sessions. It is followed by the number of sessions (see --top-sessions), i.e. "K-",
+    /// and their suffix will be a date of the form YYYYMMDD.
+    pub const SESSIONS_SERIALIZATION_PREFIX: &'static str = "session-top-v2-";
 
     /// The path to the directory where serialized camera stats are stored
     pub fn serialization_directory() -> PathBuf {
@@ -29,33 +74,104 @@ impl SerializationFilesConfig {
     }
 
     // Returns the path to the serialized camera stats file corresponding to the given date: (YYYYMMDD)
-    pub(crate) fn serialization_file_from_datestamp(datestamp: DateStamp) -> PathBuf {
+    // metric and minimum number of trips. Stats computed with a different metric or minimum number of trips are never reused.
+    pub(crate) fn serialization_file_from_datestamp(
+        datestamp: DateStamp,
+        min_trips: u32,
+        metric: SessionMetric,
+    ) -> PathBuf {
         let date_ymd = datestamp.into_string();
         let mut path = SerializationFilesConfig::serialization_directory();
         let mut serialization_filename =
             SerializationFilesConfig::SERIALIZATION_OPERATOR_PREFIX.to_string();
+        serialization_filename.push_str(format!("{}-min-trips-{}-", metric, min_trips).as_str());
         serialization_filename.push_str(date_ymd.as_str());
         path.push(serialization_filename);
         path
     }
+    // Returns the path to the serialized camera summaries file corresponding to the given date: (YYYYMMDD)
+    pub(crate) fn summary_serialization_file_from_datestamp(datestamp: DateStamp) -> PathBuf {
+        let mut path = SerializationFilesConfig::serialization_directory();
+        path.push(format!(
+            "{}{}",
+            SerializationFilesConfig::SUMMARY_SERIALIZATION_PREFIX,
+            datestamp.into_string()
+        ));
+        path
+    }
+    // Returns the path to the serialized top sessions file corresponding to the given date: (YYYYMMDD) and number of sessions.
+    pub(crate) fn sessions_serialization_file_from_datestamp(
+        datestamp: DateStamp,
+        top_sessions: usize,
+    ) -> PathBuf {
+        let mut path = SerializationFilesConfig::serialization_directory();
+        path.push(format!(
+            "{}{}-{}",
+            SerializationFilesConfig::SESSIONS_SERIALIZATION_PREFIX,
+            top_sessions,
+            datestamp.into_string()
+        ));
+        path
+    }
+    // Whether the session log file of the given date has been processed, i.e. its top 100, its summaries and its top sessions have been serialized.
+    pub(crate) fn is_processed(
+        datestamp: DateStamp,
+        min_trips: u32,
+        metric: SessionMetric,
+        top_sessions: usize,
+    ) -> bool {
+        Self::serialization_file_from_datestamp(datestamp.clone(), min_trips, metric).exists()
+            && Self::summary_serialization_file_from_datestamp(datestamp.clone()).exists()
+            && Self::sessions_serialization_file_from_datestamp(datestamp, top_sessions).exists()
+    }
     // Returns a vector of file paths for the serialized camera stats files for the last seven days.
-    pub(crate) fn serialized_file_paths_last_seven_days() -> Vec<PathBuf> {
+    pub(crate) fn serialized_file_paths_last_seven_days(
+        min_trips: u32,
+        metric: SessionMetric,
+    ) -> Vec<PathBuf> {
+        common_utils::date_utils::last_seven_days_ymd()
+            .into_iter()
+            .map(|datestamp| Self::serialization_file_from_datestamp(datestamp, min_trips, metric))
+            .collect()
+    }
+    // Returns a vector of file paths for the serialized camera summaries files for the last seven days.
+    pub(crate) fn serialized_summary_file_paths_last_seven_days() -> Vec<PathBuf> {
+        common_utils::date_utils::last_seven_days_ymd()
+            .into_iter()
+            .map(Self::summary_serialization_file_from_datestamp)
+            .collect()
+    }
+    // Returns a vector of file paths for the serialized top sessions files for the last seven days.
+    pub(crate) fn serialized_sessions_file_paths_last_seven_days(
+        top_sessions: usize,
+    ) -> Vec<PathBuf> {
         common_utils::date_utils::last_seven_days_ymd()
             .into_iter()
-            .map(Self::serialization_file_from_datestamp)
+            .map(|datestamp| {
+                Self::sessions_serialization_file_from_datestamp(datestamp, top_sessions)
+            })
             .collect()
     }
 }
 
 // The path for todays camera stats file.
 // The contents of this file should be the top 100 average pics by each camera over a seven day period.
-pub(crate) fn todays_camera_stats_file_path(out_directory: PathBuf) -> PathBuf {
+// The file extension depends on the format the file is written in.
+pub(crate) fn todays_camera_stats_file_path(
+    out_directory: PathBuf,
+    report_format: ReportFormat,
+) -> PathBuf {
     let today_ymd = common_utils::date_utils::today_ymd().into_string();
     let mut todays_camera_stats_path = out_directory;
+    let file_extension = match report_format {
+        ReportFormat::Pipe => DailyCameraBestAvgPicsFilesConfig::FILE_EXTENSION,
+        ReportFormat::JsonLines => DailyCameraBestAvgPicsFilesConfig::JSONL_FILE_EXTENSION,
+        ReportFormat::Parquet => DailyCameraBestAvgPicsFilesConfig::PARQUET_FILE_EXTENSION,
+    };
     let filename: String = [
         DailyCameraBestAvgPicsFilesConfig::FILE_PREFIX,
         today_ymd.as_str(),
-        DailyCameraBestAvgPicsFilesConfig::FILE_EXTENSION,
+        file_extension,
     ]
     .iter()
     .flat_map(|s| s.chars())
@@ -63,3 +179,63 @@ pub(crate) fn todays_camera_stats_file_path(out_directory: PathBuf) -> PathBuf {
     todays_camera_stats_path.push(filename);
     todays_camera_stats_path
 }
+
+// The path for todays camera summary file.
+// The contents of this file should be the summary metrics of each camera over a seven day period.
+// The file extension depends on the format the file is written in.
+pub(crate) fn todays_camera_summary_file_path(
+    out_directory: PathBuf,
+    report_format: ReportFormat,
+) -> PathBuf {
+    let today_ymd = common_utils::date_utils::today_ymd().into_string();
+    let file_extension = match report_format {
+        ReportFormat::Pipe => DailyCameraSummaryFilesConfig::FILE_EXTENSION,
+        ReportFormat::JsonLines => DailyCameraSummaryFilesConfig::JSONL_FILE_EXTENSION,
+        ReportFormat::Parquet => DailyCameraSummaryFilesConfig::PARQUET_FILE_EXTENSION,
+    };
+    let mut todays_camera_summary_path = out_directory;
+    todays_camera_summary_path.push(format!(
+        "{}{}{}",
+        DailyCameraSummaryFilesConfig::FILE_PREFIX,
+        today_ymd,
+        file_extension
+    ));
+    todays_camera_summary_path
+}
+
+// The path for todays top sessions file.
+// The contents of this file should be the top_sessions sessions with the most pics over a seven day period.
+// The file extension depends on the format the file is written in.
+pub(crate) fn todays_session_top_file_path(
+    out_directory: PathBuf,
+    report_format: ReportFormat,
+    top_sessions: usize,
+) -> PathBuf {
+    let today_ymd = common_utils::date_utils::today_ymd().into_string();
+    let file_extension = match report_format {
+        ReportFormat::Pipe => DailySessionTopFilesConfig::FILE_EXTENSION,
+        ReportFormat::JsonLines => DailySessionTopFilesConfig::JSONL_FILE_EXTENSION,
+        ReportFormat::Parquet => DailySessionTopFilesConfig::PARQUET_FILE_EXTENSION,
+    };
+    let mut todays_session_top_path = out_directory;
+    todays_session_top_path.push(format!(
+        "{}{}_{}{}",
+        DailySessionTopFilesConfig::FILE_PREFIX,
+        top_sessions,
+        today_ymd,
+        file_extension
+    ));
+    todays_session_top_path
+}
+
+// The path for todays run manifest.
+pub(crate) fn todays_manifest_file_path(out_directory: PathBuf) -> PathBuf {
+    let mut path = out_directory;
+    path.push(format!(
+        "{}{}{}",
+        RunManifestFilesConfig::FILE_PREFIX,
+        common_utils::date_utils::today_ymd().into_string(),
+        RunManifestFilesConfig::FILE_EXTENSION
+    ));
+    path
+}
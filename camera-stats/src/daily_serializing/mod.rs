@@ -1,5 +1,5 @@
 // This module handles saving (serializing) and loading (deserializing) of the
-// extracted best 100 average number of pics by cameras from the daily session log files.
+// extracted best 100 average number of pics and summary metrics by cameras from the daily session log files.
 
 use std::{
     fs::File,
@@ -8,45 +8,47 @@ use std::{
 };
 
 use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
 
-use crate::extracting::CameraBestAvgPicsMapping;
-
-// Serializes CameraBestAvgPicsMapping to disk.
+// Serializes a daily mapping (a CameraBestAvgPicsMapping or a CameraSummaryMapping) to disk.
 // If the given path does not exist we will attempt to create it.
-pub(crate) fn serialize_camera_best_avg_pics_mapping_to_disk(
+pub(crate) fn serialize_daily_mapping_to_disk<M: Serialize>(
     path: PathBuf,
-    camera_best_avg_pics: &CameraBestAvgPicsMapping,
+    mapping: &M,
 ) -> Result<()> {
     let file = std::fs::File::create(path.as_path())
         .with_context(|| format!("Failed to create the file: {:?}", &path.as_os_str()))?;
     const SERIALIZATION_WRITER_CAPACITY: usize = 150_000; // This should be more than enough for the mapping to fit in the buffer.
     let mut writer = BufWriter::with_capacity(SERIALIZATION_WRITER_CAPACITY, file);
-    bincode::serialize_into(&mut writer, &camera_best_avg_pics)
-        .with_context(|| "failed to serialize the CameraBestAvgPicsMapping".to_string())?;
+    bincode::serialize_into(&mut writer, mapping)
+        .with_context(|| format!("failed to serialize the {}", std::any::type_name::<M>()))?;
     writer.flush().with_context(|| {
-        "failed to write all the serialized CameraBestAvgPicsMapping bytes to disk"
+        format!(
+            "failed to write all the serialized {} bytes to disk",
+            std::any::type_name::<M>()
+        )
     })?;
     Ok(())
 }
 
-// transforms paths to serialized CameraBestAvgPicsMapings to their respective deserialized structs.
-pub(crate) fn deserialize_camera_best_avg_pics_mappings_from_files(
+// transforms paths to serialized daily mappings to their respective deserialized structs.
+pub(crate) fn deserialize_daily_mappings_from_files<M: DeserializeOwned>(
     // vector of paths to files
     paths: Vec<PathBuf>,
-) -> Result<Vec<CameraBestAvgPicsMapping>> {
-    let mut camera_best_avg_pic_mappings_previous_six_days: Vec<CameraBestAvgPicsMapping> =
-        Vec::new();
+) -> Result<Vec<M>> {
+    let mut mappings_previous_six_days: Vec<M> = Vec::new();
     for path in paths {
         let file = File::open(path.as_path())
             .with_context(|| format!("Failed to open file: {:?}", path.as_path().as_os_str()))?;
         let reader = BufReader::with_capacity(150_000, file);
-        let camera_best_avg_pics = bincode::deserialize_from(reader).with_context(|| {
+        let mapping = bincode::deserialize_from(reader).with_context(|| {
             format!(
-                "Failed to deserialize: {:?} into a CameraBestAvgPicsMapping",
-                path.as_path().as_os_str()
+                "Failed to deserialize: {:?} into a {}",
+                path.as_path().as_os_str(),
+                std::any::type_name::<M>()
             )
         })?;
-        camera_best_avg_pic_mappings_previous_six_days.push(camera_best_avg_pics);
+        mappings_previous_six_days.push(mapping);
     }
-    Ok(camera_best_avg_pic_mappings_previous_six_days)
+    Ok(mappings_previous_six_days)
 }
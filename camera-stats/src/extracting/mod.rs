@@ -2,25 +2,61 @@
 use anyhow::{Context, Result};
 use uuid::Uuid;
 
-use crate::{parsing::CameraRecord, sorting::SortedCameraRecordsIter};
-use common_utils::parsing_utils::Record;
+use crate::{
+    parsing::CameraRecord,
+    ranking::SessionMetric,
+    sessions::{SessionStats, SessionStatsAccumulator, TopSessions},
+    summarizing::CameraSummaryMapping,
+};
+use common_utils::{
+    manifest::SourceDigest,
+    metrics::RunMetrics,
+    parsing_utils::{BoundarySessionsTracker, Record, RecordValidator},
+};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 use std::{
     path::Path,
-    {cmp::Ordering, collections::HashMap, ops::AddAssign},
+    time::{Duration, Instant},
+    {
+        collections::{BTreeMap, HashMap, HashSet},
+        ops::AddAssign,
+    },
 };
 
+// The sessions extracted from a session log, see extract_camera_stats.
+#[derive(Clone, Copy)]
+pub(crate) struct SessionSelection {
+    pub(crate) min_trips: u32,
+    pub(crate) metric: SessionMetric,
+    pub(crate) top_sessions: usize,
+}
+
 // Extracts a mapping taking each camera to the top 100 average number of pics in sessions
-// found in the provided session log file.
-pub(crate) fn extract_top_100_sessions_for_cameras<P: AsRef<Path>>(
+// found in the provided session log file, together with a mapping taking each camera to its summary metrics
+// and the top_sessions sessions with the most pics over all participants.
+// Only sessions in which the camera was rented at least min_trips times are considered for the top 100,
+// and the sessions are selected by the given metric. All of these are taken from the given selection.
+// Sessions near the start or the end of the log may cross midnight, hence only their partial stats are extracted
+// so that they can be stitched together with the other part of the session when the days are merged.
+// Records are skipped if the given validator says so, the caller is responsible for finishing the validator.
+// The bytes of the log are added to the given digest while it is parsed. The time spent parsing, sorting and extracting as well as
+// the size of the sorted segments written to disk are added to the given metrics.
+// The sorter keeps up to num_items_in_sorter_memory_buffer records in memory before it writes a sorted segment to disk.
+pub(crate) fn extract_camera_stats<P: AsRef<Path>>(
     session_log_file_path: P,
-) -> Result<CameraBestAvgPicsMapping> {
+    selection: SessionSelection,
+    num_items_in_sorter_memory_buffer: usize,
+    record_validator: &mut RecordValidator,
+    source_digest: &SourceDigest,
+    metrics: &mut RunMetrics,
+) -> Result<(CameraBestAvgPicsMapping, CameraSummaryMapping, TopSessions)> {
     const BUFFER_CAPACITY: usize = 8 * 2usize.pow(10);
-    let reader = common_utils::parsing_utils::customised_csv_reader(
+    let reader = common_utils::parsing_utils::customised_csv_reader_with_digest(
         session_log_file_path.as_ref(),
         BUFFER_CAPACITY,
+        source_digest,
     )
     .with_context(|| {
         format!(
@@ -28,69 +64,259 @@ pub(crate) fn extract_top_100_sessions_for_cameras<P: AsRef<Path>>(
             session_log_file_path.as_ref().as_os_str()
         )
     })?;
+    let mut boundary_sessions_tracker =
+        BoundarySessionsTracker::new(BoundarySessionsTracker::DEFAULT_WINDOW);
+    let mut records = 0usize;
     let records_iter = reader
         .into_deserialize::<Record>()
-        .filter_map(Result::ok)
-        .map_into::<CameraRecord>();
+        .filter_map(|parsed| record_validator.validate_parsed(parsed))
+        .map_into::<CameraRecord>()
+        .inspect(|record| {
+            records += 1;
+            boundary_sessions_tracker.observe(record.session_id)
+        })
+        // stop parsing once cancellation has been requested, the cancellation is reported after sorting.
+        .take_while(|_| !common_utils::cancellation::is_requested());
+    let mut parse_time = Duration::ZERO;
+    let records_iter = TimedChunks::new(records_iter, &mut parse_time);
 
-    const NUM_ITEMS_IN_SORTER_MEMORY_BUFFER: usize = 50_000_000;
-    println!("sorting camera records");
+    // The records are parsed while they are sorted, hence parsing is part of this stage.
+    let sort_span = tracing::info_span!("sort", records = tracing::field::Empty).entered();
+    let sort_started = Instant::now();
     let sorted_iter =
-        crate::sorting::sort_camera_records(records_iter, NUM_ITEMS_IN_SORTER_MEMORY_BUFFER)
+        crate::sorting::sort_camera_records(records_iter, num_items_in_sorter_memory_buffer)
         .with_context(|| {
             format!("Could not extract the top 100 average number of pics per camera from: {:?} because sorting of the records failed.", session_log_file_path.as_ref().as_os_str())
         })?;
-    println!("sorting completed");
+    common_utils::cancellation::check()?;
+    sort_span.record("records", records);
+    drop(sort_span);
+    metrics.add_stage_duration("parse", parse_time);
+    metrics.add_stage_duration("sort", sort_started.elapsed().saturating_sub(parse_time));
+    metrics.temporary_bytes_written += sorted_iter.spilled_bytes();
+    let boundary_sessions = boundary_sessions_tracker.finish();
+
+    let extract_span = tracing::info_span!(
+        "extract",
+        boundary_sessions = boundary_sessions.len(),
+        cameras = tracing::field::Empty
+    )
+    .entered();
+    let extract_started = Instant::now();
+    // The sorted segments are merged while the stats are extracted, the number of merged records is reported on a progress bar.
+    let sorted_iter = common_utils::progress::items(records as u64, "merge sorted records")
+        .wrap_iter(sorted_iter);
+    let camera_stats = crate::extracting::camera_stats_from_sorted_iterator(
+        sorted_iter.take_while(|_| !common_utils::cancellation::is_requested()),
+        selection.min_trips,
+        selection.metric,
+        selection.top_sessions,
+        &boundary_sessions,
+    );
+    common_utils::cancellation::check()?;
+    extract_span.record("cameras", camera_stats.0.mapper.len());
+    metrics.add_stage_duration("extract", extract_started.elapsed());
+    Ok(camera_stats)
+}
+
+// Parses the records in chunks ahead of the sorter, so that the time spent parsing can be told apart from the time spent sorting
+// without reading the clock for every record.
+struct TimedChunks<'a, I: Iterator> {
+    records: I,
+    chunk: std::vec::IntoIter<I::Item>,
+    parse_time: &'a mut Duration,
+}
 
-    println!("finding top 100 average pics in sessions for each camera");
-    Ok(crate::extracting::camera_best_hundred_mapping_from_sorted_iterator(sorted_iter))
+impl<'a, I: Iterator> TimedChunks<'a, I> {
+    const CHUNK_SIZE: usize = 1 << 16;
+
+    fn new(records: I, parse_time: &'a mut Duration) -> Self {
+        Self {
+            records,
+            chunk: Vec::new().into_iter(),
+            parse_time,
+        }
+    }
 }
 
-// produces a map of present camera ids to their top 100 average number of pics in sessions found in the sorted iterator.
-pub(crate) fn camera_best_hundred_mapping_from_sorted_iterator<
-    F: Fn(&CameraRecord, &CameraRecord) -> Ordering + Send + Sync,
->(
-    sorted_iter: SortedCameraRecordsIter<F>,
-) -> CameraBestAvgPicsMapping {
+impl<'a, I: Iterator> Iterator for TimedChunks<'a, I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(record) = self.chunk.next() {
+            return Some(record);
+        }
+        let parse_started = Instant::now();
+        let chunk: Vec<_> = self.records.by_ref().take(Self::CHUNK_SIZE).collect();
+        *self.parse_time += parse_started.elapsed();
+        self.chunk = chunk.into_iter();
+        self.chunk.next()
+    }
+}
+
+// produces a map of present camera ids to the candidates for their top 100 sessions found in the sorted iterator,
+// a map of present camera ids to their summary metrics and the top_sessions sessions with the most pics.
+// All of these are computed in a single pass over the iterator.
+// A session only competes for the top 100 of a camera if the camera was rented at least min_trips times in it, as otherwise
+// a single lucky trip would easily beat cameras that were consistently used throughout a session.
+// The scores of every (session, camera) group are computed in a single fold, and the metric decides which of them the
+// candidates are selected by.
+// The boundary sessions may continue in the log of another day. Their scores and stats are kept as partials instead,
+// which are combined with the partials of the other days in merge_camera_best_avg_pics and merge_top_sessions.
+pub(crate) fn camera_stats_from_sorted_iterator<I: Iterator<Item = CameraRecord>>(
+    sorted_iter: I,
+    min_trips: u32,
+    metric: SessionMetric,
+    top_sessions: usize,
+    boundary_sessions: &HashSet<Uuid>,
+) -> (CameraBestAvgPicsMapping, CameraSummaryMapping, TopSessions) {
     let number_of_cameras = u8::MAX; // This is likely more than the actual number of cameras.
-    let mut camera_best_average_mapping: HashMap<u8, CameraBestAvgPics> =
+    let mut camera_best_average_mapping: HashMap<u8, CameraSessionCandidates> =
         HashMap::with_capacity(number_of_cameras as usize);
+    let mut boundary_partials: HashMap<(Uuid, u8), SessionScores> = HashMap::new();
+    let mut camera_summary_mapping = CameraSummaryMapping::default();
+    let mut session_stats_accumulator = SessionStatsAccumulator::default();
+    let mut best_sessions = TopSessions::new(top_sessions);
 
     for (key, group) in sorted_iter
         .group_by(|x| (x.session_id, x.camera_id))
         .into_iter()
     {
-        let (pics_by_camera, occurrences_of_camera) = group
-            .fold((0usize, 0usize), |acc, op_record| {
-                (acc.0 + op_record.nb_pics as usize, acc.1 + 1)
-            });
         let (session_id, camera_id) = (&key.0, &key.1);
-        let avg_num_pics = (pics_by_camera as f32) / (occurrences_of_camera as f32);
-        if let Some(camera_best_avg_pics) = camera_best_average_mapping.get_mut(camera_id) {
-            camera_best_avg_pics.update_on_improvement(session_id, avg_num_pics);
-        } else {
-            let mut camera_best_avg_pics = CameraBestAvgPics::default();
-            camera_best_avg_pics.update_on_improvement(session_id, avg_num_pics);
-            camera_best_average_mapping.insert(*camera_id, camera_best_avg_pics);
+        let camera_summary = camera_summary_mapping.mapper.entry(*camera_id).or_default();
+        camera_summary.add_session();
+        let scores = group.fold(SessionScores::default(), |scores, op_record| {
+            camera_summary.add_trip(op_record.nb_pics);
+            if let Some(session_stats) = session_stats_accumulator.add_record(&op_record) {
+                push_session_stats(&mut best_sessions, session_stats, boundary_sessions);
+            }
+            scores.add_trip(op_record.nb_pics)
+        });
+        // Cameras without any eligible sessions still get an (empty) entry, so that every camera appears in the report.
+        let camera_candidates = camera_best_average_mapping.entry(*camera_id).or_default();
+        if boundary_sessions.contains(session_id) {
+            boundary_partials.insert(key, scores);
+        } else if scores.trips >= min_trips {
+            camera_candidates.update_on_improvement(session_id, scores.pics(metric), scores.trips);
         }
     }
-    CameraBestAvgPicsMapping::new(camera_best_average_mapping)
+    if let Some(session_stats) = session_stats_accumulator.finish() {
+        push_session_stats(&mut best_sessions, session_stats, boundary_sessions);
+    }
+    (
+        CameraBestAvgPicsMapping::new(camera_best_average_mapping, boundary_partials),
+        camera_summary_mapping,
+        best_sessions,
+    )
+}
+
+// Pushes the stats of a session to the top sessions, or to their partials if it is a boundary session.
+fn push_session_stats(
+    best_sessions: &mut TopSessions,
+    session_stats: SessionStats,
+    boundary_sessions: &HashSet<Uuid>,
+) {
+    if boundary_sessions.contains(&session_stats.session_id) {
+        best_sessions.push_boundary_partial(session_stats);
+    } else {
+        best_sessions.push(session_stats);
+    }
+}
+
+// The scores of a camera in a single session, accumulated trip by trip.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SessionScores {
+    sum_pics: u32,
+    trips: u32,
+    max_pics: u8,
 }
 
-// This struct holds a map that takes an camera id to the data describing the top 100 average number of pics in sessions.
+impl SessionScores {
+    fn add_trip(self, nb_pics: u8) -> Self {
+        Self {
+            sum_pics: self.sum_pics + nb_pics as u32,
+            trips: self.trips + 1,
+            max_pics: self.max_pics.max(nb_pics),
+        }
+    }
+
+    // The number of pics stored for the session in the candidates when selecting sessions by the given metric.
+    // For averages and totals this is the total number of pics, for maxima the most pics in a single trip.
+    fn pics(&self, metric: SessionMetric) -> u32 {
+        match metric {
+            SessionMetric::Avg | SessionMetric::Total => self.sum_pics,
+            SessionMetric::Max => self.max_pics as u32,
+        }
+    }
+
+    // The scores of a candidate with the given pics (see SessionScores::pics) and trips.
+    fn of_candidate(pics: u32, trips: u32, metric: SessionMetric) -> Self {
+        match metric {
+            SessionMetric::Avg | SessionMetric::Total => Self {
+                sum_pics: pics,
+                trips,
+                max_pics: 0,
+            },
+            SessionMetric::Max => Self {
+                sum_pics: 0,
+                trips,
+                max_pics: pics.min(u8::MAX as u32) as u8,
+            },
+        }
+    }
+}
+
+// Combines the scores of the parts of a session that crossed midnight.
+impl AddAssign for SessionScores {
+    fn add_assign(&mut self, other: Self) {
+        self.sum_pics += other.sum_pics;
+        self.trips += other.trips;
+        self.max_pics = self.max_pics.max(other.max_pics);
+    }
+}
+
+// This struct holds a map that takes an camera id to the candidates for its top 100 sessions.
+// The scores of sessions that may cross midnight are kept separately by (session id, camera id) until the days are merged.
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub(crate) struct CameraBestAvgPicsMapping {
-    pub(crate) mapper: HashMap<u8, CameraBestAvgPics>,
+    pub(crate) mapper: HashMap<u8, CameraSessionCandidates>,
+    pub(crate) boundary_partials: HashMap<(Uuid, u8), SessionScores>,
 }
 impl CameraBestAvgPicsMapping {
-    fn new(mapper: HashMap<u8, CameraBestAvgPics>) -> Self {
-        Self { mapper }
+    fn new(
+        mapper: HashMap<u8, CameraSessionCandidates>,
+        boundary_partials: HashMap<(Uuid, u8), SessionScores>,
+    ) -> Self {
+        Self {
+            mapper,
+            boundary_partials,
+        }
+    }
+
+    // Turns the combined partial scores into candidates. This must only be done once all days have been merged,
+    // as otherwise a session crossing midnight would compete with its partial scores.
+    // A session that was a boundary session on one day may have been a regular session on another day, in which case
+    // it is already among the candidates. Those scores are folded into the partial scores, so that the session competes only once.
+    fn stitch_boundary_partials(&mut self, min_trips: u32, metric: SessionMetric) {
+        for ((session_id, camera_id), mut scores) in self.boundary_partials.drain() {
+            let camera_candidates = self.mapper.entry(camera_id).or_default();
+            for (pics, trips) in camera_candidates.remove(&session_id) {
+                scores += SessionScores::of_candidate(pics, trips, metric);
+            }
+            if scores.trips >= min_trips {
+                camera_candidates.update_on_improvement(
+                    &session_id,
+                    scores.pics(metric),
+                    scores.trips,
+                );
+            }
+        }
     }
 }
 
 // We implement this trait in order to merge CameraBEstAvgPicsMappings together.
 // Loosely speaking mapping_1.add_assign(mapping2) mutates mapping_1 to the following mapping:
-// (mapping_1,mapping_2)(camera_id) = top 100 average number of pics (and corresponding sessions) from the union of mapping_1(camera_id) and mapping_2(camera_id).
+// (mapping_1,mapping_2)(camera_id) = the candidates for the top 100 sessions from the union of mapping_1(camera_id) and mapping_2(camera_id).
 impl AddAssign for CameraBestAvgPicsMapping {
     fn add_assign(&mut self, other: Self) {
         for (id_other, best_avg_pics_other) in other.mapper.into_iter() {
@@ -101,52 +327,155 @@ impl AddAssign for CameraBestAvgPicsMapping {
                 best_avg_pics_self.add_assign(best_avg_pics_other);
             }
         }
+        // The partial scores of a session that crossed midnight are combined.
+        for (key, scores) in other.boundary_partials {
+            *self.boundary_partials.entry(key).or_default() += scores;
+        }
     }
 }
+
+// The sessions of a camera that may end up among its top 100, grouped by the number of trips in the session.
+// For every number of trips we keep the 100 sessions with the most pics, where the pics of a session depend on the
+// SessionMetric (the total number of pics, or the most pics in a single trip). All of our ranking metrics (see the ranking module)
+// increase with the number of pics when the number of trips is fixed, hence the top 100 sessions of the camera according
+// to any of them are among these candidates. This lets us postpone the ranking until the statistics of the whole window are known.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CameraSessionCandidates {
+    pub(crate) by_trips: BTreeMap<u32, CameraBestAvgPics>,
+}
+
+impl CameraSessionCandidates {
+    pub(crate) fn update_on_improvement(&mut self, session_id: &Uuid, sum_pics: u32, trips: u32) {
+        self.by_trips
+            .entry(trips)
+            .or_default()
+            .update_on_improvement(session_id, sum_pics, trips);
+    }
+
+    // Removes every occurrence of the session from the candidates and returns their pairs (sum_pics, trips).
+    fn remove(&mut self, session_id: &Uuid) -> Vec<(u32, u32)> {
+        let mut removed = Vec::new();
+        for camera_best_avg_pics in self.by_trips.values_mut() {
+            while let Some(sum_pics_and_trips) = camera_best_avg_pics.remove(session_id) {
+                removed.push(sum_pics_and_trips);
+            }
+        }
+        self.by_trips
+            .retain(|_, camera_best_avg_pics| camera_best_avg_pics.len > 0);
+        removed
+    }
+
+    // Iterates over the (non-empty) candidates as triples (session_id, sum_pics, trips).
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (Uuid, u32, u32)> + '_ {
+        self.by_trips.values().flat_map(|camera_best_avg_pics| {
+            (0..camera_best_avg_pics.len).map(move |i| {
+                (
+                    camera_best_avg_pics.sessions[i],
+                    camera_best_avg_pics.sum_pics[i],
+                    camera_best_avg_pics.trips[i],
+                )
+            })
+        })
+    }
+}
+
+impl AddAssign for CameraSessionCandidates {
+    fn add_assign(&mut self, other: Self) {
+        for (trips, best_avg_pics_other) in other.by_trips {
+            *self.by_trips.entry(trips).or_default() += best_avg_pics_other;
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub(crate) struct CameraBestAvgPics {
+    // The number of occupied entries, i.e. only the first len entries of the arrays below describe sessions.
+    // The remaining entries are padding, which is why they must never be read.
+    pub(crate) len: usize,
     // the ids of the top 100 sessions.
     #[serde(with = "BigArray")]
     pub(crate) sessions: [Uuid; 100],
-    // sorted array of the top 100 avg pics by the camera in sessions.
-    // We require that avg_pics[i] was the average number of pics by the camera in sessions[i] for all i.
-    // This relationship between the two arrays would be made clearer if we used a hashmap instead, but
+    // The top 100 averages are stored exactly as pairs (sum_pics[i], trips[i]), i.e. the average number of pics
+    // by the camera in sessions[i] is sum_pics[i] / trips[i], where trips[i] is the number of trips (rentals of the camera) in sessions[i].
+    // The pairs are sorted from the highest to the lowest average, and averages are compared exactly (see exceeds_average)
+    // so that for instance 2/3 and 4/6 are always considered equal.
+    // This relationship between the arrays would be made clearer if we used a hashmap instead, but
     // since this data structure is intended to be mutated very many times, the use of a hashmap would lead to
     // a big drop in performance.
     #[serde(with = "BigArray")]
-    pub(crate) avg_pics: [f32; 100],
-    // the 100'th highest number of pics
+    pub(crate) sum_pics: [u32; 100],
+    #[serde(with = "BigArray")]
+    pub(crate) trips: [u32; 100],
+}
+
+// Whether the average sum_pics / trips is strictly larger than other_sum_pics / other_trips.
+// The averages are compared by cross multiplication, hence no rounding is involved. Averages of no trips count as 0.
+fn exceeds_average(sum_pics: u32, trips: u32, other_sum_pics: u32, other_trips: u32) -> bool {
+    sum_pics as u64 * other_trips.max(1) as u64 > other_sum_pics as u64 * trips.max(1) as u64
 }
 
 impl Default for CameraBestAvgPics {
     fn default() -> Self {
-        let sessions = [Uuid::default(); 100];
-        let avg_pics = [0.0; 100];
-        Self { sessions, avg_pics }
+        let sessions = [Uuid::nil(); 100];
+        let sum_pics = [0; 100];
+        let trips = [0; 100];
+        Self {
+            len: 0,
+            sessions,
+            sum_pics,
+            trips,
+        }
     }
 }
 impl CameraBestAvgPics {
-    pub(crate) fn update_on_improvement(&mut self, session_id: &Uuid, avg_num_pics: f32) {
-        if self.is_improvement(avg_num_pics) {
-            self.update(*session_id, avg_num_pics)
+    pub(crate) fn update_on_improvement(&mut self, session_id: &Uuid, sum_pics: u32, trips: u32) {
+        if self.is_improvement(sum_pics, trips) {
+            self.update(*session_id, sum_pics, trips)
         }
     }
 
-    fn is_improvement(&self, avg_num_pics: f32) -> bool {
-        // We may always assume that self.avg_pics is sorted in such a way that lower indexes correspond to higher values.
-        self.avg_pics[99] < avg_num_pics
+    // Removes the session from the top 100 and returns its pair (sum_pics, trips), if it is among them.
+    // The following entries move up, hence the remaining entries are still sorted.
+    fn remove(&mut self, session_id: &Uuid) -> Option<(u32, u32)> {
+        let idx = self.sessions[..self.len]
+            .iter()
+            .position(|id| id == session_id)?;
+        let removed = (self.sum_pics[idx], self.trips[idx]);
+        self.sessions.copy_within(idx + 1..self.len, idx);
+        self.sum_pics.copy_within(idx + 1..self.len, idx);
+        self.trips.copy_within(idx + 1..self.len, idx);
+        self.len -= 1;
+        self.sessions[self.len] = Uuid::nil();
+        self.sum_pics[self.len] = 0;
+        self.trips[self.len] = 0;
+        Some(removed)
+    }
+
+    fn is_improvement(&self, sum_pics: u32, trips: u32) -> bool {
+        // We may always assume that the averages are sorted in such a way that lower indexes correspond to higher values.
+        self.len < 100 || exceeds_average(sum_pics, trips, self.sum_pics[99], self.trips[99])
     }
 
     // todo: Using a Vec instead of arrays might improve performance here. We should investigate this option.
-    fn update(&mut self, session_id: Uuid, avg_num_pics: f32) {
-        // first replace the last element
-        self.avg_pics[99] = avg_num_pics;
-        self.sessions[99] = session_id;
+    fn update(&mut self, session_id: Uuid, sum_pics: u32, trips: u32) {
+        // first fill the first free entry, or replace the last element if there is none
+        let mut i = self.len.min(99);
+        self.len = (self.len + 1).min(100);
+        self.sum_pics[i] = sum_pics;
+        self.sessions[i] = session_id;
+        self.trips[i] = trips;
         // Now sort such that the lower indexes correspond to higher values
-        let mut i = 99usize;
-        while i > 0 && self.avg_pics[i] > self.avg_pics[i - 1] {
-            self.avg_pics.swap(i - 1, i);
+        while i > 0
+            && exceeds_average(
+                self.sum_pics[i],
+                self.trips[i],
+                self.sum_pics[i - 1],
+                self.trips[i - 1],
+            )
+        {
+            self.sum_pics.swap(i - 1, i);
             self.sessions.swap(i - 1, i);
+            self.trips.swap(i - 1, i);
             i -= 1;
         }
     }
@@ -154,9 +483,13 @@ impl CameraBestAvgPics {
 
 impl AddAssign for CameraBestAvgPics {
     fn add_assign(&mut self, other: Self) {
-        for (session_id, avg_num_pics) in other.sessions.iter().zip(other.avg_pics.iter().cloned()) {
-            if self.is_improvement(avg_num_pics) {
-                self.update(*session_id, avg_num_pics);
+        for ((session_id, sum_pics), trips) in other.sessions[..other.len]
+            .iter()
+            .zip(other.sum_pics.iter().cloned())
+            .zip(other.trips.iter().cloned())
+        {
+            if self.is_improvement(sum_pics, trips) {
+                self.update(*session_id, sum_pics, trips);
             } else {
                 break;
             }
@@ -166,8 +499,11 @@ impl AddAssign for CameraBestAvgPics {
 
 // Takes a vector of CameraBestAvgPicsMappings and merges them together to a single CameraBestAvgPicsMapping.
 // The merge is obtained by collecting the top 100 average number of pics for each camera that can be obtained from any of the provided mappings.
+// Sessions that crossed midnight are stitched together from their partial scores and become candidates if they have at least min_trips trips.
 pub(crate) fn merge_camera_best_avg_pics(
     mut mappings: Vec<CameraBestAvgPicsMapping>,
+    min_trips: u32,
+    metric: SessionMetric,
 ) -> Result<CameraBestAvgPicsMapping> {
     let mut mapping = mappings
         .pop()
@@ -175,6 +511,7 @@ pub(crate) fn merge_camera_best_avg_pics(
     for other_mapping in mappings {
         mapping.add_assign(other_mapping);
     }
+    mapping.stitch_boundary_partials(min_trips, metric);
     Ok(mapping)
 }
 
@@ -182,30 +519,248 @@ pub(crate) fn merge_camera_best_avg_pics(
 mod tests {
     use super::*;
 
+    // The averages of the occupied entries, in the order they are stored in.
+    fn avg_pics(camera_best_avg_pics: &CameraBestAvgPics) -> Vec<f32> {
+        (0..camera_best_avg_pics.len)
+            .map(|i| camera_best_avg_pics.sum_pics[i] as f32 / camera_best_avg_pics.trips[i] as f32)
+            .collect()
+    }
+
+    #[test]
+    fn timed_chunks_yield_every_record_in_order() {
+        // more than a chunk, so that the last chunk is only partially filled.
+        let number_of_records = TimedChunks::<std::ops::Range<usize>>::CHUNK_SIZE + 3;
+        let mut parse_time = Duration::ZERO;
+        let records: Vec<usize> = TimedChunks::new(0..number_of_records, &mut parse_time).collect();
+        assert_eq!((0..number_of_records).collect::<Vec<_>>(), records);
+    }
+
     #[test]
     fn update_on_single_improvement_updates_on_improvement() {
         let mut camera_best_avg_pics = CameraBestAvgPics::default();
         let session_id = Uuid::from_u128(42);
-        let avg_num_pics = 2.0 as f32;
-        camera_best_avg_pics.update_on_improvement(&session_id, avg_num_pics);
-        let max_camera_avg_pics = camera_best_avg_pics
-            .avg_pics
-            .iter()
+        let avg_num_pics = 2.0_f32;
+        camera_best_avg_pics.update_on_improvement(&session_id, avg_num_pics as u32, 1);
+        let max_camera_avg_pics = avg_pics(&camera_best_avg_pics)
+            .into_iter()
             .max_by(|x, y| x.partial_cmp(y).unwrap())
             .unwrap();
-        assert_eq!(*max_camera_avg_pics, avg_num_pics);
+        assert_eq!(max_camera_avg_pics, avg_num_pics);
     }
 
     #[test]
     fn update_on_multiple_improvements_works() {
         let mut camera_best_avg_pics = CameraBestAvgPics::default();
         let mut session_ids = [Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3)];
-        let avg_pics = [1.0f32, 2.0, 3.0];
-        for (session_id, avg_num_pics) in session_ids.iter().zip(avg_pics.iter()) {
-            camera_best_avg_pics.update_on_improvement(session_id, *avg_num_pics);
+        let avg_pics_per_session = [1.0f32, 2.0, 3.0];
+        for (session_id, avg_num_pics) in session_ids.iter().zip(avg_pics_per_session.iter()) {
+            camera_best_avg_pics.update_on_improvement(session_id, *avg_num_pics as u32, 1);
         }
-        assert_eq!([3.0, 2.0, 1.0], camera_best_avg_pics.avg_pics[..3]);
+        assert_eq!([3.0, 2.0, 1.0], avg_pics(&camera_best_avg_pics)[..3]);
         session_ids.reverse();
         assert_eq!(session_ids, camera_best_avg_pics.sessions[..3]);
     }
+
+    #[test]
+    fn averages_are_stored_as_sums_and_trips() {
+        let mut camera_best_avg_pics = CameraBestAvgPics::default();
+        camera_best_avg_pics.update_on_improvement(&Uuid::from_u128(1), 5, 2);
+        camera_best_avg_pics.update_on_improvement(&Uuid::from_u128(2), 3, 1);
+        camera_best_avg_pics.update_on_improvement(&Uuid::from_u128(3), 2, 1);
+        assert_eq!(3, camera_best_avg_pics.len);
+        assert_eq!(
+            [Uuid::from_u128(2), Uuid::from_u128(1), Uuid::from_u128(3)],
+            camera_best_avg_pics.sessions[..3]
+        );
+        assert_eq!([3, 5, 2], camera_best_avg_pics.sum_pics[..3]);
+        assert_eq!([1, 2, 1], camera_best_avg_pics.trips[..3]);
+    }
+
+    #[test]
+    fn nil_sessions_and_sessions_without_pics_are_kept() {
+        let mut camera_best_avg_pics = CameraBestAvgPics::default();
+        camera_best_avg_pics.update_on_improvement(&Uuid::nil(), 0, 2);
+        camera_best_avg_pics.update_on_improvement(&Uuid::from_u128(1), 3, 1);
+        assert_eq!(2, camera_best_avg_pics.len);
+        let mut merged = CameraBestAvgPics::default();
+        merged += camera_best_avg_pics;
+        assert_eq!(
+            [Uuid::from_u128(1), Uuid::nil()],
+            merged.sessions[..merged.len]
+        );
+        assert_eq!(
+            [(3, 1), (0, 2)],
+            [
+                (merged.sum_pics[0], merged.trips[0]),
+                (merged.sum_pics[1], merged.trips[1])
+            ]
+        );
+    }
+
+    #[test]
+    fn sessions_with_too_few_trips_are_ignored() {
+        let record = |session_id: u128, nb_pics: u8| CameraRecord {
+            user_id: Uuid::from_u128(7),
+            camera_id: 1,
+            session_id: Uuid::from_u128(session_id),
+            nb_pics,
+        };
+        // In session 1 the camera was rented once and in session 2 three times.
+        let sorted_records = vec![record(1, 5), record(2, 2), record(2, 3), record(2, 4)];
+        let (best_avg_pics, summaries, best_sessions) = camera_stats_from_sorted_iterator(
+            sorted_records.into_iter(),
+            2,
+            SessionMetric::Avg,
+            10,
+            &HashSet::new(),
+        );
+        assert_eq!(
+            vec![(Uuid::from_u128(2), 9, 3)],
+            best_avg_pics.mapper[&1].iter().collect::<Vec<_>>()
+        );
+        // The summary metrics and the top sessions are not affected by the threshold.
+        assert_eq!(4, summaries.mapper[&1].trips);
+        assert_eq!(2, best_sessions.sessions.len());
+    }
+
+    #[test]
+    fn the_metric_selects_the_stored_pics() {
+        let record = |session_id: u128, nb_pics: u8| CameraRecord {
+            user_id: Uuid::from_u128(7),
+            camera_id: 1,
+            session_id: Uuid::from_u128(session_id),
+            nb_pics,
+        };
+        let sorted_records = || vec![record(1, 2), record(1, 7), record(1, 3)].into_iter();
+        let (best_total, _, _) = camera_stats_from_sorted_iterator(
+            sorted_records(),
+            1,
+            SessionMetric::Total,
+            10,
+            &HashSet::new(),
+        );
+        assert_eq!(
+            vec![(Uuid::from_u128(1), 12, 3)],
+            best_total.mapper[&1].iter().collect::<Vec<_>>()
+        );
+        let (best_max, _, _) = camera_stats_from_sorted_iterator(
+            sorted_records(),
+            1,
+            SessionMetric::Max,
+            10,
+            &HashSet::new(),
+        );
+        assert_eq!(
+            vec![(Uuid::from_u128(1), 7, 3)],
+            best_max.mapper[&1].iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sessions_crossing_midnight_are_stitched_together() {
+        let record = |session_id: u128, nb_pics: u8| CameraRecord {
+            user_id: Uuid::from_u128(7),
+            camera_id: 1,
+            session_id: Uuid::from_u128(session_id),
+            nb_pics,
+        };
+        let boundary_sessions: HashSet<Uuid> = [Uuid::from_u128(1)].iter().copied().collect();
+        // Session 1 starts before midnight with two trips and ends after midnight with one trip.
+        let (monday, _, monday_sessions) = camera_stats_from_sorted_iterator(
+            vec![record(1, 2), record(1, 4), record(2, 1), record(2, 1)].into_iter(),
+            3,
+            SessionMetric::Avg,
+            10,
+            &boundary_sessions,
+        );
+        let (tuesday, _, tuesday_sessions) = camera_stats_from_sorted_iterator(
+            vec![record(1, 6), record(3, 1), record(3, 1), record(3, 1)].into_iter(),
+            3,
+            SessionMetric::Avg,
+            10,
+            &boundary_sessions,
+        );
+        // Neither part of session 1 has enough trips on its own.
+        assert!(monday.mapper[&1].iter().next().is_none());
+        let merged =
+            merge_camera_best_avg_pics(vec![monday, tuesday], 3, SessionMetric::Avg).unwrap();
+        assert_eq!(
+            vec![(Uuid::from_u128(1), 12, 3), (Uuid::from_u128(3), 3, 3)],
+            merged.mapper[&1].iter().collect::<Vec<_>>()
+        );
+        assert!(merged.boundary_partials.is_empty());
+
+        let top_sessions =
+            crate::sessions::merge_top_sessions(vec![monday_sessions, tuesday_sessions], 10);
+        assert_eq!(
+            vec![
+                (Uuid::from_u128(1), 12, 3),
+                (Uuid::from_u128(3), 3, 3),
+                (Uuid::from_u128(2), 2, 2)
+            ],
+            top_sessions
+                .sessions
+                .iter()
+                .map(|stats| (stats.session_id, stats.total_pics, stats.trips))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn boundary_sessions_that_are_regular_on_another_day_are_listed_once() {
+        let record = |session_id: u128, nb_pics: u8| CameraRecord {
+            user_id: Uuid::from_u128(7),
+            camera_id: 1,
+            session_id: Uuid::from_u128(session_id),
+            nb_pics,
+        };
+        // Session 1 is a boundary session on Monday, but not found among the first records of Tuesday.
+        let (monday, _, monday_sessions) = camera_stats_from_sorted_iterator(
+            vec![record(1, 2), record(1, 4)].into_iter(),
+            1,
+            SessionMetric::Avg,
+            10,
+            &[Uuid::from_u128(1)].iter().copied().collect(),
+        );
+        let (tuesday, _, tuesday_sessions) = camera_stats_from_sorted_iterator(
+            vec![record(1, 6), record(2, 1)].into_iter(),
+            1,
+            SessionMetric::Avg,
+            10,
+            &HashSet::new(),
+        );
+        let merged =
+            merge_camera_best_avg_pics(vec![monday, tuesday], 1, SessionMetric::Avg).unwrap();
+        assert_eq!(
+            vec![(Uuid::from_u128(2), 1, 1), (Uuid::from_u128(1), 12, 3)],
+            merged.mapper[&1].iter().collect::<Vec<_>>()
+        );
+
+        let top_sessions =
+            crate::sessions::merge_top_sessions(vec![monday_sessions, tuesday_sessions], 10);
+        assert_eq!(
+            vec![(Uuid::from_u128(1), 12, 3), (Uuid::from_u128(2), 1, 1)],
+            top_sessions
+                .sessions
+                .iter()
+                .map(|stats| (stats.session_id, stats.total_pics, stats.trips))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn averages_are_compared_exactly() {
+        let mut camera_best_avg_pics = CameraBestAvgPics::default();
+        camera_best_avg_pics.update_on_improvement(&Uuid::from_u128(1), 2, 3);
+        // 4/6 equals 2/3, so the session that came first keeps the higher rank.
+        camera_best_avg_pics.update_on_improvement(&Uuid::from_u128(2), 4, 6);
+        // 7/10 is larger than 2/3.
+        camera_best_avg_pics.update_on_improvement(&Uuid::from_u128(3), 7, 10);
+        assert_eq!(
+            [Uuid::from_u128(3), Uuid::from_u128(1), Uuid::from_u128(2)],
+            camera_best_avg_pics.sessions[..3]
+        );
+        assert!(!exceeds_average(4, 6, 2, 3));
+        assert!(!exceeds_average(0, 1, 0, 0));
+    }
 }
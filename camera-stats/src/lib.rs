@@ -1,83 +1,600 @@
 use anyhow::{Context, Result};
 
+use common_utils::{
+    date_utils::DateStamp,
+    file_utils::{QuarantineFilesConfig, StateFilesGuard, UnprocessedSessionLogFile},
+    locking::StateDirectoryLock,
+    manifest::{RunManifest, SourceDigest, SourceLog},
+    metrics::RunMetrics,
+    parquet_utils::{ParquetReportWriter, ParquetTableWriter},
+    parsing_utils::{NilIdRule, RecordValidator},
+    progress::ProgressBar,
+    report_utils::{JsonLine, ReportFormat},
+};
 use itertools::Itertools;
 
-use std::{fs::File, io::BufWriter, path::PathBuf};
+use safari_stats_reports::{LineFormat, ReportHeader};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 pub mod configuration;
 mod daily_serializing;
 mod extracting;
 pub(crate) mod parsing;
+mod ranking;
+mod sessions;
 mod sorting;
+mod summarizing;
 mod writing;
 
 use configuration::SerializationFilesConfig;
-use writing::CameraBestAvgPicsRecord;
+use extracting::SessionSelection;
+pub use ranking::{RankingMetricKind, SessionMetric};
+use summarizing::CameraSummary;
+use writing::{CameraBestAvgPicsRecord, CameraSummaryRecord, SessionStatsRecord};
 
-pub fn run(from_path: PathBuf, to_path: PathBuf) -> Result<()> {
-    for unprocessed_log_file in
-        common_utils::file_utils::unprocessed_session_log_files(from_path, |datestamp| {
-            SerializationFilesConfig::serialization_file_from_datestamp(datestamp).exists()
-        })
-    {
-        let unprocessed_log_file = unprocessed_log_file?;
-        println!("processing {:?}", &unprocessed_log_file.path.as_os_str());
+/// The options camera-stats is run with. See the command line interface for their descriptions.
+pub struct RunOptions {
+    pub report_format: ReportFormat,
+    pub min_trips: u32,
+    pub precision: Option<usize>,
+    pub ranking_metric: RankingMetricKind,
+    pub metric: SessionMetric,
+    pub top_sessions: usize,
+    pub nil_ids: NilIdRule,
+    pub wait_for_lock: bool,
+    pub metrics_file: Option<PathBuf>,
+    pub jobs: usize,
+    /// If set, only these session logs are processed, e.g. the logs a watcher found to be complete. Any other unprocessed log
+    /// is treated like a missing one (see common_utils::watching::only_completed_logs).
+    pub completed_logs: Option<HashSet<PathBuf>>,
+}
 
-        println!(
-            "extracting the top 100 average number of pics by camera from: {:?}",
-            &unprocessed_log_file.path.as_os_str()
-        );
-        let camera_top_100_mapping = crate::extracting::extract_top_100_sessions_for_cameras(
+/// Every run writes a manifest next to the reports, which records the configuration, the session logs the reports were
+/// computed from and the reports themselves (see common_utils::manifest). If the run fails, the manifest records the error as well.
+///
+/// If a metrics file is given, the metrics of the run are written to it in the Prometheus text format once the run has finished,
+/// whether it succeeded or not (see common_utils::metrics).
+pub fn run(from_path: PathBuf, to_path: PathBuf, options: &RunOptions) -> Result<()> {
+    let mut metrics = RunMetrics::default();
+    let result = run_collecting_metrics(from_path, to_path, options, &mut metrics);
+    if let Some(metrics_file) = options.metrics_file.as_ref() {
+        let written = metrics
+            .write_textfile(metrics_file, "camera-stats", result.is_ok())
+            .with_context(|| {
+                format!(
+                    "Failed to write the metrics to {:?}",
+                    metrics_file.as_os_str()
+                )
+            });
+        if let Err(error) = written {
+            // The error of the run is more important than the missing metrics.
+            if result.is_ok() {
+                return Err(error);
+            }
+            tracing::warn!("{:#}", error);
+        }
+    }
+    result
+}
+
+// The state of a day computed from its session log.
+struct ProcessedDay {
+    date: DateStamp,
+    source_log: SourceLog,
+    time: Duration,
+    metrics: RunMetrics,
+}
+
+// Extracts the stats of the given session log and serializes them as the state of its day. The memory buffer of the sorter
+// is shared by the given number of days that are processed concurrently.
+fn process_log_file(
+    unprocessed_log_file: UnprocessedSessionLogFile,
+    options: &RunOptions,
+    concurrent_days: usize,
+    days_progress: &ProgressBar,
+) -> Result<ProcessedDay> {
+    let RunOptions {
+        min_trips,
+        metric,
+        top_sessions,
+        nil_ids,
+        ..
+    } = *options;
+    let mut metrics = RunMetrics::default();
+    let _log_span = tracing::info_span!(
+        "process_log",
+        log = ?unprocessed_log_file.path.as_os_str()
+    )
+    .entered();
+    days_progress.set_message(
+        unprocessed_log_file
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+    );
+    tracing::info!(
+        "extracting the top 100 average number of pics and summary metrics by camera and the top sessions"
+    );
+    let day_started = Instant::now();
+    let source_digest = SourceDigest::default();
+    let mut record_validator = RecordValidator::new(
+        nil_ids,
+        QuarantineFilesConfig::file_path("camera-stats", unprocessed_log_file.date.clone()),
+    );
+    // Camera records carry the user id (needed to count the participants of a session), so we keep fewer of them
+    // in memory than we used to in order to keep the memory consumption of the sorter unchanged.
+    const NUM_ITEMS_IN_SORTER_MEMORY_BUFFER: usize = 25_000_000;
+    let (camera_top_100_mapping, camera_summary_mapping, top_sessions_of_the_day) =
+        crate::extracting::extract_camera_stats(
             unprocessed_log_file.path.clone(),
+            SessionSelection {
+                min_trips,
+                metric,
+                top_sessions,
+            },
+            NUM_ITEMS_IN_SORTER_MEMORY_BUFFER / concurrent_days,
+            &mut record_validator,
+            &source_digest,
+            &mut metrics,
+        )?;
+    metrics.rows_parsed += record_validator.rows_read();
+    metrics.rows_rejected += record_validator.rows_rejected();
+    let source_log = SourceLog::new(
+        &unprocessed_log_file.path,
+        &source_digest,
+        &record_validator,
+    )
+    .with_context(|| {
+        format!(
+            "Failed to describe the session log file {:?} for the run manifest",
+            unprocessed_log_file.path.as_os_str()
+        )
+    })?;
+    let quarantine_file_path = record_validator.quarantine_file_path().to_path_buf();
+    let quarantined_records = record_validator.finish().with_context(|| {
+        format!(
+            "Invalid records were found in {:?}",
+            unprocessed_log_file.path.as_os_str()
         )
-        .unwrap();
-        println!(
-            "extraction completed. Now compactly saving this information for subsequent reuse"
+    })?;
+    if quarantined_records > 0 {
+        tracing::warn!(
+            records = quarantined_records,
+            file = ?quarantine_file_path.as_os_str(),
+            "records with a nil user or session id have been quarantined"
         );
-        let serialization_path = SerializationFilesConfig::serialization_file_from_datestamp(
+    }
+    let persist_span = tracing::info_span!("persist").entered();
+    let persist_started = Instant::now();
+    let serialization_path = SerializationFilesConfig::serialization_file_from_datestamp(
+        unprocessed_log_file.date.clone(),
+        min_trips,
+        metric,
+    );
+    let summary_serialization_path =
+        SerializationFilesConfig::summary_serialization_file_from_datestamp(
             unprocessed_log_file.date.clone(),
         );
-        crate::daily_serializing::serialize_camera_best_avg_pics_mapping_to_disk(
-            serialization_path,
-            &camera_top_100_mapping,
-        )?;
+    let sessions_serialization_path =
+        SerializationFilesConfig::sessions_serialization_file_from_datestamp(
+            unprocessed_log_file.date.clone(),
+            top_sessions,
+        );
+    let source_log_path = SourceLog::file_path(
+        &SerializationFilesConfig::serialization_directory(),
+        unprocessed_log_file.date.clone(),
+    );
+    // The day counts as processed once the serialized files exist, hence they are written to temporary paths and
+    // renamed into place last, after the source log of the manifest.
+    let state_files_guard = StateFilesGuard::new(vec![
+        source_log_path.clone(),
+        summary_serialization_path.clone(),
+        sessions_serialization_path.clone(),
+        serialization_path.clone(),
+    ]);
+    crate::daily_serializing::serialize_daily_mapping_to_disk(
+        StateFilesGuard::temporary_path(&serialization_path),
+        &camera_top_100_mapping,
+    )?;
+    crate::daily_serializing::serialize_daily_mapping_to_disk(
+        StateFilesGuard::temporary_path(&summary_serialization_path),
+        &camera_summary_mapping,
+    )?;
+    crate::daily_serializing::serialize_daily_mapping_to_disk(
+        StateFilesGuard::temporary_path(&sessions_serialization_path),
+        &top_sessions_of_the_day,
+    )?;
+    source_log
+        .save(&StateFilesGuard::temporary_path(&source_log_path))
+        .with_context(|| format!("Failed to save {:?}", source_log_path.as_os_str()))?;
+    state_files_guard
+        .keep()
+        .with_context(|| "Failed to move the serialized files into place".to_string())?;
+    metrics.add_stage_duration("persist", persist_started.elapsed());
+    metrics.days_processed += 1;
+    drop(persist_span);
+    days_progress.inc(1);
+    Ok(ProcessedDay {
+        date: unprocessed_log_file.date,
+        source_log,
+        time: day_started.elapsed(),
+        metrics,
+    })
+}
+
+fn run_collecting_metrics(
+    from_path: PathBuf,
+    to_path: PathBuf,
+    options: &RunOptions,
+    metrics: &mut RunMetrics,
+) -> Result<()> {
+    let RunOptions {
+        report_format,
+        min_trips,
+        precision,
+        ranking_metric,
+        metric,
+        top_sessions,
+        nil_ids,
+        wait_for_lock,
+        metrics_file: _,
+        jobs,
+        completed_logs: _,
+    } = *options;
+    if jobs == 0 {
+        anyhow::bail!("At least one job is needed to process the session logs");
+    }
+    if metric != SessionMetric::Avg && ranking_metric != RankingMetricKind::RawMean {
+        anyhow::bail!(
+            "The ranking metric {} only applies to averages, but the sessions are selected by their {} number of pics",
+            ranking_metric,
+            metric
+        );
+    }
+    // Another run processing the same days would write the same serialized files, hence only one run may use the state directory at a time.
+    let _lock = StateDirectoryLock::acquire(
+        &SerializationFilesConfig::serialization_directory(),
+        wait_for_lock,
+    )
+    .with_context(|| "Could not lock the directory of the serialized camera stats")?;
+    let mut manifest = RunManifest::new(
+        "camera-stats",
+        env!("CARGO_PKG_VERSION"),
+        serde_json::json!({
+            "session-logs-directory": from_path,
+            "output-directory": to_path,
+            "format": report_format.to_string(),
+            "min-trips": min_trips,
+            "precision": precision,
+            "ranking-metric": ranking_metric.to_string(),
+            "metric": metric.to_string(),
+            "top-sessions": top_sessions,
+            "nil-ids": nil_ids.to_string(),
+            "jobs": jobs,
+        }),
+    );
+    let result =
+        process_logs_and_write_reports(from_path, to_path.clone(), options, metrics, &mut manifest);
+    if let Err(error) = result.as_ref() {
+        manifest.record_failure(format!("{:#}", error));
     }
+    let manifest_path = crate::configuration::todays_manifest_file_path(to_path);
+    let written = manifest.write(&manifest_path).with_context(|| {
+        format!(
+            "Failed to write the run manifest: {:?}",
+            manifest_path.as_os_str()
+        )
+    });
+    match written {
+        Ok(()) => {
+            tracing::info!(manifest = ?manifest_path.as_os_str(), "the run manifest has been saved")
+        }
+        // The error of the run is more important than the missing manifest.
+        Err(error) if result.is_err() => tracing::warn!("{:#}", error),
+        Err(error) => return Err(error),
+    }
+    result
+}
+
+// Processes the session logs that have not been processed yet and writes the reports, recording both in the given manifest.
+fn process_logs_and_write_reports(
+    from_path: PathBuf,
+    to_path: PathBuf,
+    options: &RunOptions,
+    metrics: &mut RunMetrics,
+    manifest: &mut RunManifest,
+) -> Result<()> {
+    let RunOptions {
+        report_format,
+        min_trips,
+        precision,
+        ranking_metric,
+        metric,
+        top_sessions,
+        nil_ids: _,
+        wait_for_lock: _,
+        metrics_file: _,
+        jobs,
+        completed_logs: _,
+    } = *options;
+    let processing_started = Instant::now();
+    let unprocessed_log_files = common_utils::watching::only_completed_logs(
+        common_utils::file_utils::unprocessed_session_log_files(from_path, |datestamp| {
+            SerializationFilesConfig::is_processed(datestamp, min_trips, metric, top_sessions)
+        }),
+        options.completed_logs.as_ref(),
+    );
+    metrics.days_reused = common_utils::date_utils::NUMBER_OF_DAYS_IN_WINDOW
+        .saturating_sub(unprocessed_log_files.len()) as u64;
+    // The days are independent of each other, hence up to jobs of them are processed concurrently. The final merge below
+    // only starts once all of them have been processed. The days before a missing session log are processed before failing.
+    let mut unprocessed_days = Vec::new();
+    let mut missing_log_file = None;
+    for unprocessed_log_file in unprocessed_log_files {
+        match unprocessed_log_file {
+            Ok(unprocessed_log_file) => unprocessed_days.push(unprocessed_log_file),
+            Err(error) => {
+                missing_log_file = Some(error);
+                break;
+            }
+        }
+    }
+    // The memory budget is only split between the days that are actually processed at the same time.
+    let concurrent_days = jobs.min(unprocessed_days.len());
+    let days_progress = common_utils::progress::days(unprocessed_days.len());
+    let processed_days =
+        common_utils::parallel::map_in_parallel(unprocessed_days, jobs, |unprocessed_log_file| {
+            process_log_file(
+                unprocessed_log_file,
+                options,
+                concurrent_days,
+                &days_progress,
+            )
+        })?;
+    drop(days_progress);
+    for processed_day in processed_days {
+        metrics.merge(processed_day.metrics);
+        manifest.add_recomputed_day(
+            processed_day.date,
+            processed_day.source_log,
+            processed_day.time,
+        );
+    }
+    if let Some(error) = missing_log_file {
+        return Err(error.into());
+    }
+    manifest.add_timing("process_logs", processing_started.elapsed());
+    manifest
+        .add_reused_days(&SerializationFilesConfig::serialization_directory())
+        .with_context(|| {
+            "Failed to load the source logs of the days reused from the serialized camera stats"
+        })?;
+    let merging_started = Instant::now();
+    let merge_span = tracing::info_span!("merge", cameras = tracing::field::Empty).entered();
+    let merge_started = Instant::now();
+    // The summary metrics are merged over the last seven days in the same way as the top 100 candidates.
+    // They are needed to rank the sessions, since some ranking metrics depend on statistics of the camera over the whole window.
+    let camera_summaries_per_day = crate::daily_serializing::deserialize_daily_mappings_from_files(
+        SerializationFilesConfig::serialized_summary_file_paths_last_seven_days(),
+    )?;
+    let camera_summaries_over_seven_days =
+        crate::summarizing::merge_camera_summaries(camera_summaries_per_day);
+
     // Now we can load all of the serialized daily camera stats
-    let cameras_best_per_day =
-        crate::daily_serializing::deserialize_camera_best_avg_pics_mappings_from_files(
-            SerializationFilesConfig::serialized_file_paths_last_seven_days(),
-        )?;
-    // We now have a vector of the top 100 average pics in sessions by camera per day, but we are interested in seeing this over the last seven days so we merge the top 100 from all of these results.
+    let cameras_best_per_day = crate::daily_serializing::deserialize_daily_mappings_from_files(
+        SerializationFilesConfig::serialized_file_paths_last_seven_days(min_trips, metric),
+    )?;
+    // We now have a vector of the candidate sessions by camera per day, but we are interested in seeing this over the last seven days so we merge the candidates from all of these results.
     let best_avg_pics_over_seven_days_by_camera_mapper =
-        crate::extracting::merge_camera_best_avg_pics(cameras_best_per_day)?;
+        crate::extracting::merge_camera_best_avg_pics(cameras_best_per_day, min_trips, metric)?;
+    merge_span.record(
+        "cameras",
+        best_avg_pics_over_seven_days_by_camera_mapper.mapper.len(),
+    );
+    drop(merge_span);
+    metrics.add_stage_duration("merge", merge_started.elapsed());
+    metrics.set_output_entities(
+        "cameras",
+        best_avg_pics_over_seven_days_by_camera_mapper.mapper.len() as u64,
+    );
 
-    // We now have a mapping taking camera ids to their best average number of pics in sessions over the last seven days.
-    // We now transform this mapping into an iterator over key value pairs, where keys are ordered from smallest to largest.
+    // We now have a mapping taking camera ids to their candidate sessions over the last seven days.
+    // We now transform this mapping into an iterator over key value pairs, where keys are ordered from smallest to largest,
+    // and rank the candidates of every camera by the chosen metric.
+    let session_ranking_metric = metric.ranking_metric(ranking_metric);
+    let empty_summary = CameraSummary::default();
     let best_avg_pics_over_seven_days_by_camera_iter =
         best_avg_pics_over_seven_days_by_camera_mapper
             .mapper
             .into_iter()
-            .sorted_by_key(|(camera_id, _camera_best_avg_pics)| *camera_id)
-            .map(|(id, camera_best_avg_pics)| {
-                CameraBestAvgPicsRecord::new(id, camera_best_avg_pics)
+            .sorted_by_key(|(camera_id, _candidates)| *camera_id)
+            .map(|(id, candidates)| {
+                let summary = camera_summaries_over_seven_days
+                    .mapper
+                    .get(&id)
+                    .unwrap_or(&empty_summary);
+                let sessions = crate::ranking::top_100_sessions(
+                    &candidates,
+                    session_ranking_metric.as_ref(),
+                    summary,
+                );
+                CameraBestAvgPicsRecord::new(id, sessions, precision)
             });
+    // The report starts with a header naming the metric the sessions were ranked by.
+    let header = ReportHeader {
+        ranking_metric: session_ranking_metric.name().to_string(),
+    };
     // finally we write these results to file in the given output directory
-    let todays_camera_stats_path = crate::configuration::todays_camera_stats_file_path(to_path);
+    let todays_camera_stats_path =
+        crate::configuration::todays_camera_stats_file_path(to_path.clone(), report_format);
+    // The candidates are ranked while the report is written, hence the ranking is part of this stage.
+    let write_span =
+        tracing::info_span!("write", report = ?todays_camera_stats_path.as_os_str()).entered();
+    let write_started = Instant::now();
     let outfile = File::create(todays_camera_stats_path.as_path()).with_context(|| {
         format!(
             "Failed to create file: {:?}",
             todays_camera_stats_path.as_path().as_os_str()
         )
     })?;
-    let mut buf_writer = BufWriter::with_capacity(400_000, outfile);
-    crate::writing::write_records(
-        &mut buf_writer,
-        best_avg_pics_over_seven_days_by_camera_iter,
-        4000,
+    let buf_writer = BufWriter::with_capacity(400_000, outfile);
+    match report_format {
+        ReportFormat::Pipe => crate::writing::write_report_lines(
+            buf_writer,
+            LineFormat::Pipe,
+            precision,
+            &header,
+            best_avg_pics_over_seven_days_by_camera_iter,
+        )?,
+        ReportFormat::JsonLines => crate::writing::write_report_lines(
+            buf_writer,
+            LineFormat::JsonLines,
+            precision,
+            &header,
+            best_avg_pics_over_seven_days_by_camera_iter,
+        )?,
+        ReportFormat::Parquet => {
+            let mut parquet_writer = ParquetReportWriter::new(
+                buf_writer,
+                common_utils::date_utils::today_ymd(),
+                common_utils::date_utils::NUMBER_OF_DAYS_IN_WINDOW,
+            )
+            .with_context(|| "Failed to create a Parquet writer")?;
+            parquet_writer
+                .append_key_value_metadata("ranking_metric", session_ranking_metric.name());
+            crate::writing::write_parquet_records(
+                parquet_writer,
+                best_avg_pics_over_seven_days_by_camera_iter,
+            )?
+        }
+    }
+    tracing::info!("the top 100 sessions by camera have been saved");
+    drop(write_span);
+    metrics.add_stage_duration("write", write_started.elapsed());
+
+    let camera_summaries_iter = camera_summaries_over_seven_days
+        .mapper
+        .into_iter()
+        .sorted_by_key(|(camera_id, _summary)| *camera_id)
+        .map(|(camera_id, summary)| CameraSummaryRecord::new(camera_id, summary));
+    let todays_camera_summary_path =
+        crate::configuration::todays_camera_summary_file_path(to_path.clone(), report_format);
+    let write_span =
+        tracing::info_span!("write", report = ?todays_camera_summary_path.as_os_str()).entered();
+    let write_started = Instant::now();
+    let outfile = File::create(todays_camera_summary_path.as_path()).with_context(|| {
+        format!(
+            "Failed to create file: {:?}",
+            todays_camera_summary_path.as_path().as_os_str()
+        )
+    })?;
+    let mut buf_writer = BufWriter::new(outfile);
+    match report_format {
+        ReportFormat::Pipe => {
+            crate::writing::write_records(&mut buf_writer, camera_summaries_iter, 4000)?
+        }
+        ReportFormat::JsonLines => crate::writing::write_records(
+            &mut buf_writer,
+            camera_summaries_iter.map(JsonLine),
+            4000,
+        )?,
+        ReportFormat::Parquet => {
+            let parquet_writer =
+                ParquetTableWriter::new(buf_writer, crate::writing::CAMERA_SUMMARY_PARQUET_SCHEMA)
+                    .with_context(|| "Failed to create a Parquet writer")?;
+            let report_date = common_utils::date_utils::today_ymd().into_string();
+            crate::writing::write_parquet_table(
+                parquet_writer,
+                camera_summaries_iter.map(|record| {
+                    record.parquet_row(
+                        &report_date,
+                        common_utils::date_utils::NUMBER_OF_DAYS_IN_WINDOW,
+                    )
+                }),
+            )?
+        }
+    }
+    tracing::info!("the camera summaries have been saved");
+    drop(write_span);
+    metrics.add_stage_duration("write", write_started.elapsed());
+
+    // The top sessions of each day are merged to the top sessions over the last seven days.
+    let merge_span = tracing::info_span!("merge", sessions = tracing::field::Empty).entered();
+    let merge_started = Instant::now();
+    let top_sessions_per_day = crate::daily_serializing::deserialize_daily_mappings_from_files(
+        SerializationFilesConfig::serialized_sessions_file_paths_last_seven_days(top_sessions),
     )?;
-    println!(
-        "The results have been saved as {:?}",
-        todays_camera_stats_path.as_os_str()
+    let top_sessions_over_seven_days =
+        crate::sessions::merge_top_sessions(top_sessions_per_day, top_sessions);
+    merge_span.record("sessions", top_sessions_over_seven_days.sessions.len());
+    drop(merge_span);
+    metrics.add_stage_duration("merge", merge_started.elapsed());
+    let session_records_iter = top_sessions_over_seven_days
+        .sessions
+        .into_iter()
+        .enumerate()
+        .map(|(idx, stats)| SessionStatsRecord::new(idx + 1, stats));
+    let todays_session_top_path = crate::configuration::todays_session_top_file_path(
+        to_path.clone(),
+        report_format,
+        top_sessions,
     );
+    let write_span =
+        tracing::info_span!("write", report = ?todays_session_top_path.as_os_str()).entered();
+    let write_started = Instant::now();
+    let outfile = File::create(todays_session_top_path.as_path()).with_context(|| {
+        format!(
+            "Failed to create file: {:?}",
+            todays_session_top_path.as_path().as_os_str()
+        )
+    })?;
+    let mut buf_writer = BufWriter::new(outfile);
+    match report_format {
+        ReportFormat::Pipe => {
+            crate::writing::write_records(&mut buf_writer, session_records_iter, 4000)?
+        }
+        ReportFormat::JsonLines => crate::writing::write_records(
+            &mut buf_writer,
+            session_records_iter.map(JsonLine),
+            4000,
+        )?,
+        ReportFormat::Parquet => {
+            let parquet_writer =
+                ParquetTableWriter::new(buf_writer, crate::writing::SESSION_TOP_PARQUET_SCHEMA)
+                    .with_context(|| "Failed to create a Parquet writer")?;
+            let report_date = common_utils::date_utils::today_ymd().into_string();
+            crate::writing::write_parquet_table(
+                parquet_writer,
+                session_records_iter.map(|record| {
+                    record.parquet_row(
+                        &report_date,
+                        common_utils::date_utils::NUMBER_OF_DAYS_IN_WINDOW,
+                    )
+                }),
+            )?
+        }
+    }
+    tracing::info!("the top {} sessions have been saved", top_sessions);
+    drop(write_span);
+    metrics.add_stage_duration("write", write_started.elapsed());
+    manifest.add_timing("merge_and_write", merging_started.elapsed());
+
+    for report_path in [
+        &todays_camera_stats_path,
+        &todays_camera_summary_path,
+        &todays_session_top_path,
+    ] {
+        manifest
+            .add_output(report_path)
+            .with_context(|| format!("Failed to hash the report {:?}", report_path.as_os_str()))?;
+    }
     Ok(())
 }
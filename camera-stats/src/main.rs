@@ -1,4 +1,5 @@
 use anyhow::{Context, Result};
+use common_utils::{logging::LogFormat, parsing_utils::NilIdRule, report_utils::ReportFormat};
 use structopt::StructOpt;
 
 /// Produces a text file containing the top 100 number of average pics per camera over the last seven days.
@@ -12,10 +13,91 @@ struct Cli {
     /// We will attempt to create this directory if it does not already exist.
     #[structopt(parse(from_os_str))]
     to_path: std::path::PathBuf,
+
+    /// The format of the resulting file: "pipe" writes camera_top100_YYYYMMDD.txt, "jsonl" writes camera_top100_YYYYMMDD.jsonl and "parquet" writes camera_top100_YYYYMMDD.parquet
+    #[structopt(long, default_value = "pipe", possible_values = &["pipe", "jsonl", "parquet"])]
+    format: ReportFormat,
+
+    /// The minimum number of trips (rentals of the camera) a session must have for it to be among the top 100 sessions of the camera
+    #[structopt(long, default_value = "1")]
+    min_trips: u32,
+
+    /// The number of decimals the average number of pics are written with in camera_top100_YYYYMMDD.txt.
+    /// If not given they are written in their shortest representation.
+    #[structopt(long)]
+    precision: Option<usize>,
+
+    /// The metric the sessions of a camera are ranked by: "raw-mean" is the average number of pics per trip,
+    /// "shrunk-mean" pulls the average of sessions with few trips towards the camera's mean over the last seven days
+    /// and "wilson-lower" is the lower bound of the Wilson score interval of the average
+    #[structopt(long, default_value = "raw-mean", possible_values = &["raw-mean", "shrunk-mean", "wilson-lower"])]
+    ranking_metric: camera_stats::RankingMetricKind,
+
+    /// The value the top 100 sessions of a camera are selected by: "avg" is the average number of pics per trip,
+    /// "total" the total number of pics and "max" the largest number of pics in a single trip of the session.
+    /// Only "avg" can be combined with a --ranking-metric other than "raw-mean"
+    #[structopt(long, default_value = "avg", possible_values = &["avg", "total", "max"])]
+    metric: camera_stats::SessionMetric,
+
+    /// The number of sessions with the most pics over all participants to write to session_top_K_YYYYMMDD.txt
+    #[structopt(long, default_value = "100")]
+    top_sessions: usize,
+
+    /// What to do with records whose user or session id is the nil UUID: "accept" processes them like any other record,
+    /// "reject" fails on the first log containing them and "quarantine" skips them and writes them to ./quarantined_records
+    #[structopt(long, default_value = "accept", possible_values = &["accept", "reject", "quarantine"])]
+    nil_ids: NilIdRule,
+
+    /// Wait for another run using the same state directory to finish instead of failing immediately
+    #[structopt(long, conflicts_with = "no-wait")]
+    wait: bool,
+
+    /// Fail immediately if another run is using the same state directory. This is the default
+    #[structopt(long)]
+    no_wait: bool,
+
+    /// Write the metrics of the run to this file in the Prometheus text format, e.g. to the textfile directory of the node_exporter.
+    /// The file is written whether the run succeeds or fails
+    #[structopt(long, parse(from_os_str))]
+    metrics_file: Option<std::path::PathBuf>,
+
+    /// The number of unprocessed session logs that are processed concurrently, e.g. to catch up after an outage.
+    /// The memory budget of the run is split between them
+    #[structopt(long, default_value = "1")]
+    jobs: usize,
+
+    /// Keep running and regenerate the reports whenever a session log of the last seven days has been completed, i.e. once a
+    /// safari-sessions-YYYYMMDD.log.done marker has been written next to it or once it has stopped growing. Stop with Ctrl-C
+    #[structopt(long)]
+    watch: bool,
+
+    /// With --watch, the number of seconds a session log without a marker must not grow for to be considered complete
+    #[structopt(long, default_value = "60")]
+    settle_seconds: u64,
+
+    /// Log more details, e.g. the batches written to disk. Can be repeated
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Log less, i.e. only warnings. Can be repeated to only log errors or nothing at all
+    #[structopt(short, long, parse(from_occurrences))]
+    quiet: u8,
+
+    /// The format of the logs written to stderr: "text" is human-readable and "json" writes one JSON object per line.
+    /// Every stage of a run (sort, which also parses the log, extract, persist, merge, write) is logged with its duration when it ends
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    log_format: LogFormat,
 }
 
 fn main() -> Result<()> {
     let args = Cli::from_args();
+    common_utils::logging::init(
+        common_utils::logging::level_filter(args.verbose, args.quiet),
+        args.log_format,
+    );
+    // Ctrl-C (or SIGTERM) cancels the run gracefully, removing temporary and partially written files.
+    common_utils::cancellation::install_handler()
+        .with_context(|| "Failed to install the signal handler")?;
     let (from_path, to_path) = (args.from_path, args.to_path);
     if !to_path.exists() {
         std::fs::create_dir_all(to_path.as_path()).with_context(|| {
@@ -36,5 +118,35 @@ fn main() -> Result<()> {
             )
         })?;
     }
-    camera_stats::run(from_path, to_path)
+    let mut options = camera_stats::RunOptions {
+        report_format: args.format,
+        min_trips: args.min_trips,
+        precision: args.precision,
+        ranking_metric: args.ranking_metric,
+        metric: args.metric,
+        top_sessions: args.top_sessions,
+        nil_ids: args.nil_ids,
+        wait_for_lock: args.wait && !args.no_wait,
+        metrics_file: args.metrics_file,
+        jobs: args.jobs,
+        completed_logs: None,
+    };
+    if args.watch {
+        common_utils::watching::watch(
+            from_path.clone(),
+            std::time::Duration::from_secs(args.settle_seconds),
+            |completed_logs| {
+                options.completed_logs = Some(completed_logs.clone());
+                camera_stats::run(from_path.clone(), to_path.clone(), &options)
+            },
+        )
+        .with_context(|| {
+            format!(
+                "Failed to watch the session log directory: {:?}",
+                from_path.as_os_str()
+            )
+        })
+    } else {
+        camera_stats::run(from_path, to_path, &options)
+    }
 }
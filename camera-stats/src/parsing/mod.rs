@@ -6,6 +6,8 @@ use uuid::Uuid;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct CameraRecord {
+    // The user id is only needed to count the participants of a session (see crate::sessions).
+    pub(crate) user_id: Uuid,
     pub(crate) camera_id: u8,
     pub(crate) session_id: Uuid,
     pub(crate) nb_pics: u8,
@@ -14,6 +16,7 @@ pub(crate) struct CameraRecord {
 impl From<Record> for CameraRecord {
     fn from(record: Record) -> Self {
         Self {
+            user_id: record.user_id,
             camera_id: record.camera_id,
             session_id: record.session_id,
             nb_pics: record.nb_pics,
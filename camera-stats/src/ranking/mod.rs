@@ -0,0 +1,341 @@
+// This module contains the metrics the sessions of a camera can be ranked by.
+//
+// A session is described by the number of pics taken with the camera in it and the number of trips (rentals of the camera).
+// Ranking by the plain average favours sessions with few trips, hence we also provide metrics that take the amount of
+// evidence into account. These need statistics of the camera over the whole window (see the summarizing module),
+// which is why sessions are only ranked once the daily candidates have been merged.
+use std::{cmp::Ordering, fmt::Display, str::FromStr};
+
+use uuid::Uuid;
+
+use crate::{extracting::CameraSessionCandidates, summarizing::CameraSummary};
+
+/// The metrics the sessions of a camera can be ranked by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RankingMetricKind {
+    /// The average number of pics per trip.
+    #[default]
+    RawMean,
+    /// The average number of pics per trip shrunk towards the camera's mean over the window.
+    ShrunkMean,
+    /// The lower bound of the Wilson score interval of the average number of pics per trip.
+    WilsonLower,
+}
+
+impl FromStr for RankingMetricKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw-mean" => Ok(RankingMetricKind::RawMean),
+            "shrunk-mean" => Ok(RankingMetricKind::ShrunkMean),
+            "wilson-lower" => Ok(RankingMetricKind::WilsonLower),
+            other => Err(format!(
+                "unknown ranking metric: {}. Expected one of: raw-mean, shrunk-mean, wilson-lower",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for RankingMetricKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.metric().name())
+    }
+}
+
+impl RankingMetricKind {
+    pub(crate) fn metric(&self) -> Box<dyn RankingMetric> {
+        match self {
+            RankingMetricKind::RawMean => Box::new(RawMean),
+            RankingMetricKind::ShrunkMean => Box::new(ShrunkMean {
+                prior_trips: ShrunkMean::DEFAULT_PRIOR_TRIPS,
+            }),
+            RankingMetricKind::WilsonLower => Box::new(WilsonLower {
+                z: WilsonLower::DEFAULT_Z,
+            }),
+        }
+    }
+}
+
+/// The value of a session of a camera that its top 100 sessions are selected by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SessionMetric {
+    /// The average number of pics per trip, ranked according to the chosen RankingMetricKind.
+    #[default]
+    Avg,
+    /// The total number of pics taken with the camera in the session.
+    Total,
+    /// The largest number of pics taken with the camera in a single trip of the session.
+    Max,
+}
+
+impl FromStr for SessionMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "avg" => Ok(SessionMetric::Avg),
+            "total" => Ok(SessionMetric::Total),
+            "max" => Ok(SessionMetric::Max),
+            other => Err(format!(
+                "unknown metric: {}. Expected one of: avg, total, max",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for SessionMetric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SessionMetric::Avg => "avg",
+            SessionMetric::Total => "total",
+            SessionMetric::Max => "max",
+        })
+    }
+}
+
+impl SessionMetric {
+    // The metric the candidates are ranked by. Only averages can be ranked in different ways,
+    // totals and maxima are simply ranked by the number of pics stored for the session.
+    pub(crate) fn ranking_metric(&self, kind: RankingMetricKind) -> Box<dyn RankingMetric> {
+        match self {
+            SessionMetric::Avg => kind.metric(),
+            SessionMetric::Total => Box::new(PicsCount { name: "total" }),
+            SessionMetric::Max => Box::new(PicsCount { name: "max" }),
+        }
+    }
+}
+
+// A metric scoring a session of a camera.
+// Implementations must be increasing in sum_pics when trips is fixed, as this is what CameraSessionCandidates relies on.
+pub(crate) trait RankingMetric {
+    // The name of the metric, as accepted on the command line and written to the report header.
+    fn name(&self) -> &'static str;
+
+    // The score of a session in which the camera took sum_pics pics over the given number of trips.
+    // The summary contains the statistics of the camera over the whole window.
+    fn score(&self, sum_pics: u32, trips: u32, summary: &CameraSummary) -> f64;
+
+    // Compares the scores of two sessions of the same camera.
+    fn compare(
+        &self,
+        (sum_pics, trips): (u32, u32),
+        (other_sum_pics, other_trips): (u32, u32),
+        summary: &CameraSummary,
+    ) -> Ordering {
+        self.score(sum_pics, trips, summary)
+            .partial_cmp(&self.score(other_sum_pics, other_trips, summary))
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+pub(crate) struct RawMean;
+
+impl RankingMetric for RawMean {
+    fn name(&self) -> &'static str {
+        "raw-mean"
+    }
+
+    fn score(&self, sum_pics: u32, trips: u32, _summary: &CameraSummary) -> f64 {
+        sum_pics as f64 / trips.max(1) as f64
+    }
+
+    // The averages are compared exactly by cross multiplication.
+    fn compare(
+        &self,
+        (sum_pics, trips): (u32, u32),
+        (other_sum_pics, other_trips): (u32, u32),
+        _summary: &CameraSummary,
+    ) -> Ordering {
+        (sum_pics as u64 * other_trips.max(1) as u64)
+            .cmp(&(other_sum_pics as u64 * trips.max(1) as u64))
+    }
+}
+
+// Scores a session by the number of pics stored for it, i.e. its total number of pics or its best single trip
+// depending on the SessionMetric the candidates were selected by.
+pub(crate) struct PicsCount {
+    name: &'static str,
+}
+
+impl RankingMetric for PicsCount {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn score(&self, sum_pics: u32, _trips: u32, _summary: &CameraSummary) -> f64 {
+        sum_pics as f64
+    }
+}
+
+// The average of the session after adding prior_trips imaginary trips with the camera's mean number of pics per trip.
+// Sessions with few trips are thereby pulled towards the camera's mean, while sessions with many trips are hardly affected.
+pub(crate) struct ShrunkMean {
+    prior_trips: f64,
+}
+
+impl ShrunkMean {
+    const DEFAULT_PRIOR_TRIPS: f64 = 5.0;
+}
+
+impl RankingMetric for ShrunkMean {
+    fn name(&self) -> &'static str {
+        "shrunk-mean"
+    }
+
+    fn score(&self, sum_pics: u32, trips: u32, summary: &CameraSummary) -> f64 {
+        let prior_mean = summary.mean_pics_per_trip().unwrap_or(0.0);
+        (sum_pics as f64 + self.prior_trips * prior_mean) / (trips as f64 + self.prior_trips)
+    }
+}
+
+// The lower bound of the Wilson score interval with the given z value (1.96 corresponds to 95% confidence).
+// The Wilson interval is defined for proportions, hence we treat the average number of pics per trip as a proportion
+// of the largest number of pics the camera took in a single trip during the window, and scale the bound back afterwards.
+pub(crate) struct WilsonLower {
+    z: f64,
+}
+
+impl WilsonLower {
+    const DEFAULT_Z: f64 = 1.96;
+}
+
+impl RankingMetric for WilsonLower {
+    fn name(&self) -> &'static str {
+        "wilson-lower"
+    }
+
+    fn score(&self, sum_pics: u32, trips: u32, summary: &CameraSummary) -> f64 {
+        let max_pics_per_trip = summary
+            .pics_per_trip_histogram
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or(0) as f64;
+        if trips == 0 || max_pics_per_trip == 0.0 {
+            return 0.0;
+        }
+        let n = trips as f64;
+        let p = (sum_pics as f64 / n / max_pics_per_trip).min(1.0);
+        let z2 = self.z * self.z;
+        let lower_bound = (p + z2 / (2.0 * n)
+            - self.z * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt())
+            / (1.0 + z2 / n);
+        lower_bound.max(0.0) * max_pics_per_trip
+    }
+}
+
+// A session of a camera together with its score according to some ranking metric.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RankedSession {
+    pub(crate) session_id: Uuid,
+    pub(crate) sum_pics: u32,
+    pub(crate) trips: u32,
+    pub(crate) score: f64,
+}
+
+// Ranks the candidates of a camera by the given metric and returns (at most) the 100 best sessions, best first.
+// Sessions with equal scores are ordered by their number of trips (more trips first).
+pub(crate) fn top_100_sessions(
+    candidates: &CameraSessionCandidates,
+    metric: &dyn RankingMetric,
+    summary: &CameraSummary,
+) -> Vec<RankedSession> {
+    let mut sessions: Vec<(Uuid, u32, u32)> = candidates.iter().collect();
+    sessions.sort_by(|(_, sum_pics, trips), (_, other_sum_pics, other_trips)| {
+        metric
+            .compare(
+                (*other_sum_pics, *other_trips),
+                (*sum_pics, *trips),
+                summary,
+            )
+            .then(other_trips.cmp(trips))
+    });
+    sessions
+        .into_iter()
+        .take(100)
+        .map(|(session_id, sum_pics, trips)| RankedSession {
+            session_id,
+            sum_pics,
+            trips,
+            score: metric.score(sum_pics, trips, summary),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary_with_mean_two() -> CameraSummary {
+        let mut summary = CameraSummary::default();
+        summary.add_session();
+        for nb_pics in [1, 2, 3] {
+            summary.add_trip(nb_pics);
+        }
+        summary
+    }
+
+    fn candidates() -> CameraSessionCandidates {
+        let mut candidates = CameraSessionCandidates::default();
+        // A single lucky trip and a consistently good session with many trips.
+        candidates.update_on_improvement(&Uuid::from_u128(1), 3, 1);
+        candidates.update_on_improvement(&Uuid::from_u128(2), 28, 10);
+        candidates
+    }
+
+    #[test]
+    fn raw_mean_prefers_the_lucky_trip() {
+        let ranked = top_100_sessions(&candidates(), &RawMean, &summary_with_mean_two());
+        assert_eq!(Uuid::from_u128(1), ranked[0].session_id);
+        assert_eq!(3.0, ranked[0].score);
+    }
+
+    #[test]
+    fn shrunk_mean_and_wilson_lower_prefer_consistent_sessions() {
+        for kind in [
+            RankingMetricKind::ShrunkMean,
+            RankingMetricKind::WilsonLower,
+        ] {
+            let ranked = top_100_sessions(
+                &candidates(),
+                kind.metric().as_ref(),
+                &summary_with_mean_two(),
+            );
+            assert_eq!(Uuid::from_u128(2), ranked[0].session_id, "{}", kind);
+            assert!(ranked[0].score >= ranked[1].score);
+        }
+        // (3 + 5 * 2) / (1 + 5)
+        assert_eq!(
+            13.0 / 6.0,
+            RankingMetricKind::ShrunkMean
+                .metric()
+                .score(3, 1, &summary_with_mean_two())
+        );
+    }
+
+    #[test]
+    fn totals_are_ranked_by_the_number_of_pics() {
+        let metric = SessionMetric::Total.ranking_metric(RankingMetricKind::RawMean);
+        let ranked = top_100_sessions(&candidates(), metric.as_ref(), &summary_with_mean_two());
+        assert_eq!(Uuid::from_u128(2), ranked[0].session_id);
+        assert_eq!(28.0, ranked[0].score);
+        assert_eq!("total", metric.name());
+    }
+
+    #[test]
+    fn metric_names_round_trip() {
+        for kind in [
+            RankingMetricKind::RawMean,
+            RankingMetricKind::ShrunkMean,
+            RankingMetricKind::WilsonLower,
+        ] {
+            assert_eq!(Ok(kind), kind.to_string().parse());
+        }
+        for metric in [SessionMetric::Avg, SessionMetric::Total, SessionMetric::Max] {
+            assert_eq!(Ok(metric), metric.to_string().parse());
+        }
+    }
+}
@@ -0,0 +1,239 @@
+// This module contains functionality related to the global top sessions, i.e. the sessions with the most pics over all participants
+// regardless of cameras and users.
+//
+// The records of a session are adjacent in the iterator produced by crate::sorting::sort_camera_records, hence the statistics of
+// a session can be accumulated while extracting the camera stats in the same pass (see SessionStatsAccumulator).
+// The stats of a session that may cross midnight are kept as partials, which are combined over the days in merge_top_sessions.
+use std::{collections::HashMap, ops::AddAssign};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::parsing::CameraRecord;
+
+// The statistics of a single session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SessionStats {
+    pub(crate) session_id: Uuid,
+    // The total number of pics taken by all participants in the session.
+    pub(crate) total_pics: u32,
+    // The number of distinct users that took part in the session.
+    pub(crate) participants: u32,
+    // The number of trips in the session. Every participant uploads a record at the end of each trip,
+    // hence this is the largest number of records uploaded by a single participant.
+    pub(crate) trips: u32,
+}
+
+impl SessionStats {
+    // Sessions are ranked by their total number of pics, ties are broken in favour of the smallest session id.
+    // Sorting by this key in ascending order hence yields the best session first.
+    fn rank_key(&self) -> (std::cmp::Reverse<u32>, Uuid) {
+        (std::cmp::Reverse(self.total_pics), self.session_id)
+    }
+}
+
+// Accumulates the statistics of the session currently being visited in an iterator of camera records sorted by session id.
+#[derive(Debug, Default)]
+pub(crate) struct SessionStatsAccumulator {
+    session_id: Option<Uuid>,
+    total_pics: u32,
+    // The number of records uploaded by each participant of the session.
+    records_by_user: HashMap<Uuid, u32>,
+}
+
+impl SessionStatsAccumulator {
+    // Adds the record to the statistics of its session. If the record belongs to a new session the statistics
+    // of the previous session are complete and returned.
+    pub(crate) fn add_record(&mut self, record: &CameraRecord) -> Option<SessionStats> {
+        let finished = if self.session_id != Some(record.session_id) {
+            let finished = self.finish();
+            self.session_id = Some(record.session_id);
+            finished
+        } else {
+            None
+        };
+        self.total_pics += record.nb_pics as u32;
+        *self.records_by_user.entry(record.user_id).or_default() += 1;
+        finished
+    }
+
+    // Returns the statistics of the current session (if any) and resets the accumulator.
+    pub(crate) fn finish(&mut self) -> Option<SessionStats> {
+        let session_id = self.session_id.take()?;
+        let stats = SessionStats {
+            session_id,
+            total_pics: self.total_pics,
+            participants: self.records_by_user.len() as u32,
+            trips: self.records_by_user.values().copied().max().unwrap_or(0),
+        };
+        self.total_pics = 0;
+        self.records_by_user.clear();
+        Some(stats)
+    }
+}
+
+// Combines the stats of the parts of a session that crossed midnight.
+// A participant may have played in both parts, hence the largest number of participants is a lower bound.
+impl AddAssign for SessionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.total_pics += other.total_pics;
+        self.participants = self.participants.max(other.participants);
+        self.trips += other.trips;
+    }
+}
+
+// The (at most) size sessions with the most pics, ordered from best to worst.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TopSessions {
+    size: usize,
+    pub(crate) sessions: Vec<SessionStats>,
+    // The partial stats of the sessions that may cross midnight, by session id.
+    boundary_partials: HashMap<Uuid, SessionStats>,
+}
+
+impl TopSessions {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            size,
+            sessions: Vec::with_capacity(size),
+            boundary_partials: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn push_boundary_partial(&mut self, stats: SessionStats) {
+        match self.boundary_partials.get_mut(&stats.session_id) {
+            Some(partial) => *partial += stats,
+            None => {
+                self.boundary_partials.insert(stats.session_id, stats);
+            }
+        }
+    }
+
+    // Pushes the combined partials to the top sessions. This must only be done once all days have been merged.
+    // A session that was a boundary session on one day may have been a regular session on another day, in which case
+    // it may already be among the top sessions. Those stats are folded into the partial stats, so that the session is listed only once.
+    fn stitch_boundary_partials(&mut self) {
+        let partials: Vec<SessionStats> = self
+            .boundary_partials
+            .drain()
+            .map(|(_, stats)| stats)
+            .collect();
+        for mut stats in partials {
+            while let Some(idx) = self
+                .sessions
+                .iter()
+                .position(|session| session.session_id == stats.session_id)
+            {
+                stats += self.sessions.remove(idx);
+            }
+            self.push(stats);
+        }
+    }
+
+    pub(crate) fn push(&mut self, stats: SessionStats) {
+        let is_improvement = self.sessions.len() < self.size
+            || self
+                .sessions
+                .last()
+                .is_some_and(|worst| stats.rank_key() < worst.rank_key());
+        if is_improvement {
+            let idx = self
+                .sessions
+                .partition_point(|session| session.rank_key() <= stats.rank_key());
+            self.sessions.insert(idx, stats);
+            self.sessions.truncate(self.size);
+        }
+    }
+}
+
+// Merges the top sessions of another day into these top sessions.
+impl AddAssign for TopSessions {
+    fn add_assign(&mut self, other: Self) {
+        for stats in other.sessions {
+            self.push(stats);
+        }
+        for (_, stats) in other.boundary_partials {
+            self.push_boundary_partial(stats);
+        }
+    }
+}
+
+// Takes a vector of TopSessions and merges them together to the top sessions of the given size.
+// Sessions that crossed midnight are stitched together from their partial stats.
+pub(crate) fn merge_top_sessions(
+    top_sessions_per_day: Vec<TopSessions>,
+    size: usize,
+) -> TopSessions {
+    let mut merged = TopSessions::new(size);
+    for top_sessions in top_sessions_per_day {
+        merged += top_sessions;
+    }
+    merged.stitch_boundary_partials();
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(session_id: u128, user_id: u128, nb_pics: u8) -> CameraRecord {
+        CameraRecord {
+            user_id: Uuid::from_u128(user_id),
+            camera_id: 1,
+            session_id: Uuid::from_u128(session_id),
+            nb_pics,
+        }
+    }
+
+    #[test]
+    fn accumulator_yields_the_stats_of_each_session() {
+        let mut accumulator = SessionStatsAccumulator::default();
+        let records = [
+            record(1, 10, 2),
+            record(1, 11, 3),
+            record(1, 10, 1),
+            record(2, 12, 4),
+        ];
+        let mut finished: Vec<_> = records
+            .iter()
+            .filter_map(|record| accumulator.add_record(record))
+            .collect();
+        finished.extend(accumulator.finish());
+        assert_eq!(
+            vec![
+                SessionStats {
+                    session_id: Uuid::from_u128(1),
+                    total_pics: 6,
+                    participants: 2,
+                    trips: 2
+                },
+                SessionStats {
+                    session_id: Uuid::from_u128(2),
+                    total_pics: 4,
+                    participants: 1,
+                    trips: 1
+                }
+            ],
+            finished
+        );
+    }
+
+    #[test]
+    fn top_sessions_keep_the_sessions_with_the_most_pics() {
+        let stats = |session_id: u128, total_pics: u32| SessionStats {
+            session_id: Uuid::from_u128(session_id),
+            total_pics,
+            participants: 10,
+            trips: 1,
+        };
+        let mut monday = TopSessions::new(2);
+        monday.push(stats(1, 5));
+        monday.push(stats(2, 8));
+        monday.push(stats(3, 6));
+        let mut tuesday = TopSessions::new(2);
+        tuesday.push(stats(0, 6));
+        let merged = merge_top_sessions(vec![monday, tuesday], 2);
+        // Sessions 0 and 3 both have 6 pics, the smallest session id ranks first.
+        assert_eq!(vec![stats(2, 8), stats(0, 6)], merged.sessions);
+    }
+}
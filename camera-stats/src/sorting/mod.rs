@@ -13,6 +13,7 @@ use crate::parsing::CameraRecord;
 use anyhow::{Context, Result};
 use extsort::SortedIterator;
 use std::cmp::Ordering;
+use tempfile::TempDir;
 
 // describes how the sorter should serialise and deserialise camera records
 // when the in-memory buffer gets full and we need to write and/or read progress
@@ -30,27 +31,45 @@ impl Sortable for CameraRecord {
 // takes an iterator of camera records and returns a new iterator over the records sorted lexicographically by session_id and camera_id.
 // The segment_size parameter determines the maximum number of camera records the sorter
 // may hold in-memory at any time.
+// The sorted segments are written to a temporary directory owned by the returned iterator, so that their size is known (see spilled_bytes).
 pub(crate) fn sort_camera_records<I: Iterator<Item = CameraRecord>>(
     record_iter: I,
     segment_size: usize,
-) -> Result<
-    SortedCameraRecordsIter<
-        impl (Fn(&CameraRecord, &CameraRecord) -> Ordering) + Send + Sync,
-    >,
-> {
-    let sorter = customized_external_sorter(segment_size);
-    let sorted_iter = sorter.sort_by(record_iter, |x, y| match x.session_id.cmp(&y.session_id) {
+) -> Result<SortedCameraRecordsIter<CameraRecordsComparator>> {
+    let sort_dir = tempfile::tempdir()
+        .with_context(|| "Failed to create a temporary directory for the sorted segments")?;
+    let sorter =
+        customized_external_sorter(segment_size).with_sort_dir(sort_dir.path().to_path_buf());
+    let sorted_iter = sorter.sort_by(record_iter, by_session_id_and_camera_id as CameraRecordsComparator)
+        .with_context(|| "Failed to sort the CameraRecords lexicographically with respect to session id followed by camera id")?;
+    let spilled_bytes = common_utils::file_utils::directory_size(sort_dir.path())
+        .with_context(|| "Failed to determine the size of the sorted segments")?;
+    Ok(SortedCameraRecordsIter::new(
+        sorted_iter,
+        sort_dir,
+        spilled_bytes,
+    ))
+}
+
+// The comparator is a plain function pointer (rather than a closure) so that the sorted iterator does not borrow from the
+// iterator of unsorted records. The latter may then borrow state that is inspected once sorting has completed.
+pub(crate) type CameraRecordsComparator = fn(&CameraRecord, &CameraRecord) -> Ordering;
+
+fn by_session_id_and_camera_id(x: &CameraRecord, y: &CameraRecord) -> Ordering {
+    match x.session_id.cmp(&y.session_id) {
         std::cmp::Ordering::Greater => std::cmp::Ordering::Greater,
         std::cmp::Ordering::Less => std::cmp::Ordering::Less,
         std::cmp::Ordering::Equal => x.camera_id.cmp(&y.camera_id),
-    }).with_context(|| "Failed to sort the CameraRecords lexicographically with respect to session id followed by camera id")?;
-    Ok(SortedCameraRecordsIter::new(sorted_iter))
+    }
 }
 
 pub(crate) struct SortedCameraRecordsIter<
     F: Fn(&CameraRecord, &CameraRecord) -> Ordering + Send + Sync,
 > {
     sorted_iter: SortedIterator<CameraRecord, F>,
+    // the sorted segments are removed once the iterator is dropped.
+    _sort_dir: TempDir,
+    spilled_bytes: u64,
 }
 impl<F: Fn(&CameraRecord, &CameraRecord) -> Ordering + Send + Sync> Iterator
     for SortedCameraRecordsIter<F>
@@ -64,7 +83,20 @@ impl<F: Fn(&CameraRecord, &CameraRecord) -> Ordering + Send + Sync> Iterator
 impl<F: Fn(&CameraRecord, &CameraRecord) -> Ordering + Send + Sync>
     SortedCameraRecordsIter<F>
 {
-    fn new(sorted_iter: SortedIterator<CameraRecord, F>) -> Self {
-        Self { sorted_iter }
+    fn new(
+        sorted_iter: SortedIterator<CameraRecord, F>,
+        sort_dir: TempDir,
+        spilled_bytes: u64,
+    ) -> Self {
+        Self {
+            sorted_iter,
+            _sort_dir: sort_dir,
+            spilled_bytes,
+        }
+    }
+
+    // The number of bytes of the sorted segments written to disk, zero if all records fit in memory.
+    pub(crate) fn spilled_bytes(&self) -> u64 {
+        self.spilled_bytes
     }
 }
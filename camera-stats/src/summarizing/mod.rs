@@ -0,0 +1,115 @@
+// This module contains functionality for computing summary metrics (trips, sessions, pics and the distribution of pics per trip)
+// for each camera. Just like the top 100 average number of pics these are computed per day and merged over the last seven days.
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::AddAssign,
+};
+
+// Summary metrics of a single camera.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CameraSummary {
+    // The number of rentals (i.e. records in the session logs) of the camera.
+    pub(crate) trips: u64,
+    // The number of session-days the camera was rented in: a session is counted once per day it appears in,
+    // hence a session spanning midnight or appearing on several days of the window is counted more than once.
+    pub(crate) sessions: u64,
+    // The total number of pics taken with the camera.
+    pub(crate) total_pics: u64,
+    // Maps a number of pics to the number of trips with exactly that many pics.
+    // Storing exact counts keeps the histogram mergeable, and there are at most 256 entries.
+    pub(crate) pics_per_trip_histogram: BTreeMap<u8, u64>,
+}
+
+impl CameraSummary {
+    // Records a single trip in which nb_pics pictures were taken.
+    pub(crate) fn add_trip(&mut self, nb_pics: u8) {
+        self.trips += 1;
+        self.total_pics += nb_pics as u64;
+        *self.pics_per_trip_histogram.entry(nb_pics).or_insert(0) += 1;
+    }
+
+    // Records that the camera was rented in yet another session.
+    pub(crate) fn add_session(&mut self) {
+        self.sessions += 1;
+    }
+
+    // The mean number of pics per trip. This is None for a camera that was never rented.
+    pub(crate) fn mean_pics_per_trip(&self) -> Option<f64> {
+        if self.trips == 0 {
+            None
+        } else {
+            Some(self.total_pics as f64 / self.trips as f64)
+        }
+    }
+}
+
+impl AddAssign for CameraSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.trips += other.trips;
+        self.sessions += other.sessions;
+        self.total_pics += other.total_pics;
+        for (nb_pics, trips) in other.pics_per_trip_histogram {
+            *self.pics_per_trip_histogram.entry(nb_pics).or_insert(0) += trips;
+        }
+    }
+}
+
+// This struct holds a map that takes a camera id to its summary metrics.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CameraSummaryMapping {
+    pub(crate) mapper: HashMap<u8, CameraSummary>,
+}
+
+impl AddAssign for CameraSummaryMapping {
+    fn add_assign(&mut self, other: Self) {
+        for (camera_id, summary) in other.mapper {
+            *self.mapper.entry(camera_id).or_default() += summary;
+        }
+    }
+}
+
+// Takes a vector of (daily) CameraSummaryMappings and merges them together into a single CameraSummaryMapping.
+pub(crate) fn merge_camera_summaries(mappings: Vec<CameraSummaryMapping>) -> CameraSummaryMapping {
+    let mut mapping = CameraSummaryMapping::default();
+    for other_mapping in mappings {
+        mapping += other_mapping;
+    }
+    mapping
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merged_summaries_add_up() {
+        let mut monday = CameraSummary::default();
+        monday.add_session();
+        monday.add_trip(2);
+        monday.add_trip(4);
+        let mut tuesday = CameraSummary::default();
+        tuesday.add_session();
+        tuesday.add_trip(4);
+        let mut mappings = vec![
+            CameraSummaryMapping::default(),
+            CameraSummaryMapping::default(),
+        ];
+        mappings[0].mapper.insert(1, monday);
+        mappings[1].mapper.insert(1, tuesday);
+        let merged = merge_camera_summaries(mappings);
+        let summary = &merged.mapper[&1];
+        assert_eq!(3, summary.trips);
+        assert_eq!(2, summary.sessions);
+        assert_eq!(10, summary.total_pics);
+        assert_eq!(Some(10.0 / 3.0), summary.mean_pics_per_trip());
+        assert_eq!(
+            vec![(2u8, 1u64), (4, 2)],
+            summary
+                .pics_per_trip_histogram
+                .iter()
+                .map(|(nb_pics, trips)| (*nb_pics, *trips))
+                .collect::<Vec<_>>()
+        );
+    }
+}
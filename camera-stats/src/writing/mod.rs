@@ -4,11 +4,13 @@ use std::{
     io::{BufWriter, Write},
 };
 
-use uuid::Uuid;
-
-use crate::extracting::CameraBestAvgPics;
+use common_utils::parquet_utils::{ParquetReportWriter, ParquetTableWriter, ParquetValue};
+use safari_stats_reports::{
+    CameraReportLine, CameraSession, LineFormat, ReportHeader, ReportWriter,
+};
+use serde::{Serialize, Serializer};
 
-use itertools::Itertools;
+use crate::{ranking::RankedSession, sessions::SessionStats, summarizing::CameraSummary};
 
 use anyhow::{Context, Result};
 
@@ -35,35 +37,476 @@ pub(crate) fn write_records<W: Write, Record: Display, I: IntoIterator<Item = Re
     Ok(())
 }
 
+// Write the given header and the records from an iterator in the given text format, one line per record.
+// The lines are written by the safari-stats-reports crate, which validates them so that they can always be read back.
+pub(crate) fn write_report_lines<W: Write, I: IntoIterator<Item = CameraBestAvgPicsRecord>>(
+    writer: W,
+    format: LineFormat,
+    precision: Option<usize>,
+    header: &ReportHeader,
+    record_iterator: I,
+) -> Result<()> {
+    let mut report_writer = ReportWriter::with_precision(writer, format, precision);
+    report_writer
+        .write_header(header)
+        .with_context(|| "Failed to write the report header".to_string())?;
+    for record in record_iterator {
+        let line = match format {
+            LineFormat::Pipe => record.padded_report_line(),
+            LineFormat::JsonLines => CameraReportLine::from(&record),
+        };
+        report_writer
+            .write(&line)
+            .with_context(|| format!("Failed to write the line of camera {}", record.camera_id))?;
+    }
+    report_writer
+        .finish()
+        .with_context(|| "Failed flushing all bytes".to_string())?
+        .flush()
+        .with_context(|| "Failed flushing all bytes".to_string())?;
+    Ok(())
+}
+
+// Write the records from an iterator to the given Parquet writer, one row per (camera, session) pair.
+// Unlike in the pipe format, the sessions are not padded to 100, so a camera with fewer sessions simply has fewer rows.
+pub(crate) fn write_parquet_records<
+    W: Write + Send,
+    I: IntoIterator<Item = CameraBestAvgPicsRecord>,
+>(
+    mut parquet_writer: ParquetReportWriter<W>,
+    record_iterator: I,
+) -> Result<()> {
+    for record in record_iterator {
+        let camera_id = record.camera_id.to_string();
+        for (idx, session) in record.sessions.iter().enumerate() {
+            parquet_writer
+                .write_row(
+                    camera_id.as_str(),
+                    idx + 1,
+                    &session.session_id,
+                    session.score,
+                )
+                .with_context(|| {
+                    format!(
+                        "Failed to write a row for camera {} to the Parquet file",
+                        camera_id
+                    )
+                })?;
+        }
+    }
+    parquet_writer
+        .close()
+        .with_context(|| "Failed to finish writing the Parquet file".to_string())?;
+    Ok(())
+}
+
+// Write the given rows to the given Parquet table writer, e.g. the rows of the camera summary report.
+pub(crate) fn write_parquet_table<W: Write + Send, I: IntoIterator<Item = Vec<ParquetValue>>>(
+    mut parquet_writer: ParquetTableWriter<W>,
+    rows: I,
+) -> Result<()> {
+    for row in rows {
+        parquet_writer
+            .write_row(row)
+            .with_context(|| "Failed to write a row to the Parquet file".to_string())?;
+    }
+    parquet_writer
+        .close()
+        .with_context(|| "Failed to finish writing the Parquet file".to_string())?;
+    Ok(())
+}
+
 // This struct corresponds to a line in the file "camera_top_100_YYYYMMDD.txt" file.
+// The value written for each session is its score according to the chosen ranking metric,
+// which by default is the average number of pics.
 #[derive(PartialEq, Debug)]
 pub(crate) struct CameraBestAvgPicsRecord {
     camera_id: u8,
-    sessions: [Uuid; 100],
-    avg_pics: [f32; 100],
+    // At most 100 sessions ordered from best to worst.
+    sessions: Vec<RankedSession>,
+    // The number of decimals the averages are written with in the pipe format.
+    // If this is None they are written in their shortest representation.
+    precision: Option<usize>,
 }
 
 impl CameraBestAvgPicsRecord {
-    pub fn new(camera_id: u8, camera_best_avg_pics: CameraBestAvgPics) -> Self {
+    pub fn new(camera_id: u8, sessions: Vec<RankedSession>, precision: Option<usize>) -> Self {
         Self {
             camera_id,
-            sessions: camera_best_avg_pics.sessions,
-            avg_pics: camera_best_avg_pics.avg_pics,
+            sessions,
+            precision,
+        }
+    }
+
+    // The line of the pipe format, which just like before sessions were ranked by pluggable metrics lists exactly 100 sessions.
+    // Missing sessions are padded with the nil session id and a value of 0.
+    fn padded_report_line(&self) -> CameraReportLine {
+        let mut line = CameraReportLine::from(self);
+        line.sessions.resize(100, CameraSession::padding());
+        line
+    }
+}
+
+// The formatting of the record (in both the pipe and the JSON Lines format) is delegated to the safari-stats-reports crate,
+// so that consumers of "camera_top_100_YYYYMMDD.txt" can read back exactly what we write.
+// Only the actual sessions are converted, the padding of the pipe format is added by padded_report_line.
+impl From<&CameraBestAvgPicsRecord> for CameraReportLine {
+    fn from(record: &CameraBestAvgPicsRecord) -> Self {
+        Self {
+            camera_id: record.camera_id,
+            sessions: record
+                .sessions
+                .iter()
+                .map(|session| CameraSession {
+                    session_id: session.session_id,
+                    avg_pics: session.score as f32,
+                    trips: Some(session.trips),
+                })
+                .collect(),
         }
     }
 }
 
 impl Display for CameraBestAvgPicsRecord {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        // There must be a more efficient way to do this!
-        let write_string = std::iter::once(format!("{}|", self.camera_id))
-            .chain(
-                self.sessions
-                    .iter()
-                    .zip(self.avg_pics.iter())
-                    .map(|(id, pic_score)| format!("{}:{},", id, pic_score)),
-            )
-            .join("");
-        write!(f, "{}", write_string)
+        match self.precision {
+            Some(precision) => write!(f, "{:.*}", precision, self.padded_report_line()),
+            None => write!(f, "{}", self.padded_report_line()),
+        }
+    }
+}
+
+impl Serialize for CameraBestAvgPicsRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        CameraReportLine::from(self).serialize(serializer)
+    }
+}
+
+// This struct corresponds to a line in the file "camera_summary_YYYYMMDD.txt" file.
+// In the pipe format a line is of the form
+// camera_id|trips|sessions|total_pics|mean_pics_per_trip|nb_pics1:trips1,nb_pics2:trips2,...
+// where the last part is the histogram of the number of pics per trip, ordered by the number of pics.
+// The sessions are session-days: a session appearing on several days of the window is counted once per day.
+// In the Parquet format every line is a row with the columns of CAMERA_SUMMARY_PARQUET_SCHEMA, where the histogram
+// is written in the same notation as in the pipe format.
+#[derive(PartialEq, Debug)]
+pub(crate) struct CameraSummaryRecord {
+    camera_id: u8,
+    summary: CameraSummary,
+}
+
+// The schema of the camera summary report in the Parquet format.
+pub(crate) const CAMERA_SUMMARY_PARQUET_SCHEMA: &str = "
+message camera_summary {
+    REQUIRED INT32 camera_id;
+    REQUIRED INT64 trips;
+    REQUIRED INT64 sessions;
+    REQUIRED INT64 total_pics;
+    OPTIONAL DOUBLE mean_pics_per_trip;
+    REQUIRED BYTE_ARRAY pics_per_trip_histogram (UTF8);
+    REQUIRED BYTE_ARRAY report_date (UTF8);
+    REQUIRED INT32 window_days;
+}
+";
+
+impl CameraSummaryRecord {
+    pub fn new(camera_id: u8, summary: CameraSummary) -> Self {
+        Self { camera_id, summary }
+    }
+
+    // The histogram in the notation nb_pics1:trips1,nb_pics2:trips2,...
+    fn histogram(&self) -> String {
+        self.summary
+            .pics_per_trip_histogram
+            .iter()
+            .map(|(nb_pics, trips)| format!("{}:{},", nb_pics, trips))
+            .collect()
+    }
+
+    // The row of this record in the Parquet format (see CAMERA_SUMMARY_PARQUET_SCHEMA).
+    pub fn parquet_row(&self, report_date: &str, window_days: usize) -> Vec<ParquetValue> {
+        vec![
+            ParquetValue::Int32(self.camera_id as i32),
+            ParquetValue::Int64(self.summary.trips as i64),
+            ParquetValue::Int64(self.summary.sessions as i64),
+            ParquetValue::Int64(self.summary.total_pics as i64),
+            self.summary
+                .mean_pics_per_trip()
+                .map_or(ParquetValue::Null, ParquetValue::Double),
+            ParquetValue::Utf8(self.histogram()),
+            ParquetValue::Utf8(report_date.to_string()),
+            ParquetValue::Int32(window_days as i32),
+        ]
+    }
+}
+
+impl Display for CameraSummaryRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}|{}|{}|{}|{}|{}",
+            self.camera_id,
+            self.summary.trips,
+            self.summary.sessions,
+            self.summary.total_pics,
+            self.summary.mean_pics_per_trip().unwrap_or(0.0),
+            self.histogram()
+        )
+    }
+}
+
+// The shape of a histogram bucket in the JSON Lines format.
+#[derive(Serialize)]
+struct HistogramBucket {
+    nb_pics: u8,
+    trips: u64,
+}
+
+// The shape of a line in the JSON Lines format.
+#[derive(Serialize)]
+struct CameraSummaryLine {
+    camera_id: u8,
+    trips: u64,
+    sessions: u64,
+    total_pics: u64,
+    mean_pics_per_trip: Option<f64>,
+    pics_per_trip_histogram: Vec<HistogramBucket>,
+}
+
+impl Serialize for CameraSummaryRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        CameraSummaryLine {
+            camera_id: self.camera_id,
+            trips: self.summary.trips,
+            sessions: self.summary.sessions,
+            total_pics: self.summary.total_pics,
+            mean_pics_per_trip: self.summary.mean_pics_per_trip(),
+            pics_per_trip_histogram: self
+                .summary
+                .pics_per_trip_histogram
+                .iter()
+                .map(|(nb_pics, trips)| HistogramBucket {
+                    nb_pics: *nb_pics,
+                    trips: *trips,
+                })
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+// This struct corresponds to a line in the file "session_top_K_YYYYMMDD.txt" file.
+// In the pipe format a line is of the form
+// rank|session_id|total_pics|participants|trips
+// In the Parquet format every line is a row with the columns of SESSION_TOP_PARQUET_SCHEMA.
+#[derive(PartialEq, Debug, Serialize)]
+pub(crate) struct SessionStatsRecord {
+    rank: usize,
+    #[serde(flatten)]
+    stats: SessionStats,
+}
+
+// The schema of the top sessions report in the Parquet format.
+pub(crate) const SESSION_TOP_PARQUET_SCHEMA: &str = "
+message session_top {
+    REQUIRED INT32 rank;
+    REQUIRED BYTE_ARRAY session_id (UTF8);
+    REQUIRED INT64 total_pics;
+    REQUIRED INT64 participants;
+    REQUIRED INT64 trips;
+    REQUIRED BYTE_ARRAY report_date (UTF8);
+    REQUIRED INT32 window_days;
+}
+";
+
+impl SessionStatsRecord {
+    pub fn new(rank: usize, stats: SessionStats) -> Self {
+        Self { rank, stats }
+    }
+
+    // The row of this record in the Parquet format (see SESSION_TOP_PARQUET_SCHEMA).
+    pub fn parquet_row(&self, report_date: &str, window_days: usize) -> Vec<ParquetValue> {
+        vec![
+            ParquetValue::Int32(self.rank as i32),
+            ParquetValue::Utf8(self.stats.session_id.to_string()),
+            ParquetValue::Int64(self.stats.total_pics as i64),
+            ParquetValue::Int64(self.stats.participants as i64),
+            ParquetValue::Int64(self.stats.trips as i64),
+            ParquetValue::Utf8(report_date.to_string()),
+            ParquetValue::Int32(window_days as i32),
+        ]
+    }
+}
+
+impl Display for SessionStatsRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}|{}|{}|{}|{}",
+            self.rank,
+            self.stats.session_id,
+            self.stats.total_pics,
+            self.stats.participants,
+            self.stats.trips
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_utils::report_utils::JsonLine;
+    use uuid::Uuid;
+
+    fn ranked_session(session_id: u128, sum_pics: u32, trips: u32) -> RankedSession {
+        RankedSession {
+            session_id: Uuid::from_u128(session_id),
+            sum_pics,
+            trips,
+            score: sum_pics as f64 / trips as f64,
+        }
+    }
+
+    #[test]
+    fn json_line_contains_ranked_sessions() {
+        let sessions = vec![ranked_session(2, 14, 4), ranked_session(1, 2, 1)];
+        let record = CameraBestAvgPicsRecord::new(7, sessions, None);
+        let json: serde_json::Value = serde_json::from_str(&JsonLine(&record).to_string()).unwrap();
+        assert_eq!(7, json["camera_id"]);
+        assert_eq!(2, json["sessions"].as_array().unwrap().len());
+        assert_eq!(
+            serde_json::json!({"session_id": Uuid::from_u128(2), "value": 3.5, "trips": 4, "rank": 1}),
+            json["sessions"][0]
+        );
+        assert_eq!(2, json["sessions"][1]["rank"]);
+        assert_eq!(2.0, json["sessions"][1]["value"]);
+    }
+
+    #[test]
+    fn parquet_rows_are_only_written_for_actual_sessions() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let mut file = tempfile::tempfile().unwrap();
+        let parquet_writer = ParquetReportWriter::new(
+            file.try_clone().unwrap(),
+            common_utils::date_utils::today_ymd(),
+            7,
+        )
+        .unwrap();
+        let sessions = vec![ranked_session(2, 14, 4), ranked_session(1, 2, 1)];
+        write_parquet_records(
+            parquet_writer,
+            vec![CameraBestAvgPicsRecord::new(7, sessions, None)],
+        )
+        .unwrap();
+
+        std::io::Seek::rewind(&mut file).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        assert_eq!(2, reader.metadata().file_metadata().num_rows());
+    }
+
+    #[test]
+    fn averages_are_written_with_the_given_precision() {
+        let record = CameraBestAvgPicsRecord::new(7, vec![ranked_session(1, 2, 3)], Some(3));
+        assert!(record.to_string().starts_with(&format!(
+            "7|{}:0.667:3,{}:0.000:0,",
+            Uuid::from_u128(1),
+            Uuid::nil()
+        )));
+    }
+
+    #[test]
+    fn session_stats_record_is_formatted() {
+        let record = SessionStatsRecord::new(
+            1,
+            SessionStats {
+                session_id: Uuid::from_u128(3),
+                total_pics: 42,
+                participants: 10,
+                trips: 6,
+            },
+        );
+        assert_eq!(
+            format!("1|{}|42|10|6", Uuid::from_u128(3)),
+            record.to_string()
+        );
+        let json: serde_json::Value = serde_json::from_str(&JsonLine(&record).to_string()).unwrap();
+        assert_eq!(
+            serde_json::json!({"rank": 1, "session_id": Uuid::from_u128(3), "total_pics": 42, "participants": 10, "trips": 6}),
+            json
+        );
+        assert_eq!(
+            vec![
+                ParquetValue::Int32(1),
+                ParquetValue::Utf8(Uuid::from_u128(3).to_string()),
+                ParquetValue::Int64(42),
+                ParquetValue::Int64(10),
+                ParquetValue::Int64(6),
+                ParquetValue::Utf8("20261018".to_string()),
+                ParquetValue::Int32(7),
+            ],
+            record.parquet_row("20261018", 7)
+        );
+    }
+
+    #[test]
+    fn summary_record_is_formatted_with_histogram() {
+        let mut summary = CameraSummary::default();
+        summary.add_session();
+        summary.add_trip(3);
+        summary.add_trip(1);
+        summary.add_trip(3);
+        let record = CameraSummaryRecord::new(4, summary);
+        assert_eq!(
+            format!("4|3|1|7|{}|1:1,3:2,", 7.0 / 3.0),
+            record.to_string()
+        );
+        let json: serde_json::Value = serde_json::from_str(&JsonLine(&record).to_string()).unwrap();
+        assert_eq!(
+            serde_json::json!([{"nb_pics": 1, "trips": 1}, {"nb_pics": 3, "trips": 2}]),
+            json["pics_per_trip_histogram"]
+        );
+    }
+
+    #[test]
+    fn summary_records_are_written_as_parquet_rows() {
+        use parquet::{
+            file::reader::{FileReader, SerializedFileReader},
+            record::RowAccessor,
+        };
+
+        let mut summary = CameraSummary::default();
+        summary.add_session();
+        summary.add_trip(3);
+        let records = [
+            CameraSummaryRecord::new(4, summary),
+            CameraSummaryRecord::new(5, CameraSummary::default()),
+        ];
+        let mut file = tempfile::tempfile().unwrap();
+        let parquet_writer =
+            ParquetTableWriter::new(file.try_clone().unwrap(), CAMERA_SUMMARY_PARQUET_SCHEMA)
+                .unwrap();
+        write_parquet_table(
+            parquet_writer,
+            records
+                .iter()
+                .map(|record| record.parquet_row("20261018", 7)),
+        )
+        .unwrap();
+
+        std::io::Seek::rewind(&mut file).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(2, rows.len());
+        assert_eq!(4, rows[0].get_int(0).unwrap());
+        assert_eq!(3.0, rows[0].get_double(4).unwrap());
+        assert_eq!("3:1,", rows[0].get_string(5).unwrap());
+        // A camera without trips has no mean.
+        assert!(rows[1].get_double(4).is_err());
+        assert_eq!("20261018", rows[1].get_string(6).unwrap());
     }
 }
@@ -0,0 +1,43 @@
+//! # Cancellation
+//!
+//! This module lets a run of our applications be cancelled gracefully with Ctrl-C (SIGINT) or SIGTERM.
+//! The signal handler only records that cancellation has been requested. The long running loops check this
+//! and return an error, so that temporary directories and partially written state files are cleaned up
+//! while the error propagates.
+//!
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static CANCELLATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The exit code of a process terminated by a second signal, i.e. 128 + SIGINT.
+pub const EXIT_CODE_TERMINATED: i32 = 130;
+
+/// Installs a handler for SIGINT and SIGTERM that requests cancellation.
+/// A second signal terminates the process immediately, without any cleanup.
+pub fn install_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        if CANCELLATION_REQUESTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_CODE_TERMINATED);
+        }
+        tracing::warn!(
+            "cancelling. Press Ctrl-C again to terminate immediately without cleaning up"
+        );
+    })
+}
+
+/// Whether cancellation has been requested.
+pub fn is_requested() -> bool {
+    CANCELLATION_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Returns an error of kind Interrupted if cancellation has been requested.
+pub fn check() -> std::io::Result<()> {
+    if is_requested() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            "The run was cancelled",
+        ));
+    }
+    Ok(())
+}
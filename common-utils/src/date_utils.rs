@@ -3,23 +3,24 @@
 //! This module yields utilities for obtaining dates represented as strings of the form YYYYMMDD.
 //!
 
-use chrono::{Datelike, NaiveDate, Utc};
+use chrono::Utc;
 use regex::Regex;
 
+/// The number of days (including today) our daily reports are computed over.
+pub const NUMBER_OF_DAYS_IN_WINDOW: usize = 7;
+
 /// Produces today's date as a DateStamp
 pub fn today_ymd() -> DateStamp {
-    let today_utc = Utc::today();
-    let today = NaiveDate::from_ymd(today_utc.year(), today_utc.month(), today_utc.day());
+    let today = Utc::now().date_naive();
     DateStamp::from_ymd(today.format("%Y%m%d").to_string())
 }
 
 /// Produces a vector of the last seven dates formated as YYMMDD
 pub fn last_seven_days_ymd() -> Vec<DateStamp> {
-    let today_utc = Utc::today();
-    let today = NaiveDate::from_ymd(today_utc.year(), today_utc.month(), today_utc.day());
-    let mut dates = [today; 7];
-    for i in (0..6).rev() {
-        dates[i] = dates[i + 1].pred();
+    let today = Utc::now().date_naive();
+    let mut dates = [today; NUMBER_OF_DAYS_IN_WINDOW];
+    for i in (0..(NUMBER_OF_DAYS_IN_WINDOW - 1)).rev() {
+        dates[i] = dates[i + 1].pred_opt().unwrap();
     }
     dates
         .iter()
@@ -28,6 +29,12 @@ pub fn last_seven_days_ymd() -> Vec<DateStamp> {
         .collect()
 }
 
+/// Produces the date the given number of days before today as a DateStamp
+pub fn days_before_today_ymd(days: u32) -> DateStamp {
+    let date = Utc::now().date_naive() - chrono::Duration::days(days as i64);
+    DateStamp::from_ymd(date.format("%Y%m%d").to_string())
+}
+
 // Produces a vector of Strings of the form YYYYMMDD
 // The first entry is the date from 7 days earlier and the last is yesterday.
 pub fn previous_six_days() -> Vec<DateStamp> {
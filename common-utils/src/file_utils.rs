@@ -4,7 +4,10 @@
 //! associated with processing Safari log files.
 //!
 
-use std::path::PathBuf;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 
 use crate::date_utils::DateStamp;
 
@@ -31,6 +34,85 @@ impl SessionLogFilesConfig {
     pub const DAILY_SESSIONS_EXTENSION: &'static str = ".log";
 }
 
+/// Struct providing settings for the files records that fail validation are quarantined in (see crate::parsing_utils::RecordValidator).
+pub struct QuarantineFilesConfig;
+
+impl QuarantineFilesConfig {
+    /// The path to the directory where quarantined records are written.
+    pub const DIRECTORY_PATH: &'static str = "./quarantined_records";
+
+    /// The path of the file the given program quarantines the records of the session log file of the given date in,
+    /// i.e. "<program>-safari-sessions-YYYYMMDD.log". Each program has its own file since all of them parse the same logs.
+    pub fn file_path(program: &str, datestamp: DateStamp) -> PathBuf {
+        let mut path = PathBuf::from(Self::DIRECTORY_PATH);
+        path.push(format!(
+            "{}-{}{}{}",
+            program,
+            SessionLogFilesConfig::DAILY_SESSIONS_PREFIX,
+            datestamp.into_string(),
+            SessionLogFilesConfig::DAILY_SESSIONS_EXTENSION
+        ));
+        path
+    }
+}
+
+/// Guard for state files that are being written. The files are written to their temporary paths (see Self::temporary_path)
+/// and only renamed into place once all of them are complete, so a state file is never partially written, even if the process
+/// is killed. Otherwise a partially written state file could later be mistaken for the state of a processed log.
+/// Unless they are kept, the temporary files are removed when the guard is dropped, e.g. because an error
+/// (or a cancellation, see crate::cancellation) is propagated while writing them.
+pub struct StateFilesGuard {
+    paths: Vec<PathBuf>,
+}
+
+impl StateFilesGuard {
+    /// Guards the given files, which need not exist yet.
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        Self { paths }
+    }
+
+    /// The path a guarded file is written to before it is kept: the path with a .tmp suffix, in the same directory.
+    pub fn temporary_path(path: &Path) -> PathBuf {
+        let mut temporary_path = PathBuf::from(path);
+        temporary_path.as_mut_os_string().push(".tmp");
+        temporary_path
+    }
+
+    /// Renames the written files into place in the order they were guarded, to be called once all of them have been written
+    /// completely. The file marking the state as complete should hence be guarded last.
+    pub fn keep(mut self) -> io::Result<()> {
+        for path in self.paths.iter() {
+            fs::rename(Self::temporary_path(path), path)?;
+        }
+        self.paths.clear();
+        Ok(())
+    }
+}
+
+impl Drop for StateFilesGuard {
+    fn drop(&mut self) {
+        for temporary_path in self.paths.iter().map(|path| Self::temporary_path(path)) {
+            if temporary_path.exists() {
+                tracing::warn!(file = ?temporary_path.as_os_str(), "removing a partially written file");
+                // There is nothing more we can do if the removal fails.
+                let _ = fs::remove_file(temporary_path);
+            }
+        }
+    }
+}
+
+/// The total size in bytes of the files directly inside the given directory, e.g. a temporary directory before it is removed.
+pub fn directory_size(directory: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(directory)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
 /// Provides the metadata (date, and path) of todays session log file.
 pub fn todays_file_for_processing(session_directory: PathBuf) -> UnprocessedSessionLogFile {
     let today_ymd = crate::date_utils::today_ymd().into_string();
@@ -48,6 +130,18 @@ pub fn todays_file_for_processing(session_directory: PathBuf) -> UnprocessedSess
     UnprocessedSessionLogFile::new(DateStamp::from_ymd(today_ymd), todays_sessions_path)
 }
 
+/// The path of the session log file of the given date in the given directory.
+pub fn session_log_file_path(session_directory: PathBuf, datestamp: DateStamp) -> PathBuf {
+    let mut log_file_path = session_directory;
+    log_file_path.push(format!(
+        "{}{}{}",
+        SessionLogFilesConfig::DAILY_SESSIONS_PREFIX,
+        datestamp.into_string(),
+        SessionLogFilesConfig::DAILY_SESSIONS_EXTENSION
+    ));
+    log_file_path
+}
+
 /// Provides a vector of unprocessed session log files produced within the last seven days.
 /// If any of these log files do not exist in the specified directory an Error is placed
 /// at the corresponding position(s) in the vector.
@@ -61,11 +155,8 @@ pub fn unprocessed_session_log_files<F: (Fn(DateStamp) -> bool)>(
         Vec::new();
     for day in crate::date_utils::last_seven_days_ymd() {
         if !processed_on_date(day.clone()) {
-            let mut log_filename = SessionLogFilesConfig::DAILY_SESSIONS_PREFIX.to_string();
-            log_filename.push_str(day.clone().into_string().as_str());
-            log_filename.push_str(SessionLogFilesConfig::DAILY_SESSIONS_EXTENSION);
-            let mut log_file_path = session_log_files_directory.clone();
-            log_file_path.push(log_filename);
+            let log_file_path =
+                session_log_file_path(session_log_files_directory.clone(), day.clone());
             if !log_file_path.exists() {
                 let error = std::io::Error::new(
                     std::io::ErrorKind::NotFound,
@@ -82,3 +173,26 @@ pub fn unprocessed_session_log_files<F: (Fn(DateStamp) -> bool)>(
     }
     unprocessed_log_files
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_files_are_only_moved_into_place_when_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let (kept, removed) = (dir.path().join("kept"), dir.path().join("removed"));
+        let guard = StateFilesGuard::new(vec![kept.clone()]);
+        std::fs::write(StateFilesGuard::temporary_path(&kept), b"complete").unwrap();
+        assert!(!kept.exists());
+        guard.keep().unwrap();
+        {
+            let _guard = StateFilesGuard::new(vec![removed.clone(), dir.path().join("missing")]);
+            std::fs::write(StateFilesGuard::temporary_path(&removed), b"partial").unwrap();
+        }
+        assert_eq!(b"complete".to_vec(), std::fs::read(&kept).unwrap());
+        assert!(!StateFilesGuard::temporary_path(&kept).exists());
+        assert!(!removed.exists());
+        assert!(!StateFilesGuard::temporary_path(&removed).exists());
+    }
+}
@@ -2,6 +2,16 @@
 //!
 //! This library provides functionality needed in more than one of our Safari session stats applications.
 
+pub mod cancellation;
 pub mod date_utils;
 pub mod file_utils;
+pub mod locking;
+pub mod logging;
+pub mod manifest;
+pub mod metrics;
+pub mod parallel;
+pub mod parquet_utils;
 pub mod parsing_utils;
+pub mod progress;
+pub mod report_utils;
+pub mod watching;
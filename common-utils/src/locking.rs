@@ -0,0 +1,142 @@
+//! # Locking
+//!
+//! This module provides an advisory lock on a state directory, so that two runs of the same application
+//! (e.g. one started by cron while the previous one is still going) never process the same day at once.
+//! The lock is an exclusive flock on a lock file inside the state directory, which contains the PID of its holder.
+//! The operating system releases a flock when its holder dies, hence a lock can never outlive a crashed run.
+//! A PID left in the lock file only tells us that the previous run did not finish cleanly.
+//!
+
+use std::{
+    convert::TryFrom,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use fs2::FileExt;
+
+/// An exclusive lock on a state directory, held until it is dropped.
+pub struct StateDirectoryLock {
+    file: File,
+    stale_pid: Option<u32>,
+}
+
+impl StateDirectoryLock {
+    /// The name of the lock file inside the state directory.
+    pub const FILE_NAME: &'static str = ".lock";
+
+    /// Acquires the lock on the given state directory, which must exist.
+    ///
+    /// If the lock is held by another run, we either wait until it is released or fail immediately (with an error of kind WouldBlock)
+    /// depending on wait. We never wait for a holder that is no longer running, since such a lock is stale (e.g. held by a
+    /// child process that outlived the run or by a run on another host sharing the directory). An error naming the PID is returned instead.
+    pub fn acquire(state_directory: &Path, wait: bool) -> io::Result<Self> {
+        let path = state_directory.join(Self::FILE_NAME);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        if let Err(error) = file.try_lock_exclusive() {
+            if error.kind() != fs2::lock_contended_error().kind() {
+                return Err(error);
+            }
+            let holder = read_pid(&mut file)?;
+            if let Some(pid) = holder.filter(|pid| !is_running(*pid)) {
+                return Err(io::Error::other(format!(
+                    "{:?} is held by PID {}, which is no longer running. The lock is stale; it is released once every process sharing it has exited",
+                    path.as_os_str(),
+                    pid
+                )));
+            }
+            let holder = holder.map_or_else(
+                || "another run".to_string(),
+                |pid| format!("the run with PID {}", pid),
+            );
+            if !wait {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("{:?} is locked by {}", state_directory.as_os_str(), holder),
+                ));
+            }
+            tracing::info!(
+                directory = ?state_directory.as_os_str(),
+                "the directory is locked by {}. Waiting for it to finish",
+                holder
+            );
+            file.lock_exclusive()?;
+        }
+        // A PID left behind in the lock file belongs to a run that did not release its lock cleanly (see Drop).
+        let stale_pid = read_pid(&mut file)?;
+        if let Some(pid) = stale_pid {
+            tracing::warn!(
+                pid,
+                "the previous run did not finish cleanly. Its temporary files can be removed with `stats-admin gc`"
+            );
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(Self { file, stale_pid })
+    }
+
+    /// The PID of the previous holder if it did not release the lock cleanly.
+    pub fn stale_pid(&self) -> Option<u32> {
+        self.stale_pid
+    }
+}
+
+impl Drop for StateDirectoryLock {
+    fn drop(&mut self) {
+        // Clearing the PID marks a clean release. There is nothing more we can do if this fails,
+        // the lock itself is released when the file is closed in any case.
+        let _ = self.file.set_len(0);
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+// The PID stored in the lock file, if any.
+fn read_pid(file: &mut File) -> io::Result<Option<u32>> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut contents)?;
+    Ok(contents.trim().parse().ok())
+}
+
+// Whether a process with the given PID exists. Signal 0 only checks for existence, and EPERM means that the process
+// exists but belongs to another user.
+fn is_running(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_lock_is_exclusive_and_stale_pids_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = StateDirectoryLock::acquire(dir.path(), false).unwrap();
+        assert_eq!(None, lock.stale_pid());
+        let error = StateDirectoryLock::acquire(dir.path(), false)
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::WouldBlock, error.kind());
+        assert!(error.to_string().contains(&std::process::id().to_string()));
+        drop(lock);
+
+        // a run that was killed leaves its PID behind. PIDs never get this large on Linux.
+        std::fs::write(dir.path().join(StateDirectoryLock::FILE_NAME), "99999999").unwrap();
+        let lock = StateDirectoryLock::acquire(dir.path(), true).unwrap();
+        assert_eq!(Some(99999999), lock.stale_pid());
+        assert!(!is_running(99999999));
+    }
+}
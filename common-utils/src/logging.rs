@@ -0,0 +1,96 @@
+//! # Logging
+//!
+//! This module sets up the structured logging of our applications, which is based on the `tracing` facade.
+//! Progress and diagnostics are logged as events at the appropriate level, while the stages of a run
+//! (e.g. parse, persist, merge and write) are spans. The closing of every span is logged
+//! together with its fields (e.g. the number of records) and the time spent in it, so that slow stages can be spotted.
+//! The logs are written to stderr, either as human-readable text or as one JSON object per line.
+//!
+
+use std::{fmt::Display, io::IsTerminal, str::FromStr};
+
+use crate::progress;
+
+pub use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+
+/// The format logs are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!(
+                "unknown log format: {}. Expected one of: text, json",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+/// The most verbose level that is logged, given how often -v and -q were passed on the command line.
+/// The default is INFO, every -v lowers it by one level (down to TRACE) and every -q raises it by one level.
+/// Passing -q three times turns logging off.
+pub fn level_filter(verbose: u8, quiet: u8) -> LevelFilter {
+    const LEVELS: [LevelFilter; 6] = [
+        LevelFilter::OFF,
+        LevelFilter::ERROR,
+        LevelFilter::WARN,
+        LevelFilter::INFO,
+        LevelFilter::DEBUG,
+        LevelFilter::TRACE,
+    ];
+    let index = (3 + verbose as i32 - quiet as i32).clamp(0, LEVELS.len() as i32 - 1);
+    LEVELS[index as usize]
+}
+
+/// Installs the global logger writing events up to the given level in the given format to stderr.
+/// Events are written above the progress bars (see crate::progress).
+///
+/// # Panics
+/// If a global logger has already been installed.
+pub fn init(level: LevelFilter, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(|| progress::Stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(false).init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_flags_select_the_level() {
+        assert_eq!(LevelFilter::INFO, level_filter(0, 0));
+        assert_eq!(LevelFilter::TRACE, level_filter(4, 0));
+        assert_eq!(LevelFilter::WARN, level_filter(0, 1));
+        assert_eq!(LevelFilter::OFF, level_filter(0, 5));
+        assert_eq!(Ok(LogFormat::Json), "json".parse());
+    }
+}
@@ -0,0 +1,351 @@
+//! # Manifest
+//!
+//! This module describes the run manifest, a JSON file written next to the reports by every run of camera-stats and user-stats.
+//! It answers which inputs produced the reports, even weeks later: the version and configuration of the program, the days of the window
+//! and for each of them the session log it was computed from (path, size, SHA-256 hash, rows read and rejected) and whether it was
+//! recomputed by the run or reused from the daily state of an earlier run. It also records the timings and the peak memory of the run,
+//! as well as the hash of every report written. A run that fails still writes its manifest, with the error it failed with,
+//! so that the days it processed before failing can be told apart from those of the reports next to it.
+//!
+//! The session log of a reused day can only be described if the run that processed it saved a [SourceLog] alongside its daily state.
+//!
+
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{date_utils::DateStamp, parsing_utils::RecordValidator};
+
+/// The description of a run, see the module documentation.
+#[derive(Debug, Serialize)]
+pub struct RunManifest {
+    /// The name of the program.
+    pub tool: String,
+    /// The version of the program.
+    pub version: String,
+    /// When the run started, in RFC 3339 format.
+    pub started_at: String,
+    /// The date (YYYYMMDD) the reports were computed for, i.e. the last day of the window.
+    pub as_of: String,
+    /// The days (YYYYMMDD) the reports were computed over, the oldest first.
+    pub window: Vec<String>,
+    /// The options of the run as spelled on the command line, including the input and output directories.
+    pub config: serde_json::Value,
+    /// The days of the window, the oldest first.
+    pub days: Vec<DayManifest>,
+    /// The reports written by the run.
+    pub outputs: Vec<OutputFile>,
+    /// The time spent in each phase of the run in seconds.
+    pub timings: BTreeMap<String, f64>,
+    /// The peak resident set size of the process in bytes, if it is known.
+    pub peak_memory_bytes: Option<u64>,
+    pub status: RunStatus,
+    /// The error the run failed with, including its causes.
+    pub error: Option<String>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+/// Whether a run succeeded, i.e. wrote all of its reports, or failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+}
+
+/// A day of the window of a run.
+#[derive(Debug, Serialize)]
+pub struct DayManifest {
+    /// The date (YYYYMMDD) of the day.
+    pub date: String,
+    pub status: DayStatus,
+    /// The session log the daily state was computed from. Unknown for days reused from state saved before manifests were introduced.
+    pub source_log: Option<SourceLog>,
+    /// The time spent processing the session log in seconds, if the day was recomputed.
+    pub seconds: Option<f64>,
+}
+
+/// Whether a day was recomputed by a run or reused from the daily state of an earlier run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DayStatus {
+    Recomputed,
+    Reused,
+}
+
+/// A session log file the daily state was computed from. It is saved alongside the daily state, so that later runs reusing the state can describe it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceLog {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    /// The hex encoded SHA-256 hash of the session log file.
+    pub sha256: String,
+    /// The number of rows parsed from the session log file.
+    pub rows_read: u64,
+    /// The number of rows that were not processed, i.e. malformed rows and rows with a nil id that were rejected or quarantined.
+    pub rows_rejected: u64,
+}
+
+/// A report written by a run.
+#[derive(Debug, Serialize)]
+pub struct OutputFile {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    /// The hex encoded SHA-256 hash of the report.
+    pub sha256: String,
+}
+
+impl RunManifest {
+    /// Starts the manifest of a run of the given program (and version) with the given configuration. The window ends today.
+    pub fn new(tool: &str, version: &str, config: serde_json::Value) -> Self {
+        Self {
+            tool: tool.to_string(),
+            version: version.to_string(),
+            started_at: chrono::Local::now().to_rfc3339(),
+            as_of: crate::date_utils::today_ymd().into_string(),
+            window: crate::date_utils::last_seven_days_ymd()
+                .into_iter()
+                .map(DateStamp::into_string)
+                .collect(),
+            config,
+            days: Vec::new(),
+            outputs: Vec::new(),
+            timings: BTreeMap::new(),
+            peak_memory_bytes: None,
+            status: RunStatus::Succeeded,
+            error: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Records a day whose session log has been processed by the run in the given time.
+    pub fn add_recomputed_day(&mut self, date: DateStamp, source_log: SourceLog, time: Duration) {
+        self.days.push(DayManifest {
+            date: date.into_string(),
+            status: DayStatus::Recomputed,
+            source_log: Some(source_log),
+            seconds: Some(time.as_secs_f64()),
+        });
+    }
+
+    /// Records the days of the window that have not been recomputed as reused. Their session logs are loaded from the given state directory.
+    pub fn add_reused_days(&mut self, state_directory: &Path) -> io::Result<()> {
+        for date in self.window.clone() {
+            if self.days.iter().any(|day| day.date == date) {
+                continue;
+            }
+            let source_log = SourceLog::load(&SourceLog::file_path(
+                state_directory,
+                DateStamp::from_ymd(date.clone()),
+            ))?;
+            self.days.push(DayManifest {
+                date,
+                status: DayStatus::Reused,
+                source_log,
+                seconds: None,
+            });
+        }
+        self.days.sort_by(|a, b| a.date.cmp(&b.date));
+        Ok(())
+    }
+
+    /// Records a report written by the run.
+    pub fn add_output(&mut self, path: &Path) -> io::Result<()> {
+        self.outputs.push(OutputFile {
+            path: path.to_path_buf(),
+            size_bytes: std::fs::metadata(path)?.len(),
+            sha256: sha256_of_file(path)?,
+        });
+        Ok(())
+    }
+
+    /// Records the time spent in a phase of the run.
+    pub fn add_timing(&mut self, phase: &str, time: Duration) {
+        self.timings.insert(phase.to_string(), time.as_secs_f64());
+    }
+
+    /// Records that the run failed with the given error.
+    pub fn record_failure(&mut self, error: String) {
+        self.status = RunStatus::Failed;
+        self.error = Some(error);
+    }
+
+    /// Writes the manifest to the given file, after recording the total time and the peak memory of the run so far.
+    pub fn write(&mut self, path: &Path) -> io::Result<()> {
+        self.add_timing("total", self.started.elapsed());
+        self.peak_memory_bytes = peak_memory_bytes();
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+impl SourceLog {
+    /// The prefix of the files source logs are saved to in a state directory. Their suffix will be a date of the form YYYYMMDD.json.
+    pub const FILE_PREFIX: &'static str = "source-log-";
+
+    /// The file the source log of the given date is saved to in the given state directory.
+    pub fn file_path(state_directory: &Path, datestamp: DateStamp) -> PathBuf {
+        state_directory.join(format!(
+            "{}{}.json",
+            Self::FILE_PREFIX,
+            datestamp.into_string()
+        ))
+    }
+
+    /// Describes a session log file that has been parsed through the given digest and validator.
+    pub fn new(
+        path: &Path,
+        digest: &SourceDigest,
+        record_validator: &RecordValidator,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            size_bytes: std::fs::metadata(path)?.len(),
+            sha256: digest.to_hex(),
+            rows_read: record_validator.rows_read(),
+            rows_rejected: record_validator.rows_rejected(),
+        })
+    }
+
+    /// Loads a saved source log, None if it has not been saved.
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let reader = BufReader::new(File::open(path)?);
+        Ok(Some(serde_json::from_reader(reader)?))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+/// Computes the SHA-256 hash of the bytes read through the readers it wraps. The hash is shared with the readers,
+/// so that it can be taken after a reader has been consumed by an iterator chain that took ownership of it.
+#[derive(Clone, Default)]
+pub struct SourceDigest(Arc<Mutex<Sha256>>);
+
+impl SourceDigest {
+    pub fn wrap<R: Read>(&self, reader: R) -> DigestReader<R> {
+        DigestReader {
+            reader,
+            digest: self.clone(),
+        }
+    }
+
+    /// The hex encoded hash of the bytes read so far.
+    pub fn to_hex(&self) -> String {
+        format!("{:x}", self.0.lock().unwrap().clone().finalize())
+    }
+}
+
+/// A reader adding the bytes read to a SourceDigest.
+pub struct DigestReader<R> {
+    reader: R,
+    digest: SourceDigest,
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.digest.0.lock().unwrap().update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// The hex encoded SHA-256 hash of the given file.
+fn sha256_of_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// The peak resident set size of this process in bytes. It is reported in kilobytes on Linux and in bytes on macOS.
+fn peak_memory_bytes() -> Option<u64> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let max_rss = u64::try_from(usage.ru_maxrss).ok()?;
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_match_the_hashes_of_the_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("safari-sessions-20210101.log");
+        std::fs::write(&path, b"abc").unwrap();
+        let digest = SourceDigest::default();
+        let mut contents = String::new();
+        digest
+            .wrap(File::open(&path).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        // The SHA-256 test vector of "abc".
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(expected, digest.to_hex());
+        assert_eq!(expected, sha256_of_file(&path).unwrap());
+    }
+
+    #[test]
+    fn reused_days_are_described_by_their_saved_source_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = RunManifest::new("test", "0.1.0", serde_json::json!({}));
+        let today = crate::date_utils::today_ymd();
+        let source_log = SourceLog {
+            path: PathBuf::from("logs/safari-sessions.log"),
+            size_bytes: 3,
+            sha256: "abc".to_string(),
+            rows_read: 1,
+            rows_rejected: 0,
+        };
+        manifest.add_recomputed_day(today, source_log.clone(), Duration::from_secs(1));
+        let first_day = DateStamp::from_ymd(manifest.window[0].clone());
+        source_log
+            .save(&SourceLog::file_path(dir.path(), first_day))
+            .unwrap();
+        manifest.add_reused_days(dir.path()).unwrap();
+
+        assert_eq!(manifest.window.len(), manifest.days.len());
+        assert_eq!(DayStatus::Reused, manifest.days[0].status);
+        assert_eq!(Some(&source_log), manifest.days[0].source_log.as_ref());
+        assert_eq!(None, manifest.days[1].source_log);
+        assert_eq!(DayStatus::Recomputed, manifest.days[6].status);
+    }
+
+    #[test]
+    fn failed_runs_record_their_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        let mut manifest = RunManifest::new("test", "0.1.0", serde_json::json!({}));
+        manifest.record_failure("The run was cancelled".to_string());
+        manifest.write(&path).unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!("failed", written["status"]);
+        assert_eq!("The run was cancelled", written["error"]);
+    }
+}
@@ -0,0 +1,244 @@
+//! # Metrics
+//!
+//! This module exports the metrics of a run in the Prometheus text format, so that they can be scraped by the textfile collector of
+//! the node_exporter. They allow alerting on a daily job that stalled (the timestamp of its last run) or degraded (a failed run,
+//! many rejected rows or a slow stage) without parsing logs. Every metric is a gauge describing the last run of a program,
+//! which is named by the tool label, except for the timestamp of the last successful run, which is carried over from the
+//! previous file when a run fails.
+//!
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The metrics collected during a run. Metrics that are not known when a run fails are exported as collected so far.
+#[derive(Debug, Default)]
+pub struct RunMetrics {
+    /// The number of rows parsed from the session logs.
+    pub rows_parsed: u64,
+    /// The number of rows that were not processed, i.e. malformed rows and rows with a nil id that were rejected or quarantined.
+    pub rows_rejected: u64,
+    /// The number of days whose session log was processed.
+    pub days_processed: u64,
+    /// The number of days whose daily state was reused from an earlier run.
+    pub days_reused: u64,
+    /// The number of bytes written to temporary files, e.g. by the external sorter or as batches of FST maps.
+    pub temporary_bytes_written: u64,
+    stage_seconds: BTreeMap<&'static str, f64>,
+    output_entities: BTreeMap<&'static str, u64>,
+}
+
+impl RunMetrics {
+    /// Adds the given time to the time spent in a stage of the run, e.g. "parse" or "merge".
+    pub fn add_stage_duration(&mut self, stage: &'static str, duration: Duration) {
+        *self.stage_seconds.entry(stage).or_default() += duration.as_secs_f64();
+    }
+
+    /// Sets the number of entities of the given kind, e.g. "cameras" or "users", in the reports.
+    pub fn set_output_entities(&mut self, kind: &'static str, count: u64) {
+        self.output_entities.insert(kind, count);
+    }
+
+    /// Adds the metrics collected by a part of the run, e.g. a day processed concurrently with other days.
+    /// The time spent in a stage is summed over the parts, hence it may exceed the duration of the run.
+    pub fn merge(&mut self, other: RunMetrics) {
+        self.rows_parsed += other.rows_parsed;
+        self.rows_rejected += other.rows_rejected;
+        self.days_processed += other.days_processed;
+        self.days_reused += other.days_reused;
+        self.temporary_bytes_written += other.temporary_bytes_written;
+        for (stage, seconds) in other.stage_seconds {
+            *self.stage_seconds.entry(stage).or_default() += seconds;
+        }
+        for (kind, count) in other.output_entities {
+            self.output_entities.insert(kind, count);
+        }
+    }
+
+    /// Writes the metrics of a run of the given program to the given file, replacing it atomically.
+    /// The textfile collector only reads files ending with .prom, hence the file is written with a .tmp suffix first.
+    /// A failed run keeps the timestamp of the last successful run found in the file, if any.
+    pub fn write_textfile(&self, path: &Path, tool: &str, success: bool) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let last_success_timestamp = if success {
+            Some(timestamp)
+        } else {
+            last_success_timestamp(path, tool)
+        };
+        let mut temporary_path = PathBuf::from(path);
+        temporary_path.as_mut_os_string().push(".tmp");
+        fs::write(
+            &temporary_path,
+            self.render(tool, success, timestamp, last_success_timestamp),
+        )?;
+        fs::rename(&temporary_path, path)
+    }
+
+    // The metrics in the Prometheus text format.
+    fn render(
+        &self,
+        tool: &str,
+        success: bool,
+        timestamp: u64,
+        last_success_timestamp: Option<u64>,
+    ) -> String {
+        let mut text = String::new();
+        let mut gauge = |name: &str, help: &str, samples: Vec<(String, String)>| {
+            if samples.is_empty() {
+                return;
+            }
+            let _ = writeln!(text, "# HELP safari_stats_{} {}", name, help);
+            let _ = writeln!(text, "# TYPE safari_stats_{} gauge", name);
+            for (labels, value) in samples {
+                let _ = writeln!(
+                    text,
+                    "safari_stats_{}{{tool=\"{}\"{}}} {}",
+                    name, tool, labels, value
+                );
+            }
+        };
+        gauge(
+            "last_run_success",
+            "Whether the last run succeeded (1) or failed (0).",
+            vec![(String::new(), (success as u8).to_string())],
+        );
+        gauge(
+            "last_run_timestamp_seconds",
+            "When the last run finished, in seconds since the Unix epoch.",
+            vec![(String::new(), timestamp.to_string())],
+        );
+        gauge(
+            "last_success_timestamp_seconds",
+            "When the last successful run finished, in seconds since the Unix epoch.",
+            last_success_timestamp
+                .map(|timestamp| (String::new(), timestamp.to_string()))
+                .into_iter()
+                .collect(),
+        );
+        for (name, help, value) in [
+            (
+                "rows_parsed",
+                "Rows parsed from the session logs by the last run.",
+                self.rows_parsed,
+            ),
+            (
+                "rows_rejected",
+                "Malformed rows and rows with a nil id that were rejected or quarantined by the last run.",
+                self.rows_rejected,
+            ),
+            (
+                "days_processed",
+                "Days whose session log was processed by the last run.",
+                self.days_processed,
+            ),
+            (
+                "days_reused",
+                "Days whose daily state was reused from an earlier run by the last run.",
+                self.days_reused,
+            ),
+            (
+                "temporary_bytes_written",
+                "Bytes written to temporary files (sorted segments or batches of FST maps) by the last run.",
+                self.temporary_bytes_written,
+            ),
+        ] {
+            gauge(name, help, vec![(String::new(), value.to_string())]);
+        }
+        gauge(
+            "stage_duration_seconds",
+            "Time spent in each stage of the last run.",
+            self.stage_seconds
+                .iter()
+                .map(|(stage, seconds)| (format!(",stage=\"{}\"", stage), seconds.to_string()))
+                .collect(),
+        );
+        gauge(
+            "output_entities",
+            "Cameras or users in the reports written by the last run.",
+            self.output_entities
+                .iter()
+                .map(|(kind, count)| (format!(",kind=\"{}\"", kind), count.to_string()))
+                .collect(),
+        );
+        text
+    }
+}
+
+// The timestamp of the last successful run of the given program in a metrics file written earlier, if any.
+fn last_success_timestamp(path: &Path, tool: &str) -> Option<u64> {
+    let sample = format!(
+        "safari_stats_last_success_timestamp_seconds{{tool=\"{}\"}} ",
+        tool
+    );
+    fs::read_to_string(path)
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(sample.as_str()))
+        .and_then(|timestamp| timestamp.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_as_labelled_gauges() {
+        let mut metrics = RunMetrics {
+            rows_parsed: 10,
+            rows_rejected: 2,
+            ..Default::default()
+        };
+        metrics.add_stage_duration("merge", Duration::from_millis(1500));
+        metrics.add_stage_duration("merge", Duration::from_millis(500));
+        metrics.set_output_entities("users", 7);
+        let text = metrics.render("user-stats", false, 1_600_000_000, Some(1_500_000_000));
+
+        for line in [
+            "# TYPE safari_stats_last_run_success gauge",
+            "safari_stats_last_run_success{tool=\"user-stats\"} 0",
+            "safari_stats_last_run_timestamp_seconds{tool=\"user-stats\"} 1600000000",
+            "safari_stats_last_success_timestamp_seconds{tool=\"user-stats\"} 1500000000",
+            "safari_stats_rows_parsed{tool=\"user-stats\"} 10",
+            "safari_stats_rows_rejected{tool=\"user-stats\"} 2",
+            "safari_stats_stage_duration_seconds{tool=\"user-stats\",stage=\"merge\"} 2",
+            "safari_stats_output_entities{tool=\"user-stats\",kind=\"users\"} 7",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in {}",
+                line,
+                text
+            );
+        }
+    }
+    #[test]
+    fn failed_runs_keep_the_timestamp_of_the_last_success() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("camera_stats.prom");
+        let metrics = RunMetrics::default();
+        metrics
+            .write_textfile(&path, "camera-stats", false)
+            .unwrap();
+        assert_eq!(None, last_success_timestamp(&path, "camera-stats"));
+
+        metrics.write_textfile(&path, "camera-stats", true).unwrap();
+        let succeeded_at = last_success_timestamp(&path, "camera-stats").unwrap();
+        metrics
+            .write_textfile(&path, "camera-stats", false)
+            .unwrap();
+        assert_eq!(
+            Some(succeeded_at),
+            last_success_timestamp(&path, "camera-stats")
+        );
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("safari_stats_last_run_success{tool=\"camera-stats\"} 0"));
+    }
+}
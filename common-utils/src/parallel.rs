@@ -0,0 +1,81 @@
+//! # Parallel
+//!
+//! This module processes independent items, e.g. the unprocessed session logs of a run, on a given number of threads.
+//!
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+/// Applies the given function to every item on up to `jobs` threads and returns the results in the order of the items.
+/// Once an item has failed no further items are started. The items already started are finished, and then the error of
+/// the first failed item (in the order of the items) is returned. With a single job the items are processed one after another
+/// on the current thread.
+pub fn map_in_parallel<T, R, E, F>(items: Vec<T>, jobs: usize, f: F) -> Result<Vec<R>, E>
+where
+    T: Send,
+    R: Send,
+    E: Send,
+    F: Fn(T) -> Result<R, E> + Sync,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+    let failed = AtomicBool::new(false);
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let mut results: Vec<(usize, Result<R, E>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    while !failed.load(Ordering::Relaxed) {
+                        let next = queue.lock().unwrap().next();
+                        let (index, item) = match next {
+                            Some(next) => next,
+                            None => break,
+                        };
+                        let result = f(item);
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        results.push((index, result));
+                    }
+                    results
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_are_in_the_order_of_the_items() {
+        let items: Vec<u64> = (0..20).collect();
+        let squares = map_in_parallel(items.clone(), 4, |item| Ok::<_, ()>(item * item)).unwrap();
+        assert_eq!(
+            items.iter().map(|item| item * item).collect::<Vec<_>>(),
+            squares
+        );
+
+        let failed = map_in_parallel(
+            items,
+            4,
+            |item| if item == 3 { Err(item) } else { Ok(item) },
+        );
+        assert_eq!(Err(3), failed);
+    }
+}
@@ -0,0 +1,429 @@
+//! # Parquet utils
+//!
+//! This module provides a writer for our daily reports in the Apache Parquet format.
+//! Every report is written with the same normalized long schema where each row corresponds to
+//! a single (camera or user, session) pair:
+//! ```text
+//! entity_id: the camera or user id, rank: 1 for the best session, session_id, value: the average or number of pics,
+//! report_date: YYYYMMDD, window_days: the number of days the report was computed over.
+//! ```
+//! Only actual sessions are written. A camera or user with fewer sessions than the report holds at most simply has fewer rows,
+//! so that counts and averages computed over the file are not skewed by filler rows.
+//!
+//! The other reports (e.g. the summaries) are flat tables with a schema of their own, which are written by [ParquetTableWriter].
+//!
+
+use std::{io::Write, sync::Arc};
+
+use parquet::{
+    basic::{Compression, Repetition},
+    column::writer::ColumnWriter,
+    data_type::{ByteArray, ByteArrayType, DoubleType, Int32Type},
+    errors::{ParquetError, Result},
+    file::{metadata::KeyValue, properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use uuid::Uuid;
+
+use crate::date_utils::DateStamp;
+
+/// The schema shared by all of our Parquet reports.
+pub const REPORT_SCHEMA: &str = "
+message report {
+    REQUIRED BYTE_ARRAY entity_id (UTF8);
+    REQUIRED INT32 rank;
+    REQUIRED BYTE_ARRAY session_id (UTF8);
+    REQUIRED DOUBLE value;
+    REQUIRED BYTE_ARRAY report_date (UTF8);
+    REQUIRED INT32 window_days;
+}
+";
+
+/// Writes report rows to a Parquet file.
+///
+/// Rows are buffered column by column and written as a row group whenever the buffer holds ROW_GROUP_SIZE rows.
+/// This keeps memory consumption bounded regardless of how many cameras or users the report contains.
+pub struct ParquetReportWriter<W: Write + Send> {
+    file_writer: SerializedFileWriter<W>,
+    report_date: ByteArray,
+    window_days: i32,
+    entity_ids: Vec<ByteArray>,
+    ranks: Vec<i32>,
+    session_ids: Vec<ByteArray>,
+    values: Vec<f64>,
+}
+
+impl<W: Write + Send> ParquetReportWriter<W> {
+    /// The maximum number of rows we buffer before writing them to a row group.
+    pub const ROW_GROUP_SIZE: usize = 500_000;
+
+    pub fn new(writer: W, report_date: DateStamp, window_days: usize) -> Result<Self> {
+        let schema = Arc::new(parse_message_type(REPORT_SCHEMA)?);
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let file_writer = SerializedFileWriter::new(writer, schema, Arc::new(properties))?;
+        Ok(Self {
+            file_writer,
+            report_date: ByteArray::from(report_date.into_string().as_str()),
+            window_days: window_days as i32,
+            entity_ids: Vec::new(),
+            ranks: Vec::new(),
+            session_ids: Vec::new(),
+            values: Vec::new(),
+        })
+    }
+
+    /// Buffers a single row. A row group is written to the underlying writer once the buffer is full.
+    pub fn write_row(
+        &mut self,
+        entity_id: &str,
+        rank: usize,
+        session_id: &Uuid,
+        value: f64,
+    ) -> Result<()> {
+        self.entity_ids.push(ByteArray::from(entity_id));
+        self.ranks.push(rank as i32);
+        self.session_ids
+            .push(ByteArray::from(session_id.to_string().as_str()));
+        self.values.push(value);
+        if self.values.len() >= Self::ROW_GROUP_SIZE {
+            self.flush_row_group()?;
+        }
+        Ok(())
+    }
+
+    /// Records a key value pair (e.g. the ranking metric used) in the metadata of the file.
+    pub fn append_key_value_metadata(&mut self, key: &str, value: &str) {
+        self.file_writer
+            .append_key_value_metadata(KeyValue::new(key.to_string(), value.to_string()));
+    }
+
+    /// Writes any buffered rows and the Parquet footer.
+    pub fn close(mut self) -> Result<()> {
+        self.flush_row_group()?;
+        self.file_writer.close()?;
+        Ok(())
+    }
+
+    fn flush_row_group(&mut self) -> Result<()> {
+        if self.values.is_empty() {
+            return Ok(());
+        }
+        let num_rows = self.values.len();
+        let report_dates = vec![self.report_date.clone(); num_rows];
+        let window_days = vec![self.window_days; num_rows];
+        let mut row_group_writer = self.file_writer.next_row_group()?;
+        let mut column_idx = 0;
+        while let Some(mut column_writer) = row_group_writer.next_column()? {
+            // The columns are visited in the order they appear in REPORT_SCHEMA.
+            match column_idx {
+                0 => column_writer.typed::<ByteArrayType>().write_batch(
+                    &self.entity_ids,
+                    None,
+                    None,
+                )?,
+                1 => column_writer
+                    .typed::<Int32Type>()
+                    .write_batch(&self.ranks, None, None)?,
+                2 => column_writer.typed::<ByteArrayType>().write_batch(
+                    &self.session_ids,
+                    None,
+                    None,
+                )?,
+                3 => column_writer
+                    .typed::<DoubleType>()
+                    .write_batch(&self.values, None, None)?,
+                4 => {
+                    column_writer
+                        .typed::<ByteArrayType>()
+                        .write_batch(&report_dates, None, None)?
+                }
+                _ => column_writer
+                    .typed::<Int32Type>()
+                    .write_batch(&window_days, None, None)?,
+            };
+            column_writer.close()?;
+            column_idx += 1;
+        }
+        row_group_writer.close()?;
+        self.entity_ids.clear();
+        self.ranks.clear();
+        self.session_ids.clear();
+        self.values.clear();
+        Ok(())
+    }
+}
+
+/// A value in a row of a [ParquetTableWriter]. Null is only allowed in OPTIONAL columns.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParquetValue {
+    Int32(i32),
+    Int64(i64),
+    Double(f64),
+    Utf8(String),
+    Null,
+}
+
+/// Writes the rows of a flat table with the given schema to a Parquet file. Every value of a row must match the physical type
+/// of its column (INT32, INT64, DOUBLE or BYTE_ARRAY).
+///
+/// Just like for [ParquetReportWriter], rows are buffered and written as a row group whenever the buffer holds ROW_GROUP_SIZE rows.
+pub struct ParquetTableWriter<W: Write + Send> {
+    file_writer: SerializedFileWriter<W>,
+    // Whether each column of the schema is OPTIONAL, i.e. may hold nulls.
+    optional_columns: Vec<bool>,
+    rows: Vec<Vec<ParquetValue>>,
+}
+
+impl<W: Write + Send> ParquetTableWriter<W> {
+    /// The maximum number of rows we buffer before writing them to a row group.
+    pub const ROW_GROUP_SIZE: usize = 100_000;
+
+    pub fn new(writer: W, schema: &str) -> Result<Self> {
+        let schema = Arc::new(parse_message_type(schema)?);
+        let optional_columns = schema
+            .get_fields()
+            .iter()
+            .map(|field| field.get_basic_info().repetition() == Repetition::OPTIONAL)
+            .collect();
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let file_writer = SerializedFileWriter::new(writer, schema, Arc::new(properties))?;
+        Ok(Self {
+            file_writer,
+            optional_columns,
+            rows: Vec::new(),
+        })
+    }
+
+    /// Buffers a single row, which holds a value for every column in the order of the schema.
+    /// A row group is written to the underlying writer once the buffer is full.
+    pub fn write_row(&mut self, row: Vec<ParquetValue>) -> Result<()> {
+        if row.len() != self.optional_columns.len() {
+            return Err(ParquetError::General(format!(
+                "a row has {} values, but the schema has {} columns",
+                row.len(),
+                self.optional_columns.len()
+            )));
+        }
+        if let Some(column_idx) = row
+            .iter()
+            .zip(self.optional_columns.iter())
+            .position(|(value, optional)| *value == ParquetValue::Null && !optional)
+        {
+            return Err(ParquetError::General(format!(
+                "the required column {} is null",
+                column_idx
+            )));
+        }
+        self.rows.push(row);
+        if self.rows.len() >= Self::ROW_GROUP_SIZE {
+            self.flush_row_group()?;
+        }
+        Ok(())
+    }
+
+    /// Records a key value pair in the metadata of the file.
+    pub fn append_key_value_metadata(&mut self, key: &str, value: &str) {
+        self.file_writer
+            .append_key_value_metadata(KeyValue::new(key.to_string(), value.to_string()));
+    }
+
+    /// Writes any buffered rows and the Parquet footer.
+    pub fn close(mut self) -> Result<()> {
+        self.flush_row_group()?;
+        self.file_writer.close()?;
+        Ok(())
+    }
+
+    fn flush_row_group(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let mut row_group_writer = self.file_writer.next_row_group()?;
+        let mut column_idx = 0;
+        while let Some(mut column_writer) = row_group_writer.next_column()? {
+            let definition_levels: Vec<i16> = self
+                .rows
+                .iter()
+                .map(|row| (row[column_idx] != ParquetValue::Null) as i16)
+                .collect();
+            let definition_levels = if self.optional_columns[column_idx] {
+                Some(definition_levels.as_slice())
+            } else {
+                None
+            };
+            let rows = &self.rows;
+            match column_writer.untyped() {
+                ColumnWriter::Int32ColumnWriter(writer) => writer.write_batch(
+                    &column_values(rows, column_idx, |value| match value {
+                        ParquetValue::Int32(value) => Some(*value),
+                        _ => None,
+                    })?,
+                    definition_levels,
+                    None,
+                )?,
+                ColumnWriter::Int64ColumnWriter(writer) => writer.write_batch(
+                    &column_values(rows, column_idx, |value| match value {
+                        ParquetValue::Int64(value) => Some(*value),
+                        _ => None,
+                    })?,
+                    definition_levels,
+                    None,
+                )?,
+                ColumnWriter::DoubleColumnWriter(writer) => writer.write_batch(
+                    &column_values(rows, column_idx, |value| match value {
+                        ParquetValue::Double(value) => Some(*value),
+                        _ => None,
+                    })?,
+                    definition_levels,
+                    None,
+                )?,
+                ColumnWriter::ByteArrayColumnWriter(writer) => writer.write_batch(
+                    &column_values(rows, column_idx, |value| match value {
+                        ParquetValue::Utf8(value) => Some(ByteArray::from(value.as_str())),
+                        _ => None,
+                    })?,
+                    definition_levels,
+                    None,
+                )?,
+                _ => {
+                    return Err(ParquetError::General(format!(
+                        "the column {} has an unsupported type",
+                        column_idx
+                    )))
+                }
+            };
+            column_writer.close()?;
+            column_idx += 1;
+        }
+        row_group_writer.close()?;
+        self.rows.clear();
+        Ok(())
+    }
+}
+
+// The non-null values of the given column, converted by the given function. A value it does not convert has the wrong type.
+fn column_values<T>(
+    rows: &[Vec<ParquetValue>],
+    column_idx: usize,
+    convert: impl Fn(&ParquetValue) -> Option<T>,
+) -> Result<Vec<T>> {
+    rows.iter()
+        .map(|row| &row[column_idx])
+        .filter(|value| **value != ParquetValue::Null)
+        .map(|value| {
+            convert(value).ok_or_else(|| {
+                ParquetError::General(format!(
+                    "the value {:?} does not match the type of column {}",
+                    value, column_idx
+                ))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::RowAccessor,
+    };
+
+    #[test]
+    fn written_rows_can_be_read_back() {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut writer = ParquetReportWriter::new(
+            file.try_clone().unwrap(),
+            DateStamp::from_ymd("20210704".to_string()),
+            7,
+        )
+        .unwrap();
+        writer.append_key_value_metadata("ranking_metric", "raw-mean");
+        writer.write_row("42", 1, &Uuid::from_u128(7), 3.5).unwrap();
+        writer.write_row("42", 2, &Uuid::from_u128(8), 2.0).unwrap();
+        writer.close().unwrap();
+
+        std::io::Seek::rewind(&mut file).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        let key_value_metadata = reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap();
+        assert!(key_value_metadata
+            .iter()
+            .any(|kv| kv.key == "ranking_metric" && kv.value.as_deref() == Some("raw-mean")));
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(2, rows.len());
+        assert_eq!("42", rows[1].get_string(0).unwrap());
+        assert_eq!(2, rows[1].get_int(1).unwrap());
+        assert_eq!(
+            &Uuid::from_u128(8).to_string(),
+            rows[1].get_string(2).unwrap()
+        );
+        assert_eq!(2.0, rows[1].get_double(3).unwrap());
+        assert_eq!("20210704", rows[1].get_string(4).unwrap());
+        assert_eq!(7, rows[1].get_int(5).unwrap());
+    }
+
+    #[test]
+    fn table_rows_can_be_read_back() {
+        const SCHEMA: &str = "
+        message table {
+            REQUIRED INT32 id;
+            REQUIRED INT64 count;
+            OPTIONAL DOUBLE mean;
+            REQUIRED BYTE_ARRAY name (UTF8);
+        }
+        ";
+        let mut file = tempfile::tempfile().unwrap();
+        let mut writer = ParquetTableWriter::new(file.try_clone().unwrap(), SCHEMA).unwrap();
+        writer
+            .write_row(vec![
+                ParquetValue::Int32(1),
+                ParquetValue::Int64(10),
+                ParquetValue::Double(2.5),
+                ParquetValue::Utf8("a".to_string()),
+            ])
+            .unwrap();
+        writer
+            .write_row(vec![
+                ParquetValue::Int32(2),
+                ParquetValue::Int64(0),
+                ParquetValue::Null,
+                ParquetValue::Utf8("b".to_string()),
+            ])
+            .unwrap();
+        assert!(writer
+            .write_row(vec![
+                ParquetValue::Null,
+                ParquetValue::Int64(0),
+                ParquetValue::Null,
+                ParquetValue::Utf8("c".to_string()),
+            ])
+            .is_err());
+        writer.close().unwrap();
+
+        std::io::Seek::rewind(&mut file).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(2, rows.len());
+        assert_eq!(2.5, rows[0].get_double(2).unwrap());
+        assert_eq!(2, rows[1].get_int(0).unwrap());
+        assert_eq!(0, rows[1].get_long(1).unwrap());
+        assert!(rows[1].get_double(2).is_err());
+        assert_eq!("b", rows[1].get_string(3).unwrap());
+    }
+}
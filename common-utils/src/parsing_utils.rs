@@ -3,13 +3,22 @@
 //! This module contains structures and functions related to parsing safari session logs.
 //!
 
-use csv::{Reader, ReaderBuilder};
-use serde::Deserialize;
-use std::{fs::File, path::Path};
+use csv::{Reader, ReaderBuilder, Writer, WriterBuilder};
+use indicatif::ProgressBarIter;
+
+use crate::manifest::{DigestReader, SourceDigest};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Display,
+    fs::File,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use uuid::Uuid;
 
 /// This represents a valid row/record from a daily safari session log file (safari-sessions-YYYYMMDD.log)
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Record {
     /// User unique identifier
     pub user_id: Uuid,
@@ -22,14 +31,289 @@ pub struct Record {
     pub nb_pics: u8,
 }
 
+impl Record {
+    /// Whether the user id or the session id of the record is the nil UUID.
+    pub fn has_nil_id(&self) -> bool {
+        self.user_id.is_nil() || self.session_id.is_nil()
+    }
+}
+
 /// Produces a csv reader with a predefined buffer capacity that presumes no headers in the file.
-/// This reader can be used to parse safari session log files.
+/// This reader can be used to parse safari session log files. The number of bytes parsed is reported on a progress bar (see crate::progress).
 pub fn customised_csv_reader<P: AsRef<Path>>(
     path: P,
     buffer_capacity: usize,
-) -> csv::Result<Reader<File>> {
-    ReaderBuilder::new()
-        .has_headers(false)
-        .buffer_capacity(buffer_capacity)
-        .from_path(path)
+) -> csv::Result<Reader<ProgressBarIter<File>>> {
+    let file = crate::progress::open_with_progress(path, "parse")?;
+    Ok(customised_reader_builder(buffer_capacity).from_reader(file))
+}
+
+/// Produces a csv reader like customised_csv_reader, which adds the bytes parsed to the given digest as well.
+/// This way the hash of a session log file is known once it has been parsed, without reading it twice.
+pub fn customised_csv_reader_with_digest<P: AsRef<Path>>(
+    path: P,
+    buffer_capacity: usize,
+    digest: &SourceDigest,
+) -> csv::Result<Reader<DigestReader<ProgressBarIter<File>>>> {
+    let file = crate::progress::open_with_progress(path, "parse")?;
+    Ok(customised_reader_builder(buffer_capacity).from_reader(digest.wrap(file)))
+}
+
+fn customised_reader_builder(buffer_capacity: usize) -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder.has_headers(false).buffer_capacity(buffer_capacity);
+    builder
+}
+
+/// Finds the sessions near the boundaries of a daily session log file, i.e. the sessions that may have started
+/// on the previous day or may continue on the next day.
+///
+/// Session logs do not contain timestamps, but records are appended to the log roughly in the order their trips ended.
+/// Hence a session crossing midnight has records among the last records of one log and the first records of the next.
+/// We consider a session to be near a boundary if it has a record among the first or the last `window` records of the log.
+pub struct BoundarySessionsTracker {
+    window: usize,
+    observed: usize,
+    head: HashSet<Uuid>,
+    tail: VecDeque<Uuid>,
+}
+
+impl BoundarySessionsTracker {
+    /// The default number of records at the start and at the end of a log whose sessions are considered to be near a boundary.
+    /// This should comfortably cover the duration of a session.
+    pub const DEFAULT_WINDOW: usize = 200_000;
+
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            observed: 0,
+            head: HashSet::new(),
+            tail: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// Observes the session of the next record of the log.
+    pub fn observe(&mut self, session_id: Uuid) {
+        if self.observed < self.window {
+            self.head.insert(session_id);
+        }
+        self.tail.push_back(session_id);
+        if self.tail.len() > self.window {
+            self.tail.pop_front();
+        }
+        self.observed += 1;
+    }
+
+    /// The sessions with a record among the first or the last `window` observed records.
+    pub fn finish(self) -> HashSet<Uuid> {
+        let mut boundary_sessions = self.head;
+        boundary_sessions.extend(self.tail);
+        boundary_sessions
+    }
+}
+
+/// What to do with records whose user id or session id is the nil UUID.
+///
+/// The nil UUID is a valid id and is processed like any other id by default, but it usually indicates
+/// a bug in the upstream logging rather than a real user or session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NilIdRule {
+    /// The records are processed like any other record.
+    #[default]
+    Accept,
+    /// The session log file is rejected, i.e. processing it fails.
+    Reject,
+    /// The records are skipped and written to a quarantine file for later inspection.
+    Quarantine,
+}
+
+impl FromStr for NilIdRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept" => Ok(NilIdRule::Accept),
+            "reject" => Ok(NilIdRule::Reject),
+            "quarantine" => Ok(NilIdRule::Quarantine),
+            other => Err(format!(
+                "unknown nil id rule: {}. Expected one of: accept, reject, quarantine",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for NilIdRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NilIdRule::Accept => "accept",
+            NilIdRule::Reject => "reject",
+            NilIdRule::Quarantine => "quarantine",
+        })
+    }
+}
+
+/// Applies a NilIdRule to the records parsed from a session log file.
+///
+/// The validator is meant to be used within the iterator chain that parses the log (see RecordValidator::validate),
+/// hence rejected records do not stop the iteration. Instead the error is returned by RecordValidator::finish,
+/// which must be called before the results of the log are saved.
+pub struct RecordValidator {
+    rule: NilIdRule,
+    quarantine_file_path: PathBuf,
+    // Created once the first record is quarantined, so that no empty quarantine files are left behind.
+    quarantine_writer: Option<Writer<File>>,
+    nil_id_records: usize,
+    quarantine_error: Option<std::io::Error>,
+    rows_read: u64,
+    malformed_rows: u64,
+}
+
+impl RecordValidator {
+    pub fn new(rule: NilIdRule, quarantine_file_path: PathBuf) -> Self {
+        Self {
+            rule,
+            quarantine_file_path,
+            quarantine_writer: None,
+            nil_id_records: 0,
+            quarantine_error: None,
+            rows_read: 0,
+            malformed_rows: 0,
+        }
+    }
+
+    /// Returns the parsed record if it is to be processed. Malformed rows are skipped, but counted.
+    pub fn validate_parsed(&mut self, parsed: csv::Result<Record>) -> Option<Record> {
+        self.rows_read += 1;
+        match parsed {
+            Ok(record) => self.validate(record),
+            Err(_) => {
+                self.malformed_rows += 1;
+                None
+            }
+        }
+    }
+
+    /// Returns the record if it is to be processed.
+    pub fn validate(&mut self, record: Record) -> Option<Record> {
+        if self.rule == NilIdRule::Accept || !record.has_nil_id() {
+            return Some(record);
+        }
+        self.nil_id_records += 1;
+        if self.rule == NilIdRule::Quarantine && self.quarantine_error.is_none() {
+            if let Err(error) = self.quarantine(&record) {
+                self.quarantine_error = Some(error);
+            }
+        }
+        None
+    }
+
+    fn quarantine(&mut self, record: &Record) -> std::io::Result<()> {
+        if self.quarantine_writer.is_none() {
+            if let Some(directory) = self.quarantine_file_path.parent() {
+                std::fs::create_dir_all(directory)?;
+            }
+            let writer = WriterBuilder::new()
+                .has_headers(false)
+                .from_path(&self.quarantine_file_path)?;
+            self.quarantine_writer = Some(writer);
+        }
+        if let Some(writer) = self.quarantine_writer.as_mut() {
+            writer.serialize(record)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of records with a nil id that were skipped. Fails if such records are rejected
+    /// or could not be written to the quarantine file.
+    pub fn finish(self) -> std::io::Result<usize> {
+        if let Some(error) = self.quarantine_error {
+            return Err(error);
+        }
+        if self.rule == NilIdRule::Reject && self.nil_id_records > 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} records with a nil user or session id were rejected",
+                    self.nil_id_records
+                ),
+            ));
+        }
+        if let Some(mut writer) = self.quarantine_writer {
+            writer.flush()?;
+        }
+        Ok(self.nil_id_records)
+    }
+
+    /// The number of rows passed to RecordValidator::validate_parsed.
+    pub fn rows_read(&self) -> u64 {
+        self.rows_read
+    }
+
+    /// The number of rows that are not processed, i.e. malformed rows and records with a nil id that are rejected or quarantined.
+    pub fn rows_rejected(&self) -> u64 {
+        let nil_id_records = match self.rule {
+            NilIdRule::Accept => 0,
+            NilIdRule::Reject | NilIdRule::Quarantine => self.nil_id_records as u64,
+        };
+        self.malformed_rows + nil_id_records
+    }
+
+    /// The file records with a nil id are written to if they are quarantined.
+    pub fn quarantine_file_path(&self) -> &Path {
+        &self.quarantine_file_path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(user_id: u128, session_id: u128) -> Record {
+        Record {
+            user_id: Uuid::from_u128(user_id),
+            session_id: Uuid::from_u128(session_id),
+            camera_id: 1,
+            nb_pics: 2,
+        }
+    }
+
+    #[test]
+    fn nil_ids_are_accepted_rejected_or_quarantined() {
+        let dir = tempfile::tempdir().unwrap();
+        let quarantine_file_path = dir.path().join("quarantine").join("sessions.log");
+        let validate = |rule: NilIdRule| {
+            let mut validator = RecordValidator::new(rule, quarantine_file_path.clone());
+            let kept = vec![record(1, 2), record(0, 2), record(1, 0)]
+                .into_iter()
+                .filter_map(|record| validator.validate(record))
+                .count();
+            (kept, validator.finish())
+        };
+        let (kept, result) = validate(NilIdRule::Accept);
+        assert_eq!((3, 0), (kept, result.unwrap()));
+        assert!(!quarantine_file_path.exists());
+        let (kept, result) = validate(NilIdRule::Reject);
+        assert_eq!(1, kept);
+        assert!(result.is_err());
+        let (kept, result) = validate(NilIdRule::Quarantine);
+        assert_eq!((1, 2), (kept, result.unwrap()));
+        assert_eq!(
+            2,
+            std::fs::read_to_string(&quarantine_file_path)
+                .unwrap()
+                .lines()
+                .count()
+        );
+    }
+
+    #[test]
+    fn sessions_in_the_head_and_tail_are_boundary_sessions() {
+        let mut tracker = BoundarySessionsTracker::new(2);
+        for session_id in [1, 2, 3, 4, 3, 5] {
+            tracker.observe(Uuid::from_u128(session_id));
+        }
+        let expected: HashSet<_> = [1, 2, 3, 5].iter().map(|id| Uuid::from_u128(*id)).collect();
+        assert_eq!(expected, tracker.finish());
+    }
 }
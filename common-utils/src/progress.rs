@@ -0,0 +1,100 @@
+//! # Progress
+//!
+//! This module provides the progress bars of our applications, which are based on the `indicatif` crate.
+//! Processing a multi-GB session log file takes minutes, hence there is a bar over the session log files of a run
+//! and a bar for each long-running stage within it: bytes while a log is parsed, items while sorted records or FST sets and maps are merged.
+//! All bars share a single area on stderr, which is only drawn if stderr is a terminal. Otherwise the bars are hidden.
+//!
+//! Logs are written to stderr as well (see crate::logging). They are written through [Stderr], which clears the bars while
+//! an event is written, so that the two do not garble each other.
+//!
+
+use std::{
+    fs::File,
+    io::{self, IsTerminal, Write},
+    path::Path,
+    sync::OnceLock,
+};
+
+pub use indicatif::ProgressBar;
+use indicatif::{
+    MultiProgress, ProgressBarIter, ProgressDrawTarget, ProgressFinish, ProgressStyle,
+};
+
+// The area on stderr all progress bars are drawn in.
+fn bars() -> &'static MultiProgress {
+    static BARS: OnceLock<MultiProgress> = OnceLock::new();
+    BARS.get_or_init(|| {
+        let draw_target = if io::stderr().is_terminal() {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::hidden()
+        };
+        MultiProgress::with_draw_target(draw_target)
+    })
+}
+
+// Adds a bar of the given length and style below the existing bars. The bar is removed once it is dropped (or finished),
+// which also happens when an error is propagated while the stage is running.
+fn add_bar(len: u64, template: &str, message: impl Into<String>) -> ProgressBar {
+    let style = ProgressStyle::with_template(template)
+        .expect("the progress bar templates are valid")
+        .progress_chars("=> ");
+    bars().add(
+        ProgressBar::new(len)
+            .with_style(style)
+            .with_message(message.into())
+            .with_finish(ProgressFinish::AndClear),
+    )
+}
+
+/// A bar over the given number of session log files processed by a run. The message should name the log currently processed.
+pub fn days(len: usize) -> ProgressBar {
+    add_bar(
+        len as u64,
+        "{msg:40} [{bar:30}] {pos}/{len} logs ({elapsed})",
+        "",
+    )
+}
+
+/// A bar over the items of a stage, e.g. the keys of the FST sets or maps being merged. The length should be an estimate
+/// if the exact number of items is unknown.
+pub fn items(len: u64, message: &str) -> ProgressBar {
+    add_bar(
+        len,
+        "{msg:40} [{bar:30}] {human_pos}/{human_len} items ({eta})",
+        message,
+    )
+}
+
+/// Opens the given file for reading, while a bar reports the number of bytes read from it.
+pub fn open_with_progress<P: AsRef<Path>>(
+    path: P,
+    message: &str,
+) -> io::Result<ProgressBarIter<File>> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let bar = add_bar(
+        len,
+        "{msg:40} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+        message,
+    );
+    Ok(bar.wrap_read(file))
+}
+
+/// Writes to stderr while the progress bars are cleared. A write is expected to contain whole lines, like the events written by the logger.
+pub struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        bars().suspend(|| io::stderr().write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        bars().suspend(|| io::stderr().write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
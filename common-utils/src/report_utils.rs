@@ -0,0 +1,72 @@
+//! # Report utils
+//!
+//! This module contains structures and functionality related to the format of the human (and machine) readable
+//! reports produced by our Safari session stats applications.
+//!
+
+use serde::Serialize;
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+/// The formats the daily reports can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReportFormat {
+    /// The legacy format where each line is of the form id|session_id1:value1,session_id2:value2, ...
+    #[default]
+    Pipe,
+    /// Each line is a JSON object describing a single camera or user.
+    JsonLines,
+    /// An Apache Parquet file with one row per (camera or user, session) pair. See crate::parquet_utils.
+    Parquet,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pipe" => Ok(ReportFormat::Pipe),
+            "jsonl" => Ok(ReportFormat::JsonLines),
+            "parquet" => Ok(ReportFormat::Parquet),
+            other => Err(format!(
+                "unknown report format: {}. Expected one of: pipe, jsonl, parquet",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for ReportFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ReportFormat::Pipe => "pipe",
+            ReportFormat::JsonLines => "jsonl",
+            ReportFormat::Parquet => "parquet",
+        })
+    }
+}
+
+/// Wrapper around a serializable value whose Display implementation writes the value as a single line of JSON.
+pub struct JsonLine<T>(pub T);
+
+impl<T: Serialize> Display for JsonLine<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_string(&self.0).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_format_from_str() {
+        assert_eq!(ReportFormat::Pipe, "pipe".parse().unwrap());
+        assert_eq!(ReportFormat::JsonLines, "jsonl".parse().unwrap());
+        assert_eq!(ReportFormat::Parquet, "parquet".parse().unwrap());
+        assert!("csv".parse::<ReportFormat>().is_err());
+    }
+}
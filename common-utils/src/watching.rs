@@ -0,0 +1,346 @@
+//! # Watching
+//!
+//! This module lets our applications run as a long-running process that regenerates the reports as soon as a session log lands,
+//! instead of being scheduled at a guessed time after the upload. A session log is complete once a marker file with the suffix
+//! `.done` has been written next to it (i.e. safari-sessions-YYYYMMDD.log.done), or once it has stopped growing for a while.
+//! On Linux the session log directory is watched with inotify, so that new files and markers are noticed immediately.
+//! Elsewhere it is polled.
+//!
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::file_utils::{session_log_file_path, UnprocessedSessionLogFile};
+
+/// The suffix of the marker file signalling that the session log it is named after is complete.
+pub const DONE_MARKER_SUFFIX: &str = ".done";
+
+// How often the session logs are checked if the directory does not change, e.g. to notice that a log stopped growing.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watches a session log directory for the session logs of the last seven days that have been completed.
+pub struct SessionLogWatcher {
+    directory: PathBuf,
+    settle_time: Duration,
+    // The size of every session log that is not complete yet, and when it was first seen with this size.
+    pending: HashMap<PathBuf, (u64, Instant)>,
+    // The session logs that have already been returned as completed.
+    completed: HashSet<PathBuf>,
+    notifications: DirectoryNotifications,
+}
+
+impl SessionLogWatcher {
+    /// Watches the given directory. A session log without a marker is complete once its size has not changed for the settle time.
+    pub fn new(directory: PathBuf, settle_time: Duration) -> io::Result<Self> {
+        let notifications = DirectoryNotifications::new(&directory)?;
+        Ok(Self {
+            directory,
+            settle_time,
+            pending: HashMap::new(),
+            completed: HashSet::new(),
+            notifications,
+        })
+    }
+
+    /// Waits for session logs of the last seven days to be completed and returns them, the oldest first. Every session log is only
+    /// returned once, which includes the logs that already exist when watching starts. Logs are only returned while no other log
+    /// of the window is still growing, so that a run triggered by them does not process a partially written log.
+    ///
+    /// Returns an error of kind Interrupted if cancellation is requested (see crate::cancellation) while waiting.
+    pub fn wait_for_completed_logs(&mut self) -> io::Result<Vec<PathBuf>> {
+        match self.wait_for_completed_logs_until(crate::cancellation::is_requested)? {
+            Some(completed_logs) => Ok(completed_logs),
+            None => Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Cancellation was requested",
+            )),
+        }
+    }
+
+    // Waits for session logs to be completed like wait_for_completed_logs, but returns None as soon as stopped returns true.
+    fn wait_for_completed_logs_until(
+        &mut self,
+        stopped: impl Fn() -> bool,
+    ) -> io::Result<Option<Vec<PathBuf>>> {
+        loop {
+            if stopped() {
+                return Ok(None);
+            }
+            let completed_logs = self.completed_logs()?;
+            if !completed_logs.is_empty() {
+                return Ok(Some(completed_logs));
+            }
+            self.notifications.wait(POLL_INTERVAL)?;
+        }
+    }
+
+    // The session logs of the window that have been completed since the last call, unless another one is still growing.
+    fn completed_logs(&mut self) -> io::Result<Vec<PathBuf>> {
+        let window: Vec<PathBuf> = crate::date_utils::last_seven_days_ymd()
+            .into_iter()
+            .map(|day| session_log_file_path(self.directory.clone(), day))
+            .collect();
+        // The logs of the days that dropped out of the window are forgotten.
+        self.completed.retain(|path| window.contains(path));
+        self.pending.retain(|path, _| window.contains(path));
+
+        let mut completed_logs = Vec::new();
+        let mut growing = false;
+        for path in window {
+            if self.completed.contains(&path) {
+                continue;
+            }
+            let size = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    self.pending.remove(&path);
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            if done_marker_path(&path).exists() {
+                completed_logs.push(path);
+                continue;
+            }
+            let now = Instant::now();
+            let (pending_size, unchanged_since) =
+                self.pending.entry(path.clone()).or_insert((size, now));
+            if *pending_size != size {
+                *pending_size = size;
+                *unchanged_since = now;
+            }
+            if unchanged_since.elapsed() >= self.settle_time {
+                completed_logs.push(path);
+            } else {
+                growing = true;
+            }
+        }
+        if growing {
+            return Ok(Vec::new());
+        }
+        for path in completed_logs.iter() {
+            self.pending.remove(path);
+            self.completed.insert(path.clone());
+        }
+        Ok(completed_logs)
+    }
+}
+
+/// The path of the marker signalling that the given session log is complete.
+pub fn done_marker_path(session_log_path: &Path) -> PathBuf {
+    let mut marker_path = session_log_path.to_path_buf();
+    marker_path.as_mut_os_string().push(DONE_MARKER_SUFFIX);
+    marker_path
+}
+
+/// Calls the given run whenever session logs in the given directory have been completed (see [SessionLogWatcher]), until
+/// cancellation is requested. The run is passed all session logs of the window that have been completed so far, and must not
+/// process any other log, since it may still be written (see [only_completed_logs]). A failed run is logged, but does not stop
+/// the watching: the next completed log triggers another run.
+pub fn watch<E: Display>(
+    directory: PathBuf,
+    settle_time: Duration,
+    run: impl FnMut(&HashSet<PathBuf>) -> Result<(), E>,
+) -> io::Result<()> {
+    let mut watcher = SessionLogWatcher::new(directory.clone(), settle_time)?;
+    tracing::info!(directory = ?directory.as_os_str(), "watching for completed session logs");
+    watch_until(&mut watcher, run, crate::cancellation::is_requested)?;
+    tracing::info!("stopped watching");
+    Ok(())
+}
+
+// Calls the given run whenever the watcher returns completed session logs, like watch, until stopped returns true.
+fn watch_until<E: Display>(
+    watcher: &mut SessionLogWatcher,
+    mut run: impl FnMut(&HashSet<PathBuf>) -> Result<(), E>,
+    stopped: impl Fn() -> bool,
+) -> io::Result<()> {
+    while let Some(completed_logs) = watcher.wait_for_completed_logs_until(&stopped)? {
+        tracing::info!(logs = ?completed_logs, "session logs completed, regenerating the reports");
+        if let Err(error) = run(&watcher.completed) {
+            if stopped() {
+                tracing::warn!("{:#}", error);
+                break;
+            }
+            tracing::error!("the run failed: {:#}", error);
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the unprocessed session logs that are not among the given completed logs by errors, in the same way as the missing
+/// logs (see crate::file_utils::unprocessed_session_log_files), so that a run triggered by [watch] does not process a log that
+/// is still being written. Without completed logs, e.g. outside of watch mode, every log is kept.
+pub fn only_completed_logs(
+    unprocessed_log_files: Vec<io::Result<UnprocessedSessionLogFile>>,
+    completed_logs: Option<&HashSet<PathBuf>>,
+) -> Vec<io::Result<UnprocessedSessionLogFile>> {
+    let completed_logs = match completed_logs {
+        Some(completed_logs) => completed_logs,
+        None => return unprocessed_log_files,
+    };
+    unprocessed_log_files
+        .into_iter()
+        .map(|unprocessed_log_file| match unprocessed_log_file {
+            Ok(log_file) if !completed_logs.contains(&log_file.path) => {
+                Err(io::Error::other(format!(
+                    "The unprocessed session log file: {:?} is not complete yet.",
+                    log_file.path.as_os_str()
+                )))
+            }
+            unprocessed_log_file => unprocessed_log_file,
+        })
+        .collect()
+}
+
+// Wakes up a watcher when a file in the watched directory is created, written or moved there.
+#[cfg(target_os = "linux")]
+struct DirectoryNotifications {
+    inotify_fd: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectoryNotifications {
+    fn new(directory: &Path) -> io::Result<Self> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let inotify_fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if inotify_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The descriptor is closed when this is dropped, including on the errors below.
+        let notifications = Self { inotify_fd };
+        let directory = std::ffi::CString::new(directory.as_os_str().as_bytes())?;
+        let mask = libc::IN_CREATE | libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_ATTRIB;
+        if unsafe { libc::inotify_add_watch(inotify_fd, directory.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(notifications)
+    }
+
+    // Waits until the directory changes or the timeout elapses. The events themselves are discarded, since the watcher checks
+    // all session logs of the window anyway.
+    fn wait(&self, timeout: Duration) -> io::Result<()> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.inotify_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) } < 0 {
+            let error = io::Error::last_os_error();
+            // A signal, e.g. Ctrl-C, interrupted the wait. The watcher checks for cancellation next.
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+        let mut events = [0u8; 4096];
+        while unsafe { libc::read(self.inotify_fd, events.as_mut_ptr().cast(), events.len()) } > 0 {
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for DirectoryNotifications {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.inotify_fd) };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct DirectoryNotifications;
+
+#[cfg(not(target_os = "linux"))]
+impl DirectoryNotifications {
+    fn new(_directory: &Path) -> io::Result<Self> {
+        Ok(Self)
+    }
+
+    fn wait(&self, timeout: Duration) -> io::Result<()> {
+        std::thread::sleep(timeout);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_are_completed_by_markers_or_once_they_stop_growing() {
+        let dir = tempfile::tempdir().unwrap();
+        let window = crate::date_utils::last_seven_days_ymd();
+        let marked = session_log_file_path(dir.path().to_path_buf(), window[5].clone());
+        let growing = session_log_file_path(dir.path().to_path_buf(), window[6].clone());
+        std::fs::write(&marked, b"a").unwrap();
+        std::fs::write(&growing, b"a").unwrap();
+        let mut watcher =
+            SessionLogWatcher::new(dir.path().to_path_buf(), Duration::from_millis(200)).unwrap();
+        // The marked log is complete, but is held back while the other log may still grow.
+        std::fs::write(done_marker_path(&marked), b"").unwrap();
+        assert!(watcher.completed_logs().unwrap().is_empty());
+        std::fs::write(&growing, b"ab").unwrap();
+        assert!(watcher.completed_logs().unwrap().is_empty());
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(vec![marked, growing], watcher.completed_logs().unwrap());
+        // Every log is only returned once.
+        assert!(watcher.completed_logs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn runs_only_process_the_completed_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let window = crate::date_utils::last_seven_days_ymd();
+        let log_path =
+            |day: usize| session_log_file_path(dir.path().to_path_buf(), window[day].clone());
+        std::fs::write(log_path(5), b"a").unwrap();
+        std::fs::write(done_marker_path(&log_path(5)), b"").unwrap();
+        // The settle time is never reached, hence only the marked logs are complete.
+        let mut watcher =
+            SessionLogWatcher::new(dir.path().to_path_buf(), Duration::from_secs(3600)).unwrap();
+        let runs = std::cell::RefCell::new(Vec::new());
+        watch_until(
+            &mut watcher,
+            |completed_logs| {
+                runs.borrow_mut().push(completed_logs.clone());
+                if runs.borrow().len() > 1 {
+                    return Ok(());
+                }
+                // A log starts to be written during the first run, which must not process it.
+                std::fs::write(log_path(6), b"a").unwrap();
+                let unprocessed_log_files = only_completed_logs(
+                    crate::file_utils::unprocessed_session_log_files(
+                        dir.path().to_path_buf(),
+                        |datestamp| datestamp != window[5] && datestamp != window[6],
+                    ),
+                    Some(completed_logs),
+                );
+                assert_eq!(log_path(5), unprocessed_log_files[0].as_ref().unwrap().path);
+                assert!(matches!(&unprocessed_log_files[1],
+                    Err(error) if error.to_string().contains("not complete yet")));
+                Err("the log of the last day is not complete yet")
+            },
+            || {
+                // The failed run does not stop the watching, and the next run is triggered once the log is marked.
+                if runs.borrow().len() == 1 {
+                    std::fs::write(done_marker_path(&log_path(6)), b"").unwrap();
+                }
+                runs.borrow().len() == 2
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                HashSet::from([log_path(5)]),
+                HashSet::from([log_path(5), log_path(6)])
+            ],
+            runs.into_inner()
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{LineError, ReportLine};
+
+/// A line of `camera_top100_YYYYMMDD.txt`: the sessions in which a camera had the highest average number of pics.
+///
+/// In the pipe format a session is written as `session_id:avg_pics:trips` if its number of trips is known and as
+/// `session_id:avg_pics` otherwise. The averages are written with the precision of the formatter if one is given
+/// (e.g. `format!("{:.2}", line)`), and in their shortest exact representation otherwise.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CameraReportLine {
+    pub camera_id: u8,
+    /// Ordered from the highest to the lowest average number of pics.
+    #[serde(serialize_with = "serialize_ranked")]
+    pub sessions: Vec<CameraSession>,
+}
+
+/// A session together with the average number of pics a camera had in it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraSession {
+    pub session_id: Uuid,
+    /// The value the session was ranked by, i.e. the average number of pics unless the report header names another metric
+    /// (e.g. the total number of pics). In the JSON Lines format this is the field `value`.
+    pub avg_pics: f32,
+    /// The number of trips (rentals of the camera in the session) the average was computed over.
+    /// This is None for lines written before the number of trips was part of the report.
+    pub trips: Option<u32>,
+}
+
+impl CameraSession {
+    /// The entry the pipe format pads a line to 100 sessions with: the nil session id with a value and number of trips of 0.
+    pub fn padding() -> Self {
+        Self {
+            session_id: Uuid::nil(),
+            avg_pics: 0.0,
+            trips: Some(0),
+        }
+    }
+
+    /// Whether this is an entry padding the line rather than an actual session (see [CameraSession::padding]).
+    pub fn is_padding(&self) -> bool {
+        *self == Self::padding()
+    }
+}
+
+// The shape of a session in the JSON Lines format.
+// Older versions named the value avg_pics, even if it was a total or a maximum, hence that name is still accepted.
+#[derive(Serialize, Deserialize)]
+struct RankedCameraSession {
+    session_id: Uuid,
+    #[serde(alias = "avg_pics")]
+    value: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trips: Option<u32>,
+    rank: usize,
+}
+
+#[derive(Deserialize)]
+struct RawCameraReportLine {
+    camera_id: u8,
+    sessions: Vec<RankedCameraSession>,
+}
+
+fn serialize_ranked<S: serde::Serializer>(
+    sessions: &[CameraSession],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        sessions
+            .iter()
+            .enumerate()
+            .map(|(idx, session)| RankedCameraSession {
+                session_id: session.session_id,
+                value: session.avg_pics,
+                trips: session.trips,
+                rank: idx + 1,
+            }),
+    )
+}
+
+impl Display for CameraReportLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}|", self.camera_id)?;
+        for session in self.sessions.iter() {
+            match f.precision() {
+                Some(precision) => write!(
+                    f,
+                    "{}:{:.*}",
+                    session.session_id, precision, session.avg_pics
+                )?,
+                None => write!(f, "{}:{}", session.session_id, session.avg_pics)?,
+            }
+            match session.trips {
+                Some(trips) => write!(f, ":{},", trips)?,
+                None => write!(f, ",")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CameraReportLine {
+    type Err = LineError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (camera_id, entries) = crate::split_pipe_line(line)?;
+        let camera_id = camera_id
+            .parse::<u8>()
+            .map_err(|_| LineError::Malformed(format!("invalid camera id: {}", camera_id)))?;
+        let sessions = entries
+            .into_iter()
+            .map(|(session_id, value)| {
+                let (avg_pics, trips) = match value.split_once(':') {
+                    Some((avg_pics, trips)) => (
+                        avg_pics,
+                        Some(trips.parse::<u32>().map_err(|_| {
+                            LineError::Malformed(format!("invalid number of trips: {}", trips))
+                        })?),
+                    ),
+                    None => (value, None),
+                };
+                Ok(CameraSession {
+                    session_id: Uuid::parse_str(session_id).map_err(|_| {
+                        LineError::Malformed(format!("invalid session id: {}", session_id))
+                    })?,
+                    avg_pics: avg_pics.parse::<f32>().map_err(|_| {
+                        LineError::Malformed(format!(
+                            "invalid average number of pics: {}",
+                            avg_pics
+                        ))
+                    })?,
+                    trips,
+                })
+            })
+            .collect::<Result<Vec<_>, LineError>>()?;
+        Ok(Self {
+            camera_id,
+            sessions,
+        })
+    }
+}
+
+impl ReportLine for CameraReportLine {
+    const MAX_SESSIONS: usize = 100;
+
+    type Id = u8;
+
+    fn id(&self) -> Self::Id {
+        self.camera_id
+    }
+
+    // The padding of the pipe format is left out, so that lines padded to a different number of sessions compare equal.
+    fn ranked_values(&self) -> Vec<(Uuid, f64)> {
+        self.sessions
+            .iter()
+            .filter(|session| !session.is_padding())
+            .map(|session| (session.session_id, session.avg_pics as f64))
+            .collect()
+    }
+
+    fn from_json_line(line: &str) -> Result<Self, LineError> {
+        let raw: RawCameraReportLine =
+            serde_json::from_str(line).map_err(|e| LineError::Malformed(e.to_string()))?;
+        crate::validate_ranks(raw.sessions.iter().map(|session| session.rank))?;
+        Ok(Self {
+            camera_id: raw.camera_id,
+            sessions: raw
+                .sessions
+                .into_iter()
+                .map(|session| CameraSession {
+                    session_id: session.session_id,
+                    avg_pics: session.value,
+                    trips: session.trips,
+                })
+                .collect(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), LineError> {
+        crate::validate_ranked_values(
+            self.sessions.iter().map(|session| session.avg_pics as f64),
+            Self::MAX_SESSIONS,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LineFormat;
+
+    fn camera_report_line() -> CameraReportLine {
+        CameraReportLine {
+            camera_id: 42,
+            sessions: vec![
+                CameraSession {
+                    session_id: Uuid::from_u128(1),
+                    avg_pics: 7.0 / 3.0,
+                    trips: None,
+                },
+                CameraSession {
+                    session_id: Uuid::from_u128(2),
+                    avg_pics: 2.0,
+                    trips: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn pipe_format_round_trips_exactly() {
+        let line = camera_report_line();
+        let written = line.to_string();
+        assert_eq!(
+            format!(
+                "42|{}:2.3333333,{}:2,",
+                Uuid::from_u128(1),
+                Uuid::from_u128(2)
+            ),
+            written
+        );
+        let read = CameraReportLine::parse(&written, LineFormat::Pipe).unwrap();
+        assert_eq!(line, read);
+        assert_eq!(written, read.to_string());
+    }
+
+    #[test]
+    fn pipe_format_respects_precision() {
+        let line = camera_report_line();
+        let written = format!("{:.2}", line);
+        assert_eq!(
+            format!(
+                "42|{}:2.33,{}:2.00,",
+                Uuid::from_u128(1),
+                Uuid::from_u128(2)
+            ),
+            written
+        );
+        assert!(CameraReportLine::parse(&written, LineFormat::Pipe).is_ok());
+    }
+
+    #[test]
+    fn pipe_format_round_trips_trips() {
+        let mut line = camera_report_line();
+        line.sessions[0].trips = Some(3);
+        line.sessions[1].trips = Some(1);
+        let written = format!("{:.2}", line);
+        assert_eq!(
+            format!(
+                "42|{}:2.33:3,{}:2.00:1,",
+                Uuid::from_u128(1),
+                Uuid::from_u128(2)
+            ),
+            written
+        );
+        let read = CameraReportLine::parse(&line.to_string(), LineFormat::Pipe).unwrap();
+        assert_eq!(line, read);
+        assert!(matches!(
+            CameraReportLine::parse(&format!("42|{}:2:x,", Uuid::from_u128(1)), LineFormat::Pipe),
+            Err(LineError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn json_lines_format_round_trips_exactly() {
+        let mut line = camera_report_line();
+        let written = serde_json::to_string(&line).unwrap();
+        let read = CameraReportLine::parse(&written, LineFormat::JsonLines).unwrap();
+        assert_eq!(line, read);
+
+        line.sessions[0].trips = Some(3);
+        let written = serde_json::to_string(&line).unwrap();
+        assert!(written.contains(r#""value":2.0,"rank":2"#));
+        assert!(written.contains(r#""trips":3"#));
+        let read = CameraReportLine::parse(&written, LineFormat::JsonLines).unwrap();
+        assert_eq!(line, read);
+    }
+
+    #[test]
+    fn json_lines_written_with_avg_pics_can_be_read() {
+        let json = format!(
+            r#"{{"camera_id":1,"sessions":[{{"session_id":"{}","avg_pics":2.5,"rank":1}}]}}"#,
+            Uuid::from_u128(1)
+        );
+        let read = CameraReportLine::parse(&json, LineFormat::JsonLines).unwrap();
+        assert_eq!(2.5, read.sessions[0].avg_pics);
+    }
+
+    #[test]
+    fn unordered_sessions_are_rejected() {
+        let mut line = camera_report_line();
+        line.sessions.reverse();
+        assert!(matches!(
+            CameraReportLine::parse(&line.to_string(), LineFormat::Pipe),
+            Err(LineError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn wrong_json_ranks_are_rejected() {
+        let json = format!(
+            r#"{{"camera_id":1,"sessions":[{{"session_id":"{}","avg_pics":2.0,"rank":2}}]}}"#,
+            Uuid::from_u128(1)
+        );
+        assert!(matches!(
+            CameraReportLine::parse(&json, LineFormat::JsonLines),
+            Err(LineError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt::{Display, Formatter};
+
+/// The reasons a single line of a report can be rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineError {
+    /// The line could not be parsed.
+    Malformed(String),
+    /// The line could be parsed, but its content is not a valid report line. For example the sessions are not
+    /// ordered by rank or there are too many of them.
+    Invalid(String),
+}
+
+impl Display for LineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LineError::Malformed(reason) => write!(f, "malformed line: {}", reason),
+            LineError::Invalid(reason) => write!(f, "invalid line: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for LineError {}
+
+/// Errors that can occur while reading or writing a report.
+#[derive(Debug)]
+pub enum ReportError {
+    /// Reading from or writing to the underlying reader/writer failed.
+    Io(std::io::Error),
+    /// The line with the given (1 based) line number was rejected.
+    Line {
+        line_number: usize,
+        error: LineError,
+    },
+    /// A header was written after the first line of the report.
+    MisplacedHeader,
+}
+
+impl Display for ReportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportError::Io(error) => write!(f, "{}", error),
+            ReportError::Line { line_number, error } => {
+                write!(f, "line {}: {}", line_number, error)
+            }
+            ReportError::MisplacedHeader => {
+                write!(f, "the header must be written before any report line")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReportError::Io(error) => Some(error),
+            ReportError::Line { error, .. } => Some(error),
+            ReportError::MisplacedHeader => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ReportError {
+    fn from(error: std::io::Error) -> Self {
+        ReportError::Io(error)
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::LineFormat;
+
+/// The optional first line of a report, describing how the report was produced.
+///
+/// The camera report always starts with a header. Reports without a header were produced by older versions, which
+/// always ranked sessions by their raw mean.
+/// In the pipe format the header is written as `# ranking_metric=<metric>` and in the JSON Lines format as
+/// `{"ranking_metric":"<metric>"}`. As for the report lines, Display yields the former and Serialize the latter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReportHeader {
+    /// The metric the sessions of the report are ranked by, e.g. "shrunk-mean".
+    pub ranking_metric: String,
+}
+
+impl ReportHeader {
+    const PIPE_PREFIX: &'static str = "# ranking_metric=";
+
+    /// Parses the given line as a header. Returns None if the line is not a header.
+    pub fn parse(line: &str, format: LineFormat) -> Option<Self> {
+        match format {
+            LineFormat::Pipe => line
+                .strip_prefix(Self::PIPE_PREFIX)
+                .map(|ranking_metric| Self {
+                    ranking_metric: ranking_metric.to_string(),
+                }),
+            LineFormat::JsonLines => serde_json::from_str(line).ok(),
+        }
+    }
+}
+
+impl Display for ReportHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", Self::PIPE_PREFIX, self.ranking_metric)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips_in_both_formats() {
+        let header = ReportHeader {
+            ranking_metric: "shrunk-mean".to_string(),
+        };
+        assert_eq!("# ranking_metric=shrunk-mean", header.to_string());
+        assert_eq!(
+            Some(header.clone()),
+            ReportHeader::parse(&header.to_string(), LineFormat::Pipe)
+        );
+        let json = serde_json::to_string(&header).unwrap();
+        assert_eq!(
+            Some(header),
+            ReportHeader::parse(&json, LineFormat::JsonLines)
+        );
+        assert_eq!(
+            None,
+            ReportHeader::parse(r#"{"camera_id":1,"sessions":[]}"#, LineFormat::JsonLines)
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! # Safari stats reports
+//!
+//! This library provides typed readers and writers for the reports produced by camera-stats and user-stats:
+//!
+//! * `camera_top100_YYYYMMDD.txt` (or `.jsonl`) where every line is described by a [CameraReportLine].
+//! * `user_top_10_YYYYMMDD.txt` (or `.jsonl`) where every line is described by a [UserReportLine].
+//!
+//! The binaries format their output with the types found here, hence anything written by them can be read back
+//! exactly (floats included) and vice versa. Reports may start with a [ReportHeader] describing how they were produced.
+//!
+//! ```no_run
+//! use safari_stats_reports::{CameraReportLine, ReportReader};
+//!
+//! let reader = ReportReader::<_, CameraReportLine>::open("camera_top100_20210704.txt").unwrap();
+//! for line in reader {
+//!     let line = line.unwrap();
+//!     println!("camera {} has {} sessions", line.camera_id, line.sessions.len());
+//! }
+//! ```
+
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+mod camera;
+mod error;
+mod header;
+mod reading;
+mod user;
+mod writing;
+
+pub use camera::{CameraReportLine, CameraSession};
+pub use error::{LineError, ReportError};
+pub use header::ReportHeader;
+pub use reading::ReportReader;
+pub use user::{UserReportLine, UserSession};
+pub use writing::ReportWriter;
+
+/// The text formats a report can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineFormat {
+    /// Each line is of the form id|session_id1:value1,session_id2:value2, ... (note the trailing comma).
+    Pipe,
+    /// Each line is a JSON object of the form {"<entity>_id":..,"sessions":[{"session_id":..,<value>:..,"rank":..}, ...]}.
+    JsonLines,
+}
+
+impl LineFormat {
+    /// Infers the format from a file extension: `.jsonl` corresponds to JSON Lines and anything else to the pipe format.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") => LineFormat::JsonLines,
+            _ => LineFormat::Pipe,
+        }
+    }
+}
+
+/// A single line of one of our reports.
+///
+/// The Display implementation yields the pipe format and the Serialize implementation yields the JSON Lines format.
+pub trait ReportLine: Display + Serialize + FromStr<Err = LineError> {
+    /// The maximum number of sessions a single line may contain.
+    const MAX_SESSIONS: usize;
+
+    /// The type of the camera or user id. The binaries write their reports sorted by this id.
+    type Id: Ord + Copy + Display + Serialize;
+
+    /// The id of the camera or user the line describes.
+    fn id(&self) -> Self::Id;
+
+    /// The sessions ordered by rank together with their value (the average or the number of pics).
+    fn ranked_values(&self) -> Vec<(Uuid, f64)>;
+
+    /// Parses a line of the JSON Lines format.
+    fn from_json_line(line: &str) -> Result<Self, LineError>;
+
+    /// Checks that the line contains at most MAX_SESSIONS sessions ordered from best to worst.
+    fn validate(&self) -> Result<(), LineError>;
+
+    /// Parses a line in the given format and validates it.
+    fn parse(line: &str, format: LineFormat) -> Result<Self, LineError> {
+        let parsed = match format {
+            LineFormat::Pipe => line.parse::<Self>()?,
+            LineFormat::JsonLines => Self::from_json_line(line)?,
+        };
+        parsed.validate()?;
+        Ok(parsed)
+    }
+}
+
+// The (session_id, value) entries of a line in the pipe format, both still unparsed.
+type PipeLineEntries<'a> = Vec<(&'a str, &'a str)>;
+
+// Splits a line of the pipe format into its id and its session_id:value entries.
+// Every entry is required to be followed by a comma, which is exactly what the binaries emit.
+fn split_pipe_line(line: &str) -> Result<(&str, PipeLineEntries<'_>), LineError> {
+    let (id, entries) = line
+        .split_once('|')
+        .ok_or_else(|| LineError::Malformed(format!("no '|' found in: {}", line)))?;
+    if entries.is_empty() {
+        return Ok((id, Vec::new()));
+    }
+    let entries = entries
+        .strip_suffix(',')
+        .ok_or_else(|| LineError::Malformed(format!("missing trailing comma in: {}", line)))?;
+    let pairs = entries
+        .split(',')
+        .map(|entry| {
+            entry.split_once(':').ok_or_else(|| {
+                LineError::Malformed(format!("expected session_id:value but found: {}", entry))
+            })
+        })
+        .collect::<Result<Vec<_>, LineError>>()?;
+    Ok((id, pairs))
+}
+
+// Checks that there are at most max_sessions values and that they are ordered from highest to lowest.
+fn validate_ranked_values<I: IntoIterator<Item = f64>>(
+    values: I,
+    max_sessions: usize,
+) -> Result<(), LineError> {
+    let mut previous = f64::INFINITY;
+    for (idx, value) in values.into_iter().enumerate() {
+        if idx >= max_sessions {
+            return Err(LineError::Invalid(format!(
+                "more than {} sessions",
+                max_sessions
+            )));
+        }
+        if !value.is_finite() {
+            return Err(LineError::Invalid(format!(
+                "the value at rank {} is not a finite number",
+                idx + 1
+            )));
+        }
+        if value > previous {
+            return Err(LineError::Invalid(format!(
+                "the value at rank {} is higher than the value at rank {}",
+                idx + 1,
+                idx
+            )));
+        }
+        previous = value;
+    }
+    Ok(())
+}
+
+// Checks that the ranks found in a line of the JSON Lines format are 1, 2, 3, ...
+fn validate_ranks<I: IntoIterator<Item = usize>>(ranks: I) -> Result<(), LineError> {
+    for (idx, rank) in ranks.into_iter().enumerate() {
+        if rank != idx + 1 {
+            return Err(LineError::Invalid(format!(
+                "expected rank {} but found rank {}",
+                idx + 1,
+                rank
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_pipe_line_requires_trailing_comma() {
+        assert_eq!(
+            ("7", vec![("a", "1"), ("b", "2")]),
+            split_pipe_line("7|a:1,b:2,").unwrap()
+        );
+        assert_eq!(("7", vec![]), split_pipe_line("7|").unwrap());
+        assert!(matches!(
+            split_pipe_line("7|a:1,b:2"),
+            Err(LineError::Malformed(_))
+        ));
+        assert!(matches!(split_pipe_line("7"), Err(LineError::Malformed(_))));
+    }
+
+    #[test]
+    fn validate_ranked_values_detects_unordered_and_too_many_values() {
+        assert!(validate_ranked_values(vec![3.0, 2.0, 2.0], 3).is_ok());
+        assert!(validate_ranked_values(vec![3.0, 2.0, 2.5], 3).is_err());
+        assert!(validate_ranked_values(vec![3.0, 2.0, 1.0, 0.0], 3).is_err());
+        assert!(validate_ranked_values(vec![f64::NAN], 3).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Lines},
+    marker::PhantomData,
+    path::Path,
+};
+
+use crate::{LineFormat, ReportError, ReportHeader, ReportLine};
+
+/// Streams the lines of a report, one parsed and validated line at a time.
+///
+/// Only a single line is held in memory at any time, hence this can be used to read the (very large) user reports.
+/// A header on the first line is not yielded as a line, but is available from [ReportReader::header] once the first line has been read.
+pub struct ReportReader<R: BufRead, L: ReportLine> {
+    lines: Lines<R>,
+    format: LineFormat,
+    line_number: usize,
+    header: Option<ReportHeader>,
+    _line: PhantomData<L>,
+}
+
+impl<L: ReportLine> ReportReader<BufReader<File>, L> {
+    /// Opens the report at the given path. The format is inferred from the file extension (see [LineFormat::from_path]).
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ReportError> {
+        let format = LineFormat::from_path(path.as_ref());
+        let file = File::open(path)?;
+        Ok(Self::new(BufReader::new(file), format))
+    }
+}
+
+impl<R: BufRead, L: ReportLine> ReportReader<R, L> {
+    pub fn new(reader: R, format: LineFormat) -> Self {
+        Self {
+            lines: reader.lines(),
+            format,
+            line_number: 0,
+            header: None,
+            _line: PhantomData,
+        }
+    }
+
+    /// The header of the report, if it has one.
+    pub fn header(&self) -> Option<&ReportHeader> {
+        self.header.as_ref()
+    }
+}
+
+impl<R: BufRead, L: ReportLine> Iterator for ReportReader<R, L> {
+    type Item = Result<L, ReportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = self.lines.next()?;
+        self.line_number += 1;
+        if self.line_number == 1 {
+            if let Ok(Some(header)) = line
+                .as_ref()
+                .map(|line| ReportHeader::parse(line, self.format))
+            {
+                self.header = Some(header);
+                line = self.lines.next()?;
+                self.line_number += 1;
+            }
+        }
+        let line_number = self.line_number;
+        Some(line.map_err(ReportError::from).and_then(|line| {
+            L::parse(line.as_str(), self.format)
+                .map_err(|error| ReportError::Line { line_number, error })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LineError, UserReportLine};
+    use uuid::Uuid;
+
+    #[test]
+    fn reader_reports_line_numbers() {
+        let report = format!(
+            "{}|{}:3,\nnot a line\n",
+            Uuid::from_u128(1),
+            Uuid::from_u128(2)
+        );
+        let mut reader =
+            ReportReader::<_, UserReportLine>::new(report.as_bytes(), LineFormat::Pipe);
+        assert_eq!(Uuid::from_u128(1), reader.next().unwrap().unwrap().user_id);
+        assert!(matches!(
+            reader.next(),
+            Some(Err(ReportError::Line {
+                line_number: 2,
+                error: LineError::Malformed(_)
+            }))
+        ));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn header_is_not_yielded_as_a_line() {
+        let report = format!(
+            "# ranking_metric=shrunk-mean\n{}|{}:3,\n",
+            Uuid::from_u128(1),
+            Uuid::from_u128(2)
+        );
+        let mut reader =
+            ReportReader::<_, UserReportLine>::new(report.as_bytes(), LineFormat::Pipe);
+        assert_eq!(Uuid::from_u128(1), reader.next().unwrap().unwrap().user_id);
+        assert_eq!("shrunk-mean", reader.header().unwrap().ranking_metric);
+        assert!(reader.next().is_none());
+    }
+}
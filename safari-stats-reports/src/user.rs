@@ -0,0 +1,178 @@
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{LineError, ReportLine};
+
+/// A line of `user_top_10_YYYYMMDD.txt`: the sessions in which a user took the most pics.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UserReportLine {
+    pub user_id: Uuid,
+    /// Ordered from the highest to the lowest number of pics.
+    #[serde(serialize_with = "serialize_ranked")]
+    pub sessions: Vec<UserSession>,
+}
+
+/// A session together with the number of pics a user took in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserSession {
+    pub session_id: Uuid,
+    pub nb_pics: u8,
+}
+
+// The shape of a session in the JSON Lines format.
+#[derive(Serialize, Deserialize)]
+struct RankedUserSession {
+    session_id: Uuid,
+    nb_pics: u8,
+    rank: usize,
+}
+
+#[derive(Deserialize)]
+struct RawUserReportLine {
+    user_id: Uuid,
+    sessions: Vec<RankedUserSession>,
+}
+
+fn serialize_ranked<S: serde::Serializer>(
+    sessions: &[UserSession],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        sessions
+            .iter()
+            .enumerate()
+            .map(|(idx, session)| RankedUserSession {
+                session_id: session.session_id,
+                nb_pics: session.nb_pics,
+                rank: idx + 1,
+            }),
+    )
+}
+
+impl Display for UserReportLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}|", self.user_id)?;
+        for session in self.sessions.iter() {
+            write!(f, "{}:{},", session.session_id, session.nb_pics)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for UserReportLine {
+    type Err = LineError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (user_id, entries) = crate::split_pipe_line(line)?;
+        let user_id = Uuid::parse_str(user_id)
+            .map_err(|_| LineError::Malformed(format!("invalid user id: {}", user_id)))?;
+        let sessions = entries
+            .into_iter()
+            .map(|(session_id, nb_pics)| {
+                Ok(UserSession {
+                    session_id: Uuid::parse_str(session_id).map_err(|_| {
+                        LineError::Malformed(format!("invalid session id: {}", session_id))
+                    })?,
+                    nb_pics: nb_pics.parse::<u8>().map_err(|_| {
+                        LineError::Malformed(format!("invalid number of pics: {}", nb_pics))
+                    })?,
+                })
+            })
+            .collect::<Result<Vec<_>, LineError>>()?;
+        Ok(Self { user_id, sessions })
+    }
+}
+
+impl ReportLine for UserReportLine {
+    const MAX_SESSIONS: usize = 10;
+
+    type Id = Uuid;
+
+    fn id(&self) -> Self::Id {
+        self.user_id
+    }
+
+    fn ranked_values(&self) -> Vec<(Uuid, f64)> {
+        self.sessions
+            .iter()
+            .map(|session| (session.session_id, session.nb_pics as f64))
+            .collect()
+    }
+
+    fn from_json_line(line: &str) -> Result<Self, LineError> {
+        let raw: RawUserReportLine =
+            serde_json::from_str(line).map_err(|e| LineError::Malformed(e.to_string()))?;
+        crate::validate_ranks(raw.sessions.iter().map(|session| session.rank))?;
+        Ok(Self {
+            user_id: raw.user_id,
+            sessions: raw
+                .sessions
+                .into_iter()
+                .map(|session| UserSession {
+                    session_id: session.session_id,
+                    nb_pics: session.nb_pics,
+                })
+                .collect(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), LineError> {
+        crate::validate_ranked_values(
+            self.sessions.iter().map(|session| session.nb_pics as f64),
+            Self::MAX_SESSIONS,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LineFormat;
+
+    #[test]
+    fn pipe_and_json_lines_formats_round_trip() {
+        let line = UserReportLine {
+            user_id: Uuid::from_u128(3),
+            sessions: (0..10u8)
+                .rev()
+                .map(|nb_pics| UserSession {
+                    session_id: Uuid::from_u128(nb_pics as u128),
+                    nb_pics,
+                })
+                .collect(),
+        };
+        let pipe = line.to_string();
+        assert_eq!(
+            line,
+            UserReportLine::parse(&pipe, LineFormat::Pipe).unwrap()
+        );
+        let json = serde_json::to_string(&line).unwrap();
+        assert_eq!(
+            line,
+            UserReportLine::parse(&json, LineFormat::JsonLines).unwrap()
+        );
+    }
+
+    #[test]
+    fn more_than_ten_sessions_are_rejected() {
+        let line = UserReportLine {
+            user_id: Uuid::from_u128(3),
+            sessions: vec![
+                UserSession {
+                    session_id: Uuid::from_u128(1),
+                    nb_pics: 1,
+                };
+                11
+            ],
+        };
+        assert!(matches!(
+            UserReportLine::parse(&line.to_string(), LineFormat::Pipe),
+            Err(LineError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    io::{BufWriter, Write},
+    marker::PhantomData,
+};
+
+use crate::{LineFormat, ReportError, ReportHeader, ReportLine};
+
+/// Writes report lines in the given format. Lines are validated before they are written,
+/// so a report produced by this writer can always be read back by a [crate::ReportReader].
+pub struct ReportWriter<W: Write, L: ReportLine> {
+    writer: BufWriter<W>,
+    format: LineFormat,
+    // The precision the values are written with in the pipe format, see e.g. [crate::CameraReportLine].
+    precision: Option<usize>,
+    line_number: usize,
+    _line: PhantomData<L>,
+}
+
+impl<W: Write, L: ReportLine> ReportWriter<W, L> {
+    pub fn new(writer: W, format: LineFormat) -> Self {
+        Self::with_precision(writer, format, None)
+    }
+
+    /// Like [ReportWriter::new], but the values are written with the given number of decimals in the pipe format.
+    pub fn with_precision(writer: W, format: LineFormat, precision: Option<usize>) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            format,
+            precision,
+            line_number: 0,
+            _line: PhantomData,
+        }
+    }
+
+    /// Writes a header. Returns [ReportError::MisplacedHeader] if a header or a line has already been written.
+    pub fn write_header(&mut self, header: &ReportHeader) -> Result<(), ReportError> {
+        if self.line_number != 0 {
+            return Err(ReportError::MisplacedHeader);
+        }
+        self.line_number += 1;
+        match self.format {
+            LineFormat::Pipe => writeln!(self.writer, "{}", header)?,
+            LineFormat::JsonLines => {
+                serde_json::to_writer(&mut self.writer, header).map_err(std::io::Error::from)?;
+                writeln!(self.writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, line: &L) -> Result<(), ReportError> {
+        self.line_number += 1;
+        line.validate().map_err(|error| ReportError::Line {
+            line_number: self.line_number,
+            error,
+        })?;
+        match (self.format, self.precision) {
+            (LineFormat::Pipe, Some(precision)) => writeln!(self.writer, "{:.*}", precision, line)?,
+            (LineFormat::Pipe, None) => writeln!(self.writer, "{}", line)?,
+            (LineFormat::JsonLines, _) => {
+                serde_json::to_writer(&mut self.writer, line).map_err(std::io::Error::from)?;
+                writeln!(self.writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes all buffered lines and returns the underlying writer.
+    pub fn finish(self) -> Result<W, ReportError> {
+        self.writer
+            .into_inner()
+            .map_err(|error| ReportError::Io(error.into_error()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CameraReportLine, CameraSession, ReportReader};
+    use uuid::Uuid;
+
+    #[test]
+    fn written_reports_can_be_read_back() {
+        let lines: Vec<CameraReportLine> = (1..=3u8)
+            .map(|camera_id| CameraReportLine {
+                camera_id,
+                sessions: vec![CameraSession {
+                    session_id: Uuid::from_u128(camera_id as u128),
+                    avg_pics: 1.0 / camera_id as f32,
+                    trips: None,
+                }],
+            })
+            .collect();
+        for format in [LineFormat::Pipe, LineFormat::JsonLines] {
+            let mut writer = ReportWriter::new(Vec::new(), format);
+            for line in lines.iter() {
+                writer.write(line).unwrap();
+            }
+            let bytes = writer.finish().unwrap();
+            let read: Vec<CameraReportLine> = ReportReader::new(bytes.as_slice(), format)
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(lines, read);
+        }
+    }
+
+    #[test]
+    fn headers_are_only_written_first() {
+        let header = ReportHeader {
+            ranking_metric: "raw-mean".to_string(),
+        };
+        let line = CameraReportLine {
+            camera_id: 1,
+            sessions: vec![CameraSession {
+                session_id: Uuid::from_u128(1),
+                avg_pics: 2.0 / 3.0,
+                trips: Some(3),
+            }],
+        };
+        let mut writer = ReportWriter::with_precision(Vec::new(), LineFormat::Pipe, Some(2));
+        writer.write_header(&header).unwrap();
+        writer.write(&line).unwrap();
+        assert!(matches!(
+            writer.write_header(&header),
+            Err(ReportError::MisplacedHeader)
+        ));
+        let bytes = writer.finish().unwrap();
+        assert_eq!(
+            format!(
+                "# ranking_metric=raw-mean\n1|{}:0.67:3,\n",
+                Uuid::from_u128(1)
+            ),
+            String::from_utf8(bytes).unwrap()
+        );
+    }
+}
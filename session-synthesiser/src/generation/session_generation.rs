@@ -1,6 +1,6 @@
 use super::*;
 
-use std::{collections::HashSet, iter, u128};
+use std::{collections::HashSet, iter};
 
 pub(crate) struct Session {
     pub(super) id: Uuid,
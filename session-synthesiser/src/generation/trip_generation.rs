@@ -1,4 +1,4 @@
-use std::{collections::HashMap, u8};
+use std::collections::HashMap;
 
 use rand::prelude::SliceRandom;
 use rand_distr::{Binomial, Distribution};
@@ -15,7 +15,7 @@ impl TripData {
         let participants: Vec<ParticipantData> = team1_data
             .participants
             .into_iter()
-            .chain(team2_data.participants.into_iter())
+            .chain(team2_data.participants)
             .collect();
         participants
     }
@@ -111,7 +111,7 @@ fn gen_camera_choices<T: Rng>(
         .iter()
         .chain(team2_user_ids.iter())
         .cloned()
-        .zip(camera_ids.into_iter())
+        .zip(camera_ids)
         .collect();
     ParticipantToCamera {
         user_id_to_camera_id,
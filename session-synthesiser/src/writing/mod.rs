@@ -113,7 +113,7 @@ fn fill_batch_of_trips<T: Rng>(
     num_sessions_to_generate_this_iteration: usize,
     rng: &mut T,
 ) {
-    for _ in (0..num_sessions_to_generate_this_iteration).into_iter() {
+    for _ in 0..num_sessions_to_generate_this_iteration {
         let synthetic_session = generation::session_generation::generate_session(rng);
         let num_trips_for_session =
             generation::session_generation::generate_number_of_trips(&synthetic_session, rng);
@@ -0,0 +1,684 @@
+// This module contains functionality for comparing two camera reports or two user reports, for instance the reports
+// of two consecutive days or the reports of two runs over the same logs.
+//
+// Both camera-stats and user-stats write their reports sorted by camera/user id, hence we can compare two reports
+// by streaming through them simultaneously (just like a merge join) without loading any of them into memory.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use safari_stats_reports::{
+    CameraReportLine, ReportError, ReportLine, ReportReader, UserReportLine,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use camera_stats::configuration::DailyCameraBestAvgPicsFilesConfig;
+use user_stats::configuration::DailyUsersStatsConfig;
+
+/// The kinds of reports that can be compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportKind {
+    Camera,
+    User,
+}
+
+impl FromStr for ReportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "camera" => Ok(ReportKind::Camera),
+            "user" => Ok(ReportKind::User),
+            other => Err(format!(
+                "unknown report kind: {}. Expected one of: camera, user",
+                other
+            )),
+        }
+    }
+}
+
+impl ReportKind {
+    // Infers the kind of report from its filename.
+    fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let filename = path.as_ref().file_name()?.to_str()?;
+        if filename.starts_with(DailyCameraBestAvgPicsFilesConfig::FILE_PREFIX) {
+            Some(ReportKind::Camera)
+        } else if filename.starts_with(DailyUsersStatsConfig::FILE_PREFIX) {
+            Some(ReportKind::User)
+        } else {
+            None
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            ReportKind::Camera => "camera",
+            ReportKind::User => "user",
+        }
+    }
+
+    // The path of the report of this kind written on the given date (YYYYMMDD) to the given directory.
+    // We prefer the pipe format, but fall back to the JSON Lines format if only that exists.
+    fn report_path(&self, report_directory: &Path, date_ymd: &str) -> PathBuf {
+        let (prefix, extensions) = match self {
+            ReportKind::Camera => (
+                DailyCameraBestAvgPicsFilesConfig::FILE_PREFIX,
+                [
+                    DailyCameraBestAvgPicsFilesConfig::FILE_EXTENSION,
+                    DailyCameraBestAvgPicsFilesConfig::JSONL_FILE_EXTENSION,
+                ],
+            ),
+            ReportKind::User => (
+                DailyUsersStatsConfig::FILE_PREFIX,
+                [
+                    DailyUsersStatsConfig::FILE_EXTENSION,
+                    DailyUsersStatsConfig::JSONL_FILE_EXTENSION,
+                ],
+            ),
+        };
+        let candidates: Vec<PathBuf> = extensions
+            .iter()
+            .map(|extension| report_directory.join(format!("{}{}{}", prefix, date_ymd, extension)))
+            .collect();
+        candidates
+            .iter()
+            .find(|path| path.exists())
+            .unwrap_or(&candidates[0])
+            .clone()
+    }
+}
+
+// Turns a command line argument into the path of a report. The argument is either a path to a report file
+// or an as-of date of the form YYYYMMDD, in which case the report of that date is looked up in the report directory.
+fn resolve_report_path(
+    argument: &str,
+    report_directory: Option<&Path>,
+    kind: Option<ReportKind>,
+) -> Result<PathBuf> {
+    let path = PathBuf::from(argument);
+    let date_regex = Regex::new(r"^\d{8}$").unwrap();
+    if path.exists() || !date_regex.is_match(argument) {
+        return Ok(path);
+    }
+    match (report_directory, kind) {
+        (Some(report_directory), Some(kind)) => Ok(kind.report_path(report_directory, argument)),
+        _ => bail!(
+            "{} looks like a date. Comparing reports by date requires both --report-dir and --kind",
+            argument
+        ),
+    }
+}
+
+/// Compares two reports, writes the differences as human-readable text to stdout and (optionally) as JSON to json_path.
+///
+/// The reports are given either as paths or as dates (YYYYMMDD) of reports found in report_directory.
+/// Returns whether any differences were found.
+pub fn run(
+    old: String,
+    new: String,
+    report_directory: Option<PathBuf>,
+    kind: Option<ReportKind>,
+    json_path: Option<PathBuf>,
+) -> Result<bool> {
+    let old_path = resolve_report_path(old.as_str(), report_directory.as_deref(), kind)?;
+    let new_path = resolve_report_path(new.as_str(), report_directory.as_deref(), kind)?;
+    let kind = match kind.or_else(|| ReportKind::from_path(&old_path)) {
+        Some(kind) => kind,
+        None => bail!(
+            "Could not infer whether {:?} is a camera or a user report. Please specify --kind",
+            old_path.as_os_str()
+        ),
+    };
+    if let Some(new_kind) = ReportKind::from_path(&new_path) {
+        if new_kind != kind {
+            bail!(
+                "Cannot compare a {} report with a {} report",
+                kind.label(),
+                new_kind.label()
+            );
+        }
+    }
+    match kind {
+        ReportKind::Camera => {
+            run_with_line_type::<CameraReportLine>(&old_path, &new_path, kind, json_path)
+        }
+        ReportKind::User => {
+            run_with_line_type::<UserReportLine>(&old_path, &new_path, kind, json_path)
+        }
+    }
+}
+
+fn run_with_line_type<L: ReportLine>(
+    old_path: &Path,
+    new_path: &Path,
+    kind: ReportKind,
+    json_path: Option<PathBuf>,
+) -> Result<bool> {
+    let open = |path: &Path| {
+        ReportReader::<_, L>::open(path)
+            .with_context(|| format!("Failed to open the report: {:?}", path.as_os_str()))
+    };
+    let (old_reader, new_reader) = (open(old_path)?, open(new_path)?);
+
+    let stdout = std::io::stdout();
+    let mut text_writer = BufWriter::new(stdout.lock());
+    let mut json_writer = match json_path {
+        Some(json_path) => {
+            let file = File::create(&json_path)
+                .with_context(|| format!("Failed to create file: {:?}", json_path.as_os_str()))?;
+            Some(JsonDiffWriter::new(
+                BufWriter::new(file),
+                old_path,
+                new_path,
+            )?)
+        }
+        None => None,
+    };
+    writeln!(
+        text_writer,
+        "--- {}\n+++ {}",
+        old_path.display(),
+        new_path.display()
+    )?;
+    let summary = diff_reports(old_reader, new_reader, |entity_diff| {
+        write_text(&mut text_writer, kind, &entity_diff)?;
+        if let Some(json_writer) = json_writer.as_mut() {
+            json_writer.write(&entity_diff)?;
+        }
+        Ok(())
+    })?;
+    writeln!(
+        text_writer,
+        "{} {}s added, {} removed, {} changed, {} unchanged",
+        summary.entities_added,
+        kind.label(),
+        summary.entities_removed,
+        summary.entities_changed,
+        summary.entities_unchanged
+    )?;
+    text_writer.flush()?;
+    if let Some(json_writer) = json_writer {
+        json_writer.finish(&summary)?;
+    }
+    Ok(summary.has_differences())
+}
+
+/// The difference between the lines describing the same camera or user in two reports.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub(crate) enum EntityDiff<Id> {
+    // The camera or user only appears in the new report.
+    Added {
+        id: Id,
+    },
+    // The camera or user only appears in the old report.
+    Removed {
+        id: Id,
+    },
+    Changed {
+        id: Id,
+        // Sessions in the new top-K list that were not in the old one.
+        sessions_entered: Vec<RankedSession>,
+        // Sessions in the old top-K list that are not in the new one.
+        sessions_left: Vec<RankedSession>,
+        rank_changes: Vec<RankChange>,
+        score_deltas: Vec<ScoreDelta>,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct RankedSession {
+    session_id: Uuid,
+    rank: usize,
+    value: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct RankChange {
+    session_id: Uuid,
+    old_rank: usize,
+    new_rank: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ScoreDelta {
+    session_id: Uuid,
+    old_value: f64,
+    new_value: f64,
+    delta: f64,
+}
+
+/// Counts of cameras or users per kind of difference.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub(crate) struct DiffSummary {
+    entities_added: usize,
+    entities_removed: usize,
+    entities_changed: usize,
+    entities_unchanged: usize,
+}
+
+impl DiffSummary {
+    fn has_differences(&self) -> bool {
+        self.entities_added + self.entities_removed + self.entities_changed > 0
+    }
+}
+
+// The rank (starting at 1) of a session in a top-K list and its value.
+type RankAndValue = (usize, f64);
+
+// Compares two lines describing the same camera or user. Returns None if there are no differences.
+pub(crate) fn diff_lines<L: ReportLine>(old: &L, new: &L) -> Option<EntityDiff<L::Id>> {
+    // Maps session ids to their (rank, value). If a session appears more than once we keep its best rank.
+    let ranked_sessions = |line: &L| {
+        let mut mapping: HashMap<Uuid, RankAndValue> = HashMap::new();
+        for (idx, (session_id, value)) in line.ranked_values().into_iter().enumerate() {
+            mapping.entry(session_id).or_insert((idx + 1, value));
+        }
+        mapping
+    };
+    let (old_sessions, new_sessions) = (ranked_sessions(old), ranked_sessions(new));
+    let not_found_in = |sessions: &HashMap<Uuid, RankAndValue>,
+                        other_sessions: &HashMap<Uuid, RankAndValue>| {
+        let mut not_found: Vec<RankedSession> = sessions
+            .iter()
+            .filter(|(session_id, _)| !other_sessions.contains_key(session_id))
+            .map(|(session_id, (rank, value))| RankedSession {
+                session_id: *session_id,
+                rank: *rank,
+                value: *value,
+            })
+            .collect();
+        not_found.sort_by_key(|session| session.rank);
+        not_found
+    };
+    let sessions_entered = not_found_in(&new_sessions, &old_sessions);
+    let sessions_left = not_found_in(&old_sessions, &new_sessions);
+
+    let mut kept_sessions: Vec<(Uuid, RankAndValue, RankAndValue)> = new_sessions
+        .iter()
+        .filter_map(|(session_id, new)| {
+            old_sessions
+                .get(session_id)
+                .map(|old| (*session_id, *old, *new))
+        })
+        .collect();
+    kept_sessions.sort_by_key(|(_, _, (new_rank, _))| *new_rank);
+    let rank_changes: Vec<RankChange> = kept_sessions
+        .iter()
+        .filter(|(_, (old_rank, _), (new_rank, _))| old_rank != new_rank)
+        .map(|(session_id, (old_rank, _), (new_rank, _))| RankChange {
+            session_id: *session_id,
+            old_rank: *old_rank,
+            new_rank: *new_rank,
+        })
+        .collect();
+    let score_deltas: Vec<ScoreDelta> = kept_sessions
+        .iter()
+        .filter(|(_, (_, old_value), (_, new_value))| old_value != new_value)
+        .map(|(session_id, (_, old_value), (_, new_value))| ScoreDelta {
+            session_id: *session_id,
+            old_value: *old_value,
+            new_value: *new_value,
+            delta: new_value - old_value,
+        })
+        .collect();
+
+    if sessions_entered.is_empty()
+        && sessions_left.is_empty()
+        && rank_changes.is_empty()
+        && score_deltas.is_empty()
+    {
+        None
+    } else {
+        Some(EntityDiff::Changed {
+            id: old.id(),
+            sessions_entered,
+            sessions_left,
+            rank_changes,
+            score_deltas,
+        })
+    }
+}
+
+// Streams through two reports sorted by camera/user id and calls on_diff for every camera or user that differs.
+pub(crate) fn diff_reports<L, O, N, F>(old: O, new: N, mut on_diff: F) -> Result<DiffSummary>
+where
+    L: ReportLine,
+    O: Iterator<Item = Result<L, ReportError>>,
+    N: Iterator<Item = Result<L, ReportError>>,
+    F: FnMut(EntityDiff<L::Id>) -> Result<()>,
+{
+    let mut old = SortedLines::new(old, "old");
+    let mut new = SortedLines::new(new, "new");
+    let mut summary = DiffSummary::default();
+    let (mut old_line, mut new_line) = (old.next()?, new.next()?);
+    loop {
+        match (old_line.take(), new_line.take()) {
+            (None, None) => break,
+            (Some(old_value), None) => {
+                summary.entities_removed += 1;
+                on_diff(EntityDiff::Removed { id: old_value.id() })?;
+                old_line = old.next()?;
+            }
+            (None, Some(new_value)) => {
+                summary.entities_added += 1;
+                on_diff(EntityDiff::Added { id: new_value.id() })?;
+                new_line = new.next()?;
+            }
+            (Some(old_value), Some(new_value)) => match old_value.id().cmp(&new_value.id()) {
+                std::cmp::Ordering::Less => {
+                    summary.entities_removed += 1;
+                    on_diff(EntityDiff::Removed { id: old_value.id() })?;
+                    old_line = old.next()?;
+                    new_line = Some(new_value);
+                }
+                std::cmp::Ordering::Greater => {
+                    summary.entities_added += 1;
+                    on_diff(EntityDiff::Added { id: new_value.id() })?;
+                    old_line = Some(old_value);
+                    new_line = new.next()?;
+                }
+                std::cmp::Ordering::Equal => {
+                    match diff_lines(&old_value, &new_value) {
+                        Some(entity_diff) => {
+                            summary.entities_changed += 1;
+                            on_diff(entity_diff)?;
+                        }
+                        None => summary.entities_unchanged += 1,
+                    }
+                    old_line = old.next()?;
+                    new_line = new.next()?;
+                }
+            },
+        }
+    }
+    Ok(summary)
+}
+
+// Wraps the lines of a report and makes sure they are sorted by id, which diff_reports relies on.
+struct SortedLines<L: ReportLine, I: Iterator<Item = Result<L, ReportError>>> {
+    lines: I,
+    name: &'static str,
+    previous_id: Option<L::Id>,
+}
+
+impl<L: ReportLine, I: Iterator<Item = Result<L, ReportError>>> SortedLines<L, I> {
+    fn new(lines: I, name: &'static str) -> Self {
+        Self {
+            lines,
+            name,
+            previous_id: None,
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<L>> {
+        let line = match self.lines.next() {
+            Some(line) => {
+                line.with_context(|| format!("Failed to read the {} report", self.name))?
+            }
+            None => return Ok(None),
+        };
+        if let Some(previous_id) = self.previous_id {
+            if line.id() <= previous_id {
+                bail!(
+                    "The {} report is not sorted by id: {} appears after {}",
+                    self.name,
+                    line.id(),
+                    previous_id
+                );
+            }
+        }
+        self.previous_id = Some(line.id());
+        Ok(Some(line))
+    }
+}
+
+fn write_text<W: Write, Id: std::fmt::Display>(
+    writer: &mut W,
+    kind: ReportKind,
+    entity_diff: &EntityDiff<Id>,
+) -> Result<()> {
+    match entity_diff {
+        EntityDiff::Added { id } => writeln!(writer, "+ {} {}", kind.label(), id)?,
+        EntityDiff::Removed { id } => writeln!(writer, "- {} {}", kind.label(), id)?,
+        EntityDiff::Changed {
+            id,
+            sessions_entered,
+            sessions_left,
+            rank_changes,
+            score_deltas,
+        } => {
+            writeln!(writer, "~ {} {}", kind.label(), id)?;
+            for session in sessions_entered {
+                writeln!(
+                    writer,
+                    "    + session {} entered at rank {} with {}",
+                    session.session_id, session.rank, session.value
+                )?;
+            }
+            for session in sessions_left {
+                writeln!(
+                    writer,
+                    "    - session {} left from rank {} with {}",
+                    session.session_id, session.rank, session.value
+                )?;
+            }
+            for rank_change in rank_changes {
+                writeln!(
+                    writer,
+                    "    ~ session {} moved from rank {} to rank {}",
+                    rank_change.session_id, rank_change.old_rank, rank_change.new_rank
+                )?;
+            }
+            for score_delta in score_deltas {
+                writeln!(
+                    writer,
+                    "    ~ session {} changed from {} to {} ({:+})",
+                    score_delta.session_id,
+                    score_delta.old_value,
+                    score_delta.new_value,
+                    score_delta.delta
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Writes the differences as a single JSON document of the form
+// {"old":..,"new":..,"entities":[<EntityDiff>, ...],"summary":<DiffSummary>,"has_differences":..}
+// The entities are written one at a time so we never have to hold all the differences in memory.
+struct JsonDiffWriter<W: Write> {
+    writer: W,
+    entities_written: usize,
+}
+
+impl<W: Write> JsonDiffWriter<W> {
+    fn new(mut writer: W, old_path: &Path, new_path: &Path) -> Result<Self> {
+        write!(
+            writer,
+            "{{\"old\":{},\"new\":{},\"entities\":[",
+            serde_json::to_string(&old_path.to_string_lossy())?,
+            serde_json::to_string(&new_path.to_string_lossy())?
+        )?;
+        Ok(Self {
+            writer,
+            entities_written: 0,
+        })
+    }
+
+    fn write<Id: Serialize>(&mut self, entity_diff: &EntityDiff<Id>) -> Result<()> {
+        if self.entities_written > 0 {
+            write!(self.writer, ",")?;
+        }
+        serde_json::to_writer(&mut self.writer, entity_diff)?;
+        self.entities_written += 1;
+        Ok(())
+    }
+
+    fn finish(mut self, summary: &DiffSummary) -> Result<()> {
+        writeln!(
+            self.writer,
+            "],\"summary\":{},\"has_differences\":{}}}",
+            serde_json::to_string(summary)?,
+            summary.has_differences()
+        )?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safari_stats_reports::{CameraSession, UserSession};
+
+    fn user_line(user_id: u128, sessions: &[(u128, u8)]) -> UserReportLine {
+        UserReportLine {
+            user_id: Uuid::from_u128(user_id),
+            sessions: sessions
+                .iter()
+                .map(|(session_id, nb_pics)| UserSession {
+                    session_id: Uuid::from_u128(*session_id),
+                    nb_pics: *nb_pics,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn diff_lines_detects_all_kinds_of_changes() {
+        let old = user_line(1, &[(10, 5), (11, 4), (12, 3)]);
+        let new = user_line(1, &[(11, 6), (10, 5), (13, 1)]);
+        assert_eq!(
+            Some(EntityDiff::Changed {
+                id: Uuid::from_u128(1),
+                sessions_entered: vec![RankedSession {
+                    session_id: Uuid::from_u128(13),
+                    rank: 3,
+                    value: 1.0
+                }],
+                sessions_left: vec![RankedSession {
+                    session_id: Uuid::from_u128(12),
+                    rank: 3,
+                    value: 3.0
+                }],
+                rank_changes: vec![
+                    RankChange {
+                        session_id: Uuid::from_u128(11),
+                        old_rank: 2,
+                        new_rank: 1
+                    },
+                    RankChange {
+                        session_id: Uuid::from_u128(10),
+                        old_rank: 1,
+                        new_rank: 2
+                    }
+                ],
+                score_deltas: vec![ScoreDelta {
+                    session_id: Uuid::from_u128(11),
+                    old_value: 4.0,
+                    new_value: 6.0,
+                    delta: 2.0
+                }],
+            }),
+            diff_lines(&old, &new)
+        );
+        assert_eq!(None, diff_lines(&old, &old.clone()));
+    }
+
+    #[test]
+    fn padding_of_camera_reports_is_ignored() {
+        let padded_line = |sessions: &[(u128, f32)]| {
+            let mut line = CameraReportLine {
+                camera_id: 1,
+                sessions: sessions
+                    .iter()
+                    .map(|(session_id, avg_pics)| CameraSession {
+                        session_id: Uuid::from_u128(*session_id),
+                        avg_pics: *avg_pics,
+                        trips: Some(2),
+                    })
+                    .collect(),
+            };
+            line.sessions.resize(100, CameraSession::padding());
+            CameraReportLine::from_str(&line.to_string()).unwrap()
+        };
+        let old = padded_line(&[(10, 5.0), (11, 3.0)]);
+        let new = padded_line(&[(10, 5.0), (11, 3.0), (12, 1.0)]);
+        assert_eq!(
+            Some(EntityDiff::Changed {
+                id: 1,
+                sessions_entered: vec![RankedSession {
+                    session_id: Uuid::from_u128(12),
+                    rank: 3,
+                    value: 1.0
+                }],
+                sessions_left: vec![],
+                rank_changes: vec![],
+                score_deltas: vec![],
+            }),
+            diff_lines(&old, &new)
+        );
+    }
+
+    #[test]
+    fn diff_reports_streams_added_removed_and_changed_entities() {
+        let old = vec![
+            user_line(1, &[(10, 1)]),
+            user_line(2, &[(10, 1)]),
+            user_line(4, &[(10, 1)]),
+        ];
+        let new = vec![
+            user_line(2, &[(10, 1)]),
+            user_line(3, &[(10, 1)]),
+            user_line(4, &[(10, 2)]),
+        ];
+        let mut diffs = Vec::new();
+        let summary = diff_reports(old.into_iter().map(Ok), new.into_iter().map(Ok), |diff| {
+            diffs.push(diff);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            DiffSummary {
+                entities_added: 1,
+                entities_removed: 1,
+                entities_changed: 1,
+                entities_unchanged: 1
+            },
+            summary
+        );
+        assert_eq!(
+            EntityDiff::Removed {
+                id: Uuid::from_u128(1)
+            },
+            diffs[0]
+        );
+        assert_eq!(
+            EntityDiff::Added {
+                id: Uuid::from_u128(3)
+            },
+            diffs[1]
+        );
+        assert!(matches!(diffs[2], EntityDiff::Changed { .. }));
+    }
+
+    #[test]
+    fn diff_reports_rejects_unsorted_reports() {
+        let old = vec![user_line(2, &[]), user_line(1, &[])];
+        let result = diff_reports(
+            old.into_iter().map(Ok),
+            std::iter::empty::<Result<UserReportLine, ReportError>>(),
+            |_| Ok(()),
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,2 @@
+pub mod diffing;
+pub mod pruning;
@@ -0,0 +1,106 @@
+use structopt::StructOpt;
+
+use stats_admin::diffing::ReportKind;
+
+/// Administrative tasks related to the reports and state produced by camera-stats and user-stats.
+#[derive(StructOpt)]
+enum Cli {
+    /// Compares two camera reports or two user reports.
+    ///
+    /// The differences are printed as human-readable text. The exit code is 0 if the reports are equal,
+    /// 1 if differences were found and 2 if an error occurred.
+    Diff {
+        /// The old report: either a path or a date of the form YYYYMMDD (requires --report-dir and --kind)
+        old: String,
+
+        /// The new report: either a path or a date of the form YYYYMMDD (requires --report-dir and --kind)
+        new: String,
+
+        /// The directory containing the reports when comparing by date
+        #[structopt(long, parse(from_os_str))]
+        report_dir: Option<std::path::PathBuf>,
+
+        /// The kind of reports to compare. Inferred from the filenames if not given.
+        #[structopt(long, possible_values = &["camera", "user"])]
+        kind: Option<ReportKind>,
+
+        /// Additionally write the differences as JSON to this file
+        #[structopt(long, parse(from_os_str))]
+        json: Option<std::path::PathBuf>,
+    },
+    /// Removes the daily state of camera-stats and user-stats that is no longer needed.
+    ///
+    /// This covers the serialized camera stats, the saved FST files and the quarantined records dated more than --keep-days
+    /// days back, as well as temporary directories of user-stats left behind by crashed runs. The run manifests in the given
+    /// output directories are removed once they are as old. Every removed path is printed.
+    Gc {
+        /// The number of days (today included) whose state is kept
+        #[structopt(long, default_value = "7")]
+        keep_days: u32,
+
+        /// Only print what would be removed
+        #[structopt(long)]
+        dry_run: bool,
+
+        /// The directory camera-stats and user-stats are run from, i.e. the one containing their state directories
+        #[structopt(long, parse(from_os_str), default_value = ".")]
+        working_dir: std::path::PathBuf,
+
+        /// A directory camera-stats or user-stats write their reports and run manifests to. Can be repeated
+        #[structopt(long = "output-dir", parse(from_os_str), number_of_values = 1)]
+        output_dirs: Vec<std::path::PathBuf>,
+
+        /// Wait for runs of camera-stats and user-stats using the state directories to finish instead of failing immediately
+        #[structopt(long, conflicts_with = "no-wait")]
+        wait: bool,
+
+        /// Fail immediately if camera-stats is using its state directory. This is the default
+        #[structopt(long)]
+        no_wait: bool,
+    },
+}
+
+fn main() {
+    let args = Cli::from_args();
+    common_utils::logging::init(
+        common_utils::logging::LevelFilter::INFO,
+        common_utils::logging::LogFormat::Text,
+    );
+    let exit_code = match args {
+        Cli::Diff {
+            old,
+            new,
+            report_dir,
+            kind,
+            json,
+        } => match stats_admin::diffing::run(old, new, report_dir, kind, json) {
+            Ok(false) => 0,
+            Ok(true) => 1,
+            Err(error) => {
+                eprintln!("Error: {:?}", error);
+                2
+            }
+        },
+        Cli::Gc {
+            keep_days,
+            dry_run,
+            working_dir,
+            output_dirs,
+            wait,
+            no_wait,
+        } => match stats_admin::pruning::run(
+            working_dir,
+            output_dirs,
+            keep_days,
+            dry_run,
+            wait && !no_wait,
+        ) {
+            Ok(()) => 0,
+            Err(error) => {
+                eprintln!("Error: {:?}", error);
+                2
+            }
+        },
+    };
+    std::process::exit(exit_code);
+}
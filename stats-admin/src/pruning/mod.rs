@@ -0,0 +1,319 @@
+// This module contains functionality for removing the daily state of camera-stats and user-stats that is no longer needed.
+//
+// Both programs only ever read the state of the last seven days, but never remove anything. Every file they store in their
+// state directories (and every quarantined log) ends with the date of the session log file it was computed from, hence old
+// files are recognised by that date alone. This includes the source logs saved for the run manifests (see common_utils::manifest).
+// Files without such a date are left alone. The run manifests themselves are written next to the reports they describe, hence they are
+// only removed from the output directories we are given, and only the files named like a run manifest, never the reports.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+use camera_stats::configuration::{
+    RunManifestFilesConfig as CameraRunManifestFilesConfig, SerializationFilesConfig,
+};
+use common_utils::{
+    date_utils::NUMBER_OF_DAYS_IN_WINDOW, file_utils::QuarantineFilesConfig,
+    locking::StateDirectoryLock,
+};
+use user_stats::configuration::{
+    RunManifestFilesConfig as UserRunManifestFilesConfig, SavedFstSetFilesConfig,
+};
+
+// A temporary directory of user-stats that has not been modified for this long is considered to be left behind by a killed run,
+// even if user-stats is running at the moment.
+const ORPHANED_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Removes the daily state of camera-stats and user-stats that is older than keep_days days (today included) from the given
+/// working directory, i.e. the directory the programs are run from. This covers the serialized camera stats, the saved FST files,
+/// the source logs saved alongside them and the quarantined records. Temporary directories of user-stats are removed as well, unless user-stats is running, in which case
+/// only those that have not been modified for a day are removed. The lock of user-stats is held while removing (see StateDirectoryLock).
+/// The lock of camera-stats is held as well, hence if camera-stats is running we either wait for it to finish or fail, depending on wait.
+/// With wait, we also wait for a running user-stats to finish.
+/// The run manifests in the given output directories that are older than keep_days days are removed too.
+///
+/// Every removed path is printed. If dry_run is set, the paths are only printed.
+pub fn run(
+    working_directory: PathBuf,
+    output_directories: Vec<PathBuf>,
+    keep_days: u32,
+    dry_run: bool,
+    wait: bool,
+) -> Result<()> {
+    if keep_days == 0 {
+        bail!("--keep-days must be at least 1, otherwise the state of today would be removed");
+    }
+    if (keep_days as usize) < NUMBER_OF_DAYS_IN_WINDOW {
+        println!(
+            "Warning: the reports are computed over the last {} days, hence the logs of the removed days will be processed again",
+            NUMBER_OF_DAYS_IN_WINDOW
+        );
+    }
+    let first_kept_date =
+        common_utils::date_utils::days_before_today_ymd(keep_days - 1).into_string();
+    // camera-stats reads and writes its serialized stats while holding the lock on their directory, hence they are only removed
+    // while we hold it.
+    let serialization_directory = state_directory(
+        &working_directory,
+        SerializationFilesConfig::SERIALIZATION_DIRECTORY_PATH,
+    );
+    let _camera_lock = if serialization_directory.is_dir() {
+        Some(
+            StateDirectoryLock::acquire(&serialization_directory, wait).with_context(|| {
+                "Could not lock the directory of the serialized camera stats".to_string()
+            })?,
+        )
+    } else {
+        None
+    };
+    // While no run of user-stats holds the lock on its storage directory, all of its temporary directories have been left behind.
+    let saved_fst_files_directory =
+        state_directory(&working_directory, SavedFstSetFilesConfig::DIRECTORY_PATH);
+    let lock = if saved_fst_files_directory.is_dir() {
+        match StateDirectoryLock::acquire(&saved_fst_files_directory, wait) {
+            Ok(lock) => Some(lock),
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                println!(
+                    "{}. Only temporary directories not modified for a day are removed",
+                    error
+                );
+                None
+            }
+            Err(error) => {
+                return Err(error).with_context(|| {
+                    "Could not lock the directory of the saved FST files".to_string()
+                })
+            }
+        }
+    } else {
+        None
+    };
+    let orphaned_before = if lock.is_some() {
+        SystemTime::now()
+    } else {
+        SystemTime::now() - ORPHANED_AFTER
+    };
+    let mut paths = expired_paths(&working_directory, &first_kept_date, orphaned_before)?;
+    for output_directory in output_directories.iter() {
+        paths.extend(expired_manifests(output_directory, &first_kept_date)?);
+    }
+    for path in paths.iter() {
+        if dry_run {
+            println!("would remove {:?}", path.as_os_str());
+            continue;
+        }
+        println!("removing {:?}", path.as_os_str());
+        if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }
+        .with_context(|| format!("Failed to remove {:?}", path.as_os_str()))?;
+    }
+    println!(
+        "{} {} files and directories",
+        if dry_run { "Would remove" } else { "Removed" },
+        paths.len()
+    );
+    Ok(())
+}
+
+// The files dated before first_kept_date (YYYYMMDD) in the state directories below the working directory, followed by the
+// temporary directories of user-stats that have not been modified since orphaned_before.
+fn expired_paths(
+    working_directory: &Path,
+    first_kept_date: &str,
+    orphaned_before: SystemTime,
+) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for state_directory in [
+        SerializationFilesConfig::SERIALIZATION_DIRECTORY_PATH,
+        SavedFstSetFilesConfig::DIRECTORY_PATH,
+        QuarantineFilesConfig::DIRECTORY_PATH,
+    ] {
+        let state_directory = self::state_directory(working_directory, state_directory);
+        if !state_directory.is_dir() {
+            continue;
+        }
+        for path in sorted_entries(&state_directory)? {
+            let date = path
+                .file_name()
+                .and_then(|filename| filename.to_str())
+                .and_then(date_of_filename);
+            if path.is_file() && date.is_some_and(|date| date.as_str() < first_kept_date) {
+                paths.push(path);
+            }
+        }
+    }
+    for path in sorted_entries(working_directory)? {
+        let is_temporary_directory = path.is_dir()
+            && path
+                .file_name()
+                .and_then(|filename| filename.to_str())
+                .is_some_and(|filename| {
+                    filename.starts_with(SavedFstSetFilesConfig::TEMPORARY_DIRECTORY_PREFIX)
+                });
+        if !is_temporary_directory {
+            continue;
+        }
+        let modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to read the metadata of {:?}", path.as_os_str()))?;
+        if modified <= orphaned_before {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+// The run manifests of camera-stats and user-stats in the given output directory dated before first_kept_date (YYYYMMDD).
+fn expired_manifests(output_directory: &Path, first_kept_date: &str) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for path in sorted_entries(output_directory)? {
+        let filename = match path.file_name().and_then(|filename| filename.to_str()) {
+            Some(filename) => filename,
+            None => continue,
+        };
+        let is_manifest = [
+            CameraRunManifestFilesConfig::FILE_PREFIX,
+            UserRunManifestFilesConfig::FILE_PREFIX,
+        ]
+        .iter()
+        .any(|prefix| filename.starts_with(prefix));
+        let is_expired =
+            date_of_filename(filename).is_some_and(|date| date.as_str() < first_kept_date);
+        if path.is_file() && is_manifest && is_expired {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+// The path of the given state directory (relative to the directory the programs are run from) below the working directory.
+fn state_directory(working_directory: &Path, state_directory: &str) -> PathBuf {
+    let state_directory = Path::new(state_directory);
+    working_directory.join(state_directory.strip_prefix(".").unwrap_or(state_directory))
+}
+
+// The entries of the given directory in the order of their names.
+fn sorted_entries(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(directory)
+        .with_context(|| format!("Failed to read the directory {:?}", directory.as_os_str()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read the directory {:?}", directory.as_os_str()))?;
+    paths.sort();
+    Ok(paths)
+}
+
+// The date (YYYYMMDD) a file of the daily state ends with, ignoring its extensions (e.g. .fst.tmp for a file left behind by a killed run).
+fn date_of_filename(filename: &str) -> Option<String> {
+    // Compiled once, as this is called for every file of the state directories.
+    static DATE_REGEX: OnceLock<Regex> = OnceLock::new();
+    DATE_REGEX
+        .get_or_init(|| Regex::new(r"(\d{8})(\.[[:alnum:]]+)*$").unwrap())
+        .captures(filename)
+        .map(|captures| captures[1].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_old_state_and_orphaned_temporary_directories_are_expired() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved_fst_files = dir.path().join(SavedFstSetFilesConfig::DIRECTORY_PATH);
+        let serialized_camera_stats = dir
+            .path()
+            .join(SerializationFilesConfig::SERIALIZATION_DIRECTORY_PATH);
+        fs::create_dir_all(&saved_fst_files).unwrap();
+        fs::create_dir_all(&serialized_camera_stats).unwrap();
+        for path in [
+            saved_fst_files.join("user-summary-20210613.fst"),
+            saved_fst_files.join("source-log-20210613.json"),
+            saved_fst_files.join("user-top-10-pics-v2-20210612.fst.tmp"),
+            saved_fst_files.join("user-summary-20210614.fst"),
+            saved_fst_files.join("notes.txt"),
+            serialized_camera_stats.join("camera-summary-20210601"),
+            serialized_camera_stats.join("session-top-v2-100-20210620"),
+        ] {
+            fs::write(path, b"").unwrap();
+        }
+        let temporary_directory = dir.path().join(format!(
+            "{}{}",
+            SavedFstSetFilesConfig::TEMPORARY_DIRECTORY_PREFIX,
+            uuid::Uuid::new_v4()
+        ));
+        fs::create_dir(&temporary_directory).unwrap();
+
+        let yesterday = SystemTime::now() - ORPHANED_AFTER;
+        assert_eq!(
+            vec![
+                serialized_camera_stats.join("camera-summary-20210601"),
+                saved_fst_files.join("source-log-20210613.json"),
+                saved_fst_files.join("user-summary-20210613.fst"),
+                saved_fst_files.join("user-top-10-pics-v2-20210612.fst.tmp"),
+            ],
+            expired_paths(dir.path(), "20210614", yesterday).unwrap()
+        );
+        assert_eq!(
+            Some(&temporary_directory),
+            expired_paths(dir.path(), "20210614", SystemTime::now())
+                .unwrap()
+                .last()
+        );
+    }
+
+    #[test]
+    fn nothing_is_removed_while_camera_stats_holds_its_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let serialized_camera_stats = dir
+            .path()
+            .join(SerializationFilesConfig::SERIALIZATION_DIRECTORY_PATH);
+        fs::create_dir_all(&serialized_camera_stats).unwrap();
+        let expired_file = serialized_camera_stats.join("camera-summary-20210601");
+        fs::write(&expired_file, b"").unwrap();
+
+        let camera_lock = StateDirectoryLock::acquire(&serialized_camera_stats, false).unwrap();
+        let error = run(dir.path().to_path_buf(), Vec::new(), 7, false, false).unwrap_err();
+        assert_eq!(
+            Some(std::io::ErrorKind::WouldBlock),
+            error
+                .downcast_ref::<std::io::Error>()
+                .map(|error| error.kind())
+        );
+        assert!(expired_file.exists());
+
+        drop(camera_lock);
+        run(dir.path().to_path_buf(), Vec::new(), 7, false, false).unwrap();
+        assert!(!expired_file.exists());
+    }
+
+    #[test]
+    fn only_old_run_manifests_are_expired_from_the_output_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        for filename in [
+            "camera_stats_manifest_20210613.json",
+            "user_stats_manifest_20210613.json",
+            "user_stats_manifest_20210614.json",
+            "camera_top100_20210613.txt",
+            "user_summary_20210613.parquet",
+        ] {
+            fs::write(dir.path().join(filename), b"").unwrap();
+        }
+        assert_eq!(
+            vec![
+                dir.path().join("camera_stats_manifest_20210613.json"),
+                dir.path().join("user_stats_manifest_20210613.json"),
+            ],
+            expired_manifests(dir.path(), "20210614").unwrap()
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use std::{path::PathBuf, str::FromStr};
 
-use common_utils::date_utils::DateStamp;
+use common_utils::{date_utils::DateStamp, report_utils::ReportFormat};
 
 /// Configuration for storage of FST sets describing the top 10 number of pics in sessions a user had on a given date.
 pub struct SavedFstSetFilesConfig;
@@ -8,14 +8,26 @@ pub struct SavedFstSetFilesConfig;
 impl SavedFstSetFilesConfig {
     /// The path to the directory where we serialize daily camera and user stats.
     /// The files in this directory are not supposed to be viewed by anyone or anything apart from this program.
-    /// The exception is files dating more than seven days back. One could/should set up a cronjob that deletes those.
+    /// The exception is files dating more than seven days back, which can be removed with `stats-admin gc`.
     pub const DIRECTORY_PATH: &'static str = "./saved_fst_files";
 
-    /// The prefix for the serialized camera stats. Their suffix will be a date of the form YYYYMMDD.
-    pub const FILE_PREFIX: &'static str = "user-top-10-pics-";
+    /// The prefix for the saved FST sets containing the top 10 sessions of the users. Their suffix will be a date of the form YYYYMMDD.
+    /// The "v2" marks sets that include the last user of the day, the sets saved before were missing it.
+    pub const FILE_PREFIX: &'static str = "user-top-10-pics-v2-";
 
     pub const FILE_EXTENSION: &'static str = ".fst";
 
+    /// The prefix for the saved FST maps containing the daily summaries of the users. Their suffix will be a date of the form YYYYMMDD.
+    pub const SUMMARY_FILE_PREFIX: &'static str = "user-summary-";
+
+    /// The prefix for the saved FST maps containing the partial number of pics of the sessions that may cross midnight.
+    /// Their suffix will be a date of the form YYYYMMDD.
+    pub const BOUNDARY_FILE_PREFIX: &'static str = "user-boundary-sessions-";
+
+    /// The prefix of the temporary directories (in the working directory) the batches of a session log file are written to
+    /// while it is processed. Their suffix is a random UUID. They are removed once the log has been processed.
+    pub const TEMPORARY_DIRECTORY_PREFIX: &'static str = "temporary_fsts_";
+
     pub fn storage_directory() -> PathBuf {
         PathBuf::from_str(Self::DIRECTORY_PATH).unwrap()
     }
@@ -37,7 +49,61 @@ impl SavedFstSetFilesConfig {
             .map(Self::file_path_from_date)
             .collect()
     }
+
+    // Returns the path to the saved user summaries corresponding to the given date: (YYYYMMDD)
+    pub(crate) fn summary_file_path_from_date(datestamp: DateStamp) -> PathBuf {
+        let mut path = SavedFstSetFilesConfig::storage_directory();
+        path.push(format!(
+            "{}{}{}",
+            SavedFstSetFilesConfig::SUMMARY_FILE_PREFIX,
+            datestamp.into_string(),
+            SavedFstSetFilesConfig::FILE_EXTENSION
+        ));
+        path
+    }
+
+    pub(crate) fn summary_file_paths_last_seven_days() -> Vec<PathBuf> {
+        common_utils::date_utils::last_seven_days_ymd()
+            .into_iter()
+            .map(Self::summary_file_path_from_date)
+            .collect()
+    }
+
+    // Returns the path to the saved boundary sessions corresponding to the given date: (YYYYMMDD)
+    pub(crate) fn boundary_file_path_from_date(datestamp: DateStamp) -> PathBuf {
+        let mut path = SavedFstSetFilesConfig::storage_directory();
+        path.push(format!(
+            "{}{}{}",
+            SavedFstSetFilesConfig::BOUNDARY_FILE_PREFIX,
+            datestamp.into_string(),
+            SavedFstSetFilesConfig::FILE_EXTENSION
+        ));
+        path
+    }
+
+    pub(crate) fn boundary_file_paths_last_seven_days() -> Vec<PathBuf> {
+        common_utils::date_utils::last_seven_days_ymd()
+            .into_iter()
+            .map(Self::boundary_file_path_from_date)
+            .collect()
+    }
+
+    // Whether the session log file of the given date has been processed, i.e. its top 10 sessions, its summaries
+    // and its boundary sessions have been saved.
+    pub(crate) fn is_processed(datestamp: DateStamp) -> bool {
+        Self::file_path_from_date(datestamp.clone()).exists()
+            && Self::summary_file_path_from_date(datestamp.clone()).exists()
+            && Self::boundary_file_path_from_date(datestamp).exists()
+    }
+}
+/// Configuration describing filenames of the run manifests, which describe the inputs and outputs of a run (see common_utils::manifest).
+pub struct RunManifestFilesConfig;
+
+impl RunManifestFilesConfig {
+    pub const FILE_PREFIX: &'static str = "user_stats_manifest_";
+    pub const FILE_EXTENSION: &'static str = ".json";
 }
+
 /// Configuration describing filenames of hunamly readable files containing the top 10 number of pics in sessions by each user
 /// over the last seven days.
 pub struct DailyUsersStatsConfig;
@@ -45,15 +111,25 @@ pub struct DailyUsersStatsConfig;
 impl DailyUsersStatsConfig {
     pub const FILE_PREFIX: &'static str = "user_top_10_";
     pub const FILE_EXTENSION: &'static str = ".txt";
+    pub const JSONL_FILE_EXTENSION: &'static str = ".jsonl";
+    pub const PARQUET_FILE_EXTENSION: &'static str = ".parquet";
 }
 
-pub(crate) fn todays_users_stats_file_path(out_directory: PathBuf) -> PathBuf {
+pub(crate) fn todays_users_stats_file_path(
+    out_directory: PathBuf,
+    report_format: ReportFormat,
+) -> PathBuf {
     let today_ymd = common_utils::date_utils::today_ymd().into_string();
     let mut todays_camera_stats_path = out_directory;
+    let file_extension = match report_format {
+        ReportFormat::Pipe => DailyUsersStatsConfig::FILE_EXTENSION,
+        ReportFormat::JsonLines => DailyUsersStatsConfig::JSONL_FILE_EXTENSION,
+        ReportFormat::Parquet => DailyUsersStatsConfig::PARQUET_FILE_EXTENSION,
+    };
     let filename: String = [
         DailyUsersStatsConfig::FILE_PREFIX,
         today_ymd.as_str(),
-        DailyUsersStatsConfig::FILE_EXTENSION,
+        file_extension,
     ]
     .iter()
     .flat_map(|s| s.chars())
@@ -61,3 +137,78 @@ pub(crate) fn todays_users_stats_file_path(out_directory: PathBuf) -> PathBuf {
     todays_camera_stats_path.push(filename);
     todays_camera_stats_path
 }
+
+/// Configuration describing filenames of the files containing the summary metrics of each user over the last seven days.
+pub struct DailyUsersSummaryConfig;
+
+impl DailyUsersSummaryConfig {
+    pub const FILE_PREFIX: &'static str = "user_summary_";
+    pub const FILE_EXTENSION: &'static str = ".txt";
+    pub const JSONL_FILE_EXTENSION: &'static str = ".jsonl";
+    pub const PARQUET_FILE_EXTENSION: &'static str = ".parquet";
+}
+
+// The path for todays user summary file. The file extension depends on the format the file is written in.
+pub(crate) fn todays_users_summary_file_path(
+    out_directory: PathBuf,
+    report_format: ReportFormat,
+) -> PathBuf {
+    let today_ymd = common_utils::date_utils::today_ymd().into_string();
+    let file_extension = match report_format {
+        ReportFormat::Pipe => DailyUsersSummaryConfig::FILE_EXTENSION,
+        ReportFormat::JsonLines => DailyUsersSummaryConfig::JSONL_FILE_EXTENSION,
+        ReportFormat::Parquet => DailyUsersSummaryConfig::PARQUET_FILE_EXTENSION,
+    };
+    let mut path = out_directory;
+    path.push(format!(
+        "{}{}{}",
+        DailyUsersSummaryConfig::FILE_PREFIX,
+        today_ymd,
+        file_extension
+    ));
+    path
+}
+
+/// Configuration describing filenames of the files containing the global leaderboard of the users with the most pics
+/// over the last seven days.
+pub struct DailyUsersLeaderboardConfig;
+
+impl DailyUsersLeaderboardConfig {
+    pub const FILE_PREFIX: &'static str = "user_leaderboard_";
+    pub const FILE_EXTENSION: &'static str = ".txt";
+    pub const JSONL_FILE_EXTENSION: &'static str = ".jsonl";
+    pub const PARQUET_FILE_EXTENSION: &'static str = ".parquet";
+}
+
+// The path for todays user leaderboard file. The file extension depends on the format the file is written in.
+pub(crate) fn todays_users_leaderboard_file_path(
+    out_directory: PathBuf,
+    report_format: ReportFormat,
+) -> PathBuf {
+    let today_ymd = common_utils::date_utils::today_ymd().into_string();
+    let file_extension = match report_format {
+        ReportFormat::Pipe => DailyUsersLeaderboardConfig::FILE_EXTENSION,
+        ReportFormat::JsonLines => DailyUsersLeaderboardConfig::JSONL_FILE_EXTENSION,
+        ReportFormat::Parquet => DailyUsersLeaderboardConfig::PARQUET_FILE_EXTENSION,
+    };
+    let mut path = out_directory;
+    path.push(format!(
+        "{}{}{}",
+        DailyUsersLeaderboardConfig::FILE_PREFIX,
+        today_ymd,
+        file_extension
+    ));
+    path
+}
+
+// The path for todays run manifest.
+pub(crate) fn todays_manifest_file_path(out_directory: PathBuf) -> PathBuf {
+    let mut path = out_directory;
+    path.push(format!(
+        "{}{}{}",
+        RunManifestFilesConfig::FILE_PREFIX,
+        common_utils::date_utils::today_ymd().into_string(),
+        RunManifestFilesConfig::FILE_EXTENSION
+    ));
+    path
+}
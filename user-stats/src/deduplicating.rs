@@ -0,0 +1,78 @@
+// This module contains the policy deciding how the pics of a session are combined when the same (user, session) pair is found
+// more than once over the last seven days. This happens when a session crossed midnight, in which case each day holds a part of the
+// session, or when a log was delivered again, in which case the same pics are found twice.
+use std::{collections::HashSet, fmt::Display, str::FromStr};
+
+use uuid::Uuid;
+
+// The (user_id, session_id) pairs whose number of pics was clamped to u8::MAX, either when the pics of a day were summed up
+// or when the occurrences of a session were combined.
+pub(crate) type ClampedSessions = HashSet<(Uuid, Uuid)>;
+
+/// How the pics of a session found more than once for the same user are combined, so that each session appears at most once per user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateSessionPolicy {
+    /// The pics are summed up, i.e. the duplicates are treated as parts of the same session.
+    #[default]
+    Sum,
+    /// The largest number of pics is kept, i.e. the duplicates are treated as copies of the same session.
+    Max,
+}
+
+impl FromStr for DuplicateSessionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(DuplicateSessionPolicy::Sum),
+            "max" => Ok(DuplicateSessionPolicy::Max),
+            other => Err(format!(
+                "unknown duplicate session policy: {}. Expected one of: sum, max",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for DuplicateSessionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DuplicateSessionPolicy::Sum => "sum",
+            DuplicateSessionPolicy::Max => "max",
+        })
+    }
+}
+
+impl DuplicateSessionPolicy {
+    // Combines the pics of two occurrences of the same session and returns whether the result was clamped.
+    // Sums saturate at u8::MAX, as the number of pics in a session is stored as a u8.
+    pub(crate) fn combine(self, nb_pics: u8, other_nb_pics: u8) -> (u8, bool) {
+        match self {
+            DuplicateSessionPolicy::Sum => match nb_pics.checked_add(other_nb_pics) {
+                Some(sum) => (sum, false),
+                None => (u8::MAX, true),
+            },
+            DuplicateSessionPolicy::Max => (nb_pics.max(other_nb_pics), false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_combine_duplicates() {
+        assert_eq!(Ok(DuplicateSessionPolicy::Max), "max".parse());
+        assert_eq!((9, false), DuplicateSessionPolicy::Sum.combine(4, 5));
+        assert_eq!(
+            (u8::MAX, false),
+            DuplicateSessionPolicy::Sum.combine(200, 55)
+        );
+        assert_eq!(
+            (u8::MAX, true),
+            DuplicateSessionPolicy::Sum.combine(200, 100)
+        );
+        assert_eq!((5, false), DuplicateSessionPolicy::Max.combine(4, 5));
+    }
+}
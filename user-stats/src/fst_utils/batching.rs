@@ -1,25 +1,38 @@
 use fst::MapBuilder;
 use itertools::Itertools;
 use std::{
+    collections::HashSet,
     fs::File,
     io::BufWriter,
     path::{Path, PathBuf},
 };
 
+use crate::deduplicating::ClampedSessions;
 use crate::parsing::{UserSessionRecord, UserRecord};
 use anyhow::{Context, Result};
-use common_utils::parsing_utils::Record;
+use common_utils::{
+    manifest::SourceDigest,
+    parsing_utils::{BoundarySessionsTracker, Record, RecordValidator},
+};
+use uuid::Uuid;
 
 // parses a session log file. Extracts user records from the parsed file (user_id, session_id, sum num_pics) and places this into a vector.
 // When the vector reaches the provided capacity limit. The vector is sorted and records with the same user and session ids are merged to a single record (where num_pics is the sum of the pics).
 // If (number of elements in batch vector after sorting and collecting)/capcity_limit > max_capacity_ratio_after_sort_collect then we write the contents of the batch vector to disk and clear the vector.
 // Note that a records with the same (user_id and session_id) pairs can end up in different files.
+// Records with a nil user or session id are handled by the given record validator before they reach the batches.
+// Returns the sessions among the first or the last boundary_window records of the log, which may cross midnight (see BoundarySessionsTracker),
+// together with the (user, session) pairs whose sum of pics within a batch was clamped to u8::MAX.
+// The bytes of the log are added to the given digest while it is parsed.
 pub(crate) fn from_log_file_to_batched_fst_maps<P: AsRef<Path>>(
     log_file_path: P,
     temporary_fst_dir_path: PathBuf,
     capacity_limit: usize,
     max_capacity_ratio_after_sort_collect: f64,
-) -> Result<()> {
+    boundary_window: usize,
+    record_validator: &mut RecordValidator,
+    source_digest: &SourceDigest,
+) -> Result<(HashSet<Uuid>, ClampedSessions)> {
     // recreate the temporary fst dir path if it exists.
     if temporary_fst_dir_path.exists() {
         std::fs::remove_dir_all(&temporary_fst_dir_path).with_context(|| {
@@ -36,19 +49,32 @@ pub(crate) fn from_log_file_to_batched_fst_maps<P: AsRef<Path>>(
         )
     })?;
     const BUFFER_CAPACITY: usize = 8 * 2usize.pow(10);
-    let reader = common_utils::parsing_utils::customised_csv_reader(log_file_path, BUFFER_CAPACITY)
-        .with_context(|| "Failer to create a csv reader for session log file parsing")?;
+    let reader = common_utils::parsing_utils::customised_csv_reader_with_digest(
+        log_file_path,
+        BUFFER_CAPACITY,
+        source_digest,
+    )
+    .with_context(|| "Failer to create a csv reader for session log file parsing")?;
+    let mut boundary_sessions_tracker = BoundarySessionsTracker::new(boundary_window);
     // create an iterator with items (UserMatchRecord, num_pics)
     let records_iter = reader
         .into_deserialize::<Record>()
-        .filter_map(Result::ok)
+        .filter_map(|parsed| record_validator.validate_parsed(parsed))
         .map_into::<UserRecord>()
+        .inspect(|record| boundary_sessions_tracker.observe(record.session_id))
         .map(|record| record.split());
     let mut batch_vector: Vec<(UserSessionRecord, u8)> = Vec::with_capacity(capacity_limit);
     let mut batch_counter = 0;
+    let mut clamped_sessions = ClampedSessions::new();
+    let mut records = 0usize;
     for pair in records_iter {
+        common_utils::cancellation::check()?;
+        records += 1;
         if batch_vector.len() >= capacity_limit {
-            crate::sorting::sort_collect_splitted_user_records(&mut batch_vector);
+            crate::sorting::sort_collect_splitted_user_records(
+                &mut batch_vector,
+                &mut clamped_sessions,
+            );
             // If there were multiple entries with equal UserSessionRecord then the vector's length
             // will have decreased. If the decrease was not sufficient we save our progress to a temporary fst map and clear the vector.
             if batch_vector.len()
@@ -58,6 +84,11 @@ pub(crate) fn from_log_file_to_batched_fst_maps<P: AsRef<Path>>(
                 let path = temporary_fst_dir_path
                     .clone()
                     .join(format!("{}.fst", batch_counter));
+                tracing::debug!(
+                    batch = batch_counter,
+                    pairs = batch_vector.len(),
+                    "writing a batch to disk"
+                );
                 write_batch_fst_map(path, &mut batch_vector).with_context(|| {
                     format!("Failed to write batch number {} to disk", batch_counter)
                 })?;
@@ -67,17 +98,23 @@ pub(crate) fn from_log_file_to_batched_fst_maps<P: AsRef<Path>>(
     }
     if !batch_vector.is_empty() {
         // if there is anything left in the batch vector, then we write this final batch to disk as well.
-        crate::sorting::sort_collect_splitted_user_records(&mut batch_vector);
+        crate::sorting::sort_collect_splitted_user_records(
+            &mut batch_vector,
+            &mut clamped_sessions,
+        );
         batch_counter += 1;
         let path = temporary_fst_dir_path.join(format!("{}.fst", batch_counter));
         write_batch_fst_map(path, &mut batch_vector)
             .with_context(|| format!("Could not write batch number {} to disk", batch_counter))?;
     }
-    println!(
-        "We have sucessfully encoded {} batches as FST maps",
-        batch_counter
+    let boundary_sessions = boundary_sessions_tracker.finish();
+    tracing::info!(
+        records,
+        batches = batch_counter,
+        boundary_sessions = boundary_sessions.len(),
+        "encoded the batches as FST maps"
     );
-    Ok(())
+    Ok((boundary_sessions, clamped_sessions))
 }
 
 // drains the batch_vector and saves a temporary fst where the keys are obtained from the function "user_session_record_temp_fs_key"
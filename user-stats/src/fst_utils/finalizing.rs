@@ -1,24 +1,44 @@
-use crate::writing::UserBestStats;
+use crate::{
+    deduplicating::{ClampedSessions, DuplicateSessionPolicy},
+    leaderboard::UserLeaderboard,
+    summarizing::UserSummary,
+    writing::{UserBestStats, UserStatsWriter, UserSummaryRecord, UserSummaryWriter},
+};
 use anyhow::{Context, Result};
-use fst::{Set, Streamer};
+use common_utils::report_utils::ReportFormat;
+use fst::{Map, Set, Streamer};
 use memmap::Mmap;
-use std::{
-    fs::File,
-    io::{BufWriter, Write},
-    path::Path,
-};
+use std::{collections::BTreeMap, fs::File, io::Write, path::Path};
 use uuid::Uuid;
 
 // Load the stored fst sets (produced by super::storing::from_batched_fst_maps_to_fst_set) and finds the top 10 session for each user present in the union of these sets.
 // write these user stats to the given file with the following format:
 // user_id|session_id1:nb_pics1,session_id2:nb_pics2, ...,session_id10:nb_pics10
+// or as one JSON object per line if the JSON Lines report format is requested
+// or as one Parquet row per (user, session) pair if the Parquet report format is requested.
+// The partial number of pics of the sessions that may cross midnight are loaded from the given boundary fst maps and merged into the top 10
+// of their users.
+// A session can be found more than once for the same user, e.g. in the top 10 of two days because it crossed midnight or because a log was
+// delivered again. The occurrences are combined according to the given duplicate_session_policy, so each session appears at most once per user.
+// Since the number of pics in a session is stored as a u8, summed occurrences are clamped to u8::MAX. The (user, session) pairs
+// that were clamped are returned.
 //
 // WARNING: This function uses memory maps which can lead to undefined behaviour if some other process/program modifies the corresponding file(s)
 // while our program is running.
 pub(crate) fn from_fst_sets_to_stats_file<P: AsRef<Path>>(
     stored_fst_set_paths: Vec<P>,
+    stored_boundary_fst_map_paths: Vec<P>,
     output_file_path: P,
-) -> Result<()> {
+    report_format: ReportFormat,
+    duplicate_session_policy: DuplicateSessionPolicy,
+) -> Result<ClampedSessions> {
+    let mut clamped_sessions = ClampedSessions::new();
+    let mut boundary_sessions = load_boundary_sessions(
+        stored_boundary_fst_map_paths,
+        duplicate_session_policy,
+        &mut clamped_sessions,
+    )?;
+
     // Open files defined in the given paths.
     let files = stored_fst_set_paths
         .iter()
@@ -35,70 +55,287 @@ pub(crate) fn from_fst_sets_to_stats_file<P: AsRef<Path>>(
         .collect::<Result<Vec<_>, std::io::Error>>()
         .with_context(|| "Could not memory map all the specified files")?;
 
-    // Produce FST sets for each of the memory maps
-    let mut fst_sets: Vec<Set<Mmap>> = Vec::new();
+    // Produce FST sets for each of the memory maps. The sets are viewed as maps (with all values equal to zero),
+    // since the union of maps tells us how many of the days a key was found on.
+    let mut fst_sets: Vec<Map<Mmap>> = Vec::new();
     for memory_map in memory_maps {
         let set = Set::new(memory_map)
             .with_context(|| "Unable to obtain an FST set from the given memory map")?;
-        fst_sets.push(set);
+        fst_sets.push(Map::from(set.into_fst()));
     }
     // take the union of all the FST sets.
-    let mut op_builder = fst::set::OpBuilder::new();
+    let mut op_builder = fst::map::OpBuilder::new();
     for fst_set in fst_sets.iter() {
         op_builder.push(fst_set);
     }
     let mut union = op_builder.union();
+    // A key found on several days appears only once in the union, hence the number of keys of all the sets is an upper bound.
+    let progress = common_utils::progress::items(
+        fst_sets.iter().map(|fst_set| fst_set.len() as u64).sum(),
+        "merge top 10 sets",
+    );
     // The keys in this union correspond to (user_id, u8::MAX - nb_pics, session_id) and are ordered lexicographically.
     // that is the first 16 bytes give us the user id, the 17'th byte is u8::MAX - nb_pics, and the 18'th until the 33rd byte gives us the session id.
-    // The last observed user id encoded as bytes
-    let mut current_pid_bytes = [0u8; 16];
-    // The number of records we have recorded for the
-    let mut pushed_records_for_current_user = 0;
+    // The last observed user id, None until the first key has been seen.
+    let mut current_pid: Option<Uuid> = None;
 
     let mut current_user_best_stats = UserBestStats::default();
-    let mut buf_writer = BufWriter::new(File::create(&output_file_path).with_context(|| {
-        format!(
-            "Could not create file: {:?}",
-            &output_file_path.as_ref().as_os_str()
-        )
-    })?);
+    let mut user_stats_writer = UserStatsWriter::new(
+        File::create(&output_file_path).with_context(|| {
+            format!(
+                "Could not create file: {:?}",
+                &output_file_path.as_ref().as_os_str()
+            )
+        })?,
+        report_format,
+    )?;
 
-    while let Some(key) = union.next() {
+    while let Some((key, index_values)) = union.next() {
+        common_utils::cancellation::check()?;
+        progress.inc(1);
         // throughout recall once more that the key corresponds to (user_id, u8::MAX - nb_pics, session_id).
         // that is the first 16 bytes give us the user_id, the 17'th byte is u8::MAX - nb_pics, and the 18'th until the 33rd byte gives us the session id.
 
+        let next_user_id = Uuid::from_slice(&key[..16])?;
         // as soon as we see another user id, we write the current user's best stats to file.
-        if key[..16] != current_pid_bytes[..] {
-            // the exception is on the very first iteration. Here we are assumming that Uuid::default() is not an actual user id!
-            if current_pid_bytes != [0u8; 16] {
-                writeln!(buf_writer, "{}", current_user_best_stats).with_context(|| {
-                    format!("Failed writing {} to file", current_user_best_stats)
-                })?;
+        if current_pid != Some(next_user_id) {
+            // the exception is on the very first iteration, where there is no current user yet.
+            if current_pid.is_some() {
+                let partials = boundary_sessions.remove(&current_user_best_stats.user_id);
+                write_user(
+                    &mut user_stats_writer,
+                    &mut current_user_best_stats,
+                    partials.unwrap_or_default(),
+                    duplicate_session_policy,
+                    &mut clamped_sessions,
+                )?;
+            }
+            // users who only played boundary sessions are written in the order of their user ids as well.
+            while let Some(entry) = boundary_sessions.first_entry() {
+                if *entry.key() >= next_user_id {
+                    break;
+                }
+                let (user_id, partials) = entry.remove_entry();
+                write_user(
+                    &mut user_stats_writer,
+                    &mut UserBestStats::new(user_id),
+                    partials,
+                    duplicate_session_policy,
+                    &mut clamped_sessions,
+                )?;
             }
             // reset the current user best stats data:
             current_user_best_stats.clear(); // consider assinging to default value instead here.
                                                // update the current user id
-            current_pid_bytes.clone_from_slice(&key[..16]);
-            current_user_best_stats.update_user_id(current_pid_bytes);
-            // reset the number of records pushed into current_user_best_stats
-            pushed_records_for_current_user = 0;
+            current_pid = Some(next_user_id);
+            current_user_best_stats.update_user_id(*next_user_id.as_bytes());
+        }
+        // every daily set holds at most ten entries per user, but a session found on several days has several entries
+        // (with different nb_pics if the days disagree). Hence all entries of a user are collected and combined by session id
+        // before the top 10 is taken when the user is written.
+        let mut session_id_bytes = [0u8; 16];
+        session_id_bytes.clone_from_slice(&key[17..]);
+        let session_id = Uuid::from_bytes(session_id_bytes);
+        let nb_pics = (u8::MAX as u64) - (key[16] as u64);
+        // there is one index value per day the key was found on.
+        for _ in index_values {
+            if current_user_best_stats.add_session(
+                session_id,
+                nb_pics as u8,
+                duplicate_session_policy,
+            ) {
+                clamped_sessions.insert((next_user_id, session_id));
+            }
+        }
+    }
+    // The last user has not been written inside the loop.
+    if current_pid.is_some() {
+        let partials = boundary_sessions.remove(&current_user_best_stats.user_id);
+        write_user(
+            &mut user_stats_writer,
+            &mut current_user_best_stats,
+            partials.unwrap_or_default(),
+            duplicate_session_policy,
+            &mut clamped_sessions,
+        )?;
+    }
+    for (user_id, partials) in boundary_sessions {
+        write_user(
+            &mut user_stats_writer,
+            &mut UserBestStats::new(user_id),
+            partials,
+            duplicate_session_policy,
+            &mut clamped_sessions,
+        )?;
+    }
+    user_stats_writer.finish()?;
+    Ok(clamped_sessions)
+}
+
+// Loads the stored fst maps of boundary sessions (produced by super::storing::from_batched_fst_maps_to_fst_set) and combines the partial
+// number of pics of every (user, session) pair according to the given policy. A session crossing midnight hence appears once.
+//
+// WARNING: This function uses memory maps which can lead to undefined behaviour if some other process/program modifies the corresponding file(s)
+// while our program is running.
+fn load_boundary_sessions<P: AsRef<Path>>(
+    stored_fst_map_paths: Vec<P>,
+    duplicate_session_policy: DuplicateSessionPolicy,
+    clamped_sessions: &mut ClampedSessions,
+) -> Result<BTreeMap<Uuid, Vec<(Uuid, u8)>>> {
+    let files = stored_fst_map_paths
+        .iter()
+        .map(File::open)
+        .collect::<Result<Vec<_>, std::io::Error>>()
+        .with_context(|| {
+            "Could not open all the specified files. Perhaps there is a permission issue?"
+        })?;
+    let memory_maps = files
+        .iter()
+        .map(|file| unsafe { Mmap::map(file) })
+        .collect::<Result<Vec<_>, std::io::Error>>()
+        .with_context(|| "Could not memory map all the specified files")?;
+    let mut fst_maps: Vec<Map<Mmap>> = Vec::new();
+    for memory_map in memory_maps {
+        let map = Map::new(memory_map)
+            .with_context(|| "Unable to obtain an FST map from the given memory map")?;
+        fst_maps.push(map);
+    }
+    let mut op_builder = fst::map::OpBuilder::new();
+    for fst_map in fst_maps.iter() {
+        op_builder.push(fst_map);
+    }
+    let mut union = op_builder.union();
+    // The keys in this union correspond to (user_id, session_id) and there is one index value per day the session was played on.
+    let mut boundary_sessions: BTreeMap<Uuid, Vec<(Uuid, u8)>> = BTreeMap::new();
+    while let Some((key, index_values)) = union.next() {
+        let user_id = Uuid::from_slice(&key[..16])?;
+        let session_id = Uuid::from_slice(&key[16..32])?;
+        let nb_pics = index_values
+            .iter()
+            .map(|index_value| index_value.value as u8)
+            .reduce(|acc, nb_pics| {
+                let (combined, clamped) = duplicate_session_policy.combine(acc, nb_pics);
+                if clamped {
+                    clamped_sessions.insert((user_id, session_id));
+                }
+                combined
+            })
+            .unwrap_or_default();
+        boundary_sessions
+            .entry(user_id)
+            .or_default()
+            .push((session_id, nb_pics));
+    }
+    Ok(boundary_sessions)
+}
+
+// Merges the boundary sessions of a user into its sessions and writes its top 10.
+fn write_user<W: Write + Send>(
+    user_stats_writer: &mut UserStatsWriter<W>,
+    user_best_stats: &mut UserBestStats,
+    partials: Vec<(Uuid, u8)>,
+    duplicate_session_policy: DuplicateSessionPolicy,
+    clamped_sessions: &mut ClampedSessions,
+) -> Result<()> {
+    for (session_id, nb_pics) in partials {
+        if user_best_stats.add_session(session_id, nb_pics, duplicate_session_policy) {
+            clamped_sessions.insert((user_best_stats.user_id, session_id));
+        }
+    }
+    user_best_stats.retain_top_10();
+    user_stats_writer.write(user_best_stats)
+}
+
+// Loads the stored fst maps of daily user summaries (produced by super::storing::from_batched_fst_maps_to_fst_set), merges the summaries of each user
+// present in the union of these maps and writes the merged summaries to the given file with the following format:
+// user_id|sessions|total_pics|mean_pics_per_session|best_session_id:best_nb_pics
+// or as one JSON object per line if the JSON Lines report format is requested.
+// In the same pass the users with the most pics are collected in a leaderboard of the given size, which is returned.
+//
+// WARNING: This function uses memory maps which can lead to undefined behaviour if some other process/program modifies the corresponding file(s)
+// while our program is running.
+pub(crate) fn from_summary_fst_maps_to_summary_file<P: AsRef<Path>>(
+    stored_fst_map_paths: Vec<P>,
+    output_file_path: P,
+    report_format: ReportFormat,
+    leaderboard_size: usize,
+) -> Result<UserLeaderboard> {
+    let files = stored_fst_map_paths
+        .iter()
+        .map(File::open)
+        .collect::<Result<Vec<_>, std::io::Error>>()
+        .with_context(|| {
+            "Could not open all the specified files. Perhaps there is a permission issue?"
+        })?;
+    let memory_maps = files
+        .iter()
+        .map(|file| unsafe { Mmap::map(file) })
+        .collect::<Result<Vec<_>, std::io::Error>>()
+        .with_context(|| "Could not memory map all the specified files")?;
+    let mut fst_maps: Vec<Map<Mmap>> = Vec::new();
+    for memory_map in memory_maps {
+        let map = Map::new(memory_map)
+            .with_context(|| "Unable to obtain an FST map from the given memory map")?;
+        fst_maps.push(map);
+    }
+    let mut op_builder = fst::map::OpBuilder::new();
+    for fst_map in fst_maps.iter() {
+        op_builder.push(fst_map);
+    }
+    let mut union = op_builder.union();
+    let progress = common_utils::progress::items(
+        fst_maps.iter().map(|fst_map| fst_map.len() as u64).sum(),
+        "merge summaries",
+    );
+
+    let outfile = File::create(&output_file_path).with_context(|| {
+        format!(
+            "Could not create file: {:?}",
+            &output_file_path.as_ref().as_os_str()
+        )
+    })?;
+    let mut summary_writer = UserSummaryWriter::new(outfile, report_format)?;
+    let mut leaderboard = UserLeaderboard::new(leaderboard_size);
+    let mut finish_user = |user_id: Uuid, summary: UserSummary| -> Result<()> {
+        leaderboard.push(user_id, &summary);
+        summary_writer.write(&UserSummaryRecord::new(user_id, summary))
+    };
+    // The keys in this union correspond to (user_id, best_session_id), hence all daily summaries of a user appear one after the other.
+    let mut current_user: Option<(Uuid, UserSummary)> = None;
+    while let Some((key, index_values)) = union.next() {
+        common_utils::cancellation::check()?;
+        progress.inc(1);
+        let mut user_id_bytes = [0u8; 16];
+        user_id_bytes.clone_from_slice(&key[..16]);
+        let user_id = Uuid::from_bytes(user_id_bytes);
+        if let Some((current_user_id, current_summary)) = current_user.take() {
+            if current_user_id == user_id {
+                current_user = Some((current_user_id, current_summary));
+            } else {
+                finish_user(current_user_id, current_summary)?;
+            }
         }
-        // since the first ten entries per user id correspond to their best sessions (here we are using the u8::MAX - nb_pics trick!)
-        // we only need to consider these ten first entries for each user.
-        if pushed_records_for_current_user < 10 {
-            let mut session_id_bytes = [0u8; 16];
-            session_id_bytes.clone_from_slice(&key[17..]);
-            let session_id = Uuid::from_bytes(session_id_bytes);
-            let nb_pics = (u8::MAX as u64) - (key[16] as u64);
-            current_user_best_stats.push_session_pics_pair(session_id, nb_pics as u8);
-            pushed_records_for_current_user += 1;
+        let (_, summary) = current_user.get_or_insert_with(|| (user_id, UserSummary::default()));
+        // The same (user, best session) pair can appear on several days, in which case there is one index value per day.
+        for index_value in index_values {
+            *summary += UserSummary::from_fst_entry(key, index_value.value);
         }
     }
-    buf_writer.flush()?;
-    Ok(())
+    if let Some((user_id, summary)) = current_user {
+        finish_user(user_id, summary)?;
+    }
+    summary_writer.finish()?;
+    Ok(leaderboard)
 }
 
 impl UserBestStats {
+    pub(crate) fn new(user_id: Uuid) -> Self {
+        Self {
+            user_id,
+            ..Self::default()
+        }
+    }
+
     fn clear(&mut self) {
         self.user_id = UserBestStats::default().user_id;
         self.session_id_num_pics_pairs = UserBestStats::default().session_id_num_pics_pairs;
@@ -108,7 +345,108 @@ impl UserBestStats {
         self.user_id = Uuid::from_bytes(user_id_bytes);
     }
 
-    fn push_session_pics_pair(&mut self, session_id: Uuid, nb_pics: u8) {
-        self.session_id_num_pics_pairs.push((session_id, nb_pics));
+    // Adds a session of the user. If the session has been added before, e.g. because it was found on another day or was a boundary session,
+    // the number of pics are combined according to the given policy. Returns whether the combined number of pics was clamped to u8::MAX.
+    pub(crate) fn add_session(
+        &mut self,
+        session_id: Uuid,
+        nb_pics: u8,
+        policy: DuplicateSessionPolicy,
+    ) -> bool {
+        match self
+            .session_id_num_pics_pairs
+            .iter_mut()
+            .find(|(id, _)| *id == session_id)
+        {
+            Some((_, pics)) => {
+                let (combined, clamped) = policy.combine(*pics, nb_pics);
+                *pics = combined;
+                clamped
+            }
+            None => {
+                self.session_id_num_pics_pairs.push((session_id, nb_pics));
+                false
+            }
+        }
+    }
+
+    // Keeps the 10 best sessions of the user, the best sessions come first and ties are broken in favour of the smallest session id.
+    pub(crate) fn retain_top_10(&mut self) {
+        self.session_id_num_pics_pairs
+            .sort_by(|(id_a, pics_a), (id_b, pics_b)| pics_b.cmp(pics_a).then(id_a.cmp(id_b)));
+        self.session_id_num_pics_pairs.truncate(10);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundary_sessions_are_merged_into_the_top_10() {
+        let mut user_best_stats = UserBestStats::default();
+        for pics in (1..=10).rev() {
+            user_best_stats.add_session(
+                Uuid::from_u128(pics as u128),
+                pics,
+                DuplicateSessionPolicy::Sum,
+            );
+        }
+        // Session 20 crossed midnight and is the best session once stitched together, session 3 also had a part on a boundary.
+        for (session_id, pics) in [(3, 4), (20, 12)] {
+            user_best_stats.add_session(
+                Uuid::from_u128(session_id),
+                pics,
+                DuplicateSessionPolicy::Sum,
+            );
+        }
+        user_best_stats.retain_top_10();
+        let expected: Vec<(Uuid, u8)> = [
+            (20, 12),
+            (10, 10),
+            (9, 9),
+            (8, 8),
+            (3, 7),
+            (7, 7),
+            (6, 6),
+            (5, 5),
+            (4, 4),
+            (2, 2),
+        ]
+        .iter()
+        .map(|(session_id, pics)| (Uuid::from_u128(*session_id), *pics))
+        .collect();
+        assert_eq!(expected, user_best_stats.session_id_num_pics_pairs);
+    }
+
+    #[test]
+    fn duplicate_sessions_appear_once() {
+        for (policy, nb_pics) in [
+            (DuplicateSessionPolicy::Sum, 9),
+            (DuplicateSessionPolicy::Max, 5),
+        ] {
+            let mut user_best_stats = UserBestStats::default();
+            // Session 1 was found on two days with 5 and 4 pics respectively.
+            for (session_id, pics) in [(1, 5), (2, 3), (1, 4)] {
+                user_best_stats.add_session(Uuid::from_u128(session_id), pics, policy);
+            }
+            user_best_stats.retain_top_10();
+            assert_eq!(
+                vec![(Uuid::from_u128(1), nb_pics), (Uuid::from_u128(2), 3)],
+                user_best_stats.session_id_num_pics_pairs
+            );
+        }
+    }
+
+    #[test]
+    fn clamped_sums_are_reported() {
+        let mut user_best_stats = UserBestStats::default();
+        let session_id = Uuid::from_u128(1);
+        assert!(!user_best_stats.add_session(session_id, 200, DuplicateSessionPolicy::Sum));
+        assert!(user_best_stats.add_session(session_id, 100, DuplicateSessionPolicy::Sum));
+        assert_eq!(
+            vec![(session_id, u8::MAX)],
+            user_best_stats.session_id_num_pics_pairs
+        );
     }
 }
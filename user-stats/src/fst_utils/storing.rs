@@ -1,23 +1,39 @@
 use fst::Map;
-use fst::{map::OpBuilder, SetBuilder, Streamer};
+use fst::{map::OpBuilder, MapBuilder, SetBuilder, Streamer};
 use memmap::Mmap;
 use std::{
+    collections::HashSet,
+    convert::TryFrom,
     fs::{self, File},
     io::{self, BufWriter},
     path::{Path, PathBuf},
 };
 
 use anyhow::{Context, Result};
+use uuid::Uuid;
+
+use crate::{deduplicating::ClampedSessions, summarizing::UserSummary};
 
 // builds an fst::Set with keys corresponding to triples (user_id, u8::MAX - sum_pics, session_id) from the temporarily stored fst::Maps
 // created by super::batching::from_log_file_to_batched_fst_maps. The fst::Set will be saved to the given output_file_path.
-// The temporary fst directory is deleted at the end of this function.
+// In the same pass the summary of every user is computed and saved as an fst::Map to the given summary_output_file_path
+// (see crate::summarizing for how the summaries are encoded).
+// The given boundary sessions may cross midnight, hence they do not compete for the top 10 of the day. Instead their partial number of pics
+// are saved as an fst::Map with keys [user_id, session_id] to the given boundary_output_file_path, so that they can be stitched together
+// with the other part of the session over the last seven days (see super::finalizing::from_fst_sets_to_stats_file).
+// The same (user, session) pair may be found in several batches. Its pics are summed up and clamped to u8::MAX, and the pairs
+// this happened to are added to the given clamped sessions.
+// The temporary fst directory is left to the caller, which is responsible for deleting it.
 //
 // WARNING: This function uses memory maps which can lead to undefined behaviour if some other process/program modifies the corresponding file(s)
 // while our program is running.
 pub(crate) fn from_batched_fst_maps_to_fst_set<P: AsRef<Path>>(
     temporary_fst_dir_path: PathBuf,
     output_file_path: P,
+    summary_output_file_path: P,
+    boundary_output_file_path: P,
+    boundary_sessions: &HashSet<Uuid>,
+    clamped_sessions: &mut ClampedSessions,
 ) -> Result<()> {
     // Open all the files found in temporary_fst_dir_path
     let files = fs::read_dir(&temporary_fst_dir_path)?
@@ -52,6 +68,11 @@ pub(crate) fn from_batched_fst_maps_to_fst_set<P: AsRef<Path>>(
         op_builder.push(fst_map);
     }
     let mut union = op_builder.union();
+    // The union has at most as many keys as the batches together, since the same (user, session) pair may be found in several batches.
+    let progress = common_utils::progress::items(
+        fst_maps.iter().map(|fst_map| fst_map.len() as u64).sum(),
+        "merge batches",
+    );
     // we will be keep updating the users best pics in sessions from inside the stream.
     let mut user_best_sum_pics = UserBestSumPics::default();
     // once we are sure we have found a user's top 10 best pics in sessions we will write this information to our fst::Set.
@@ -64,51 +85,122 @@ pub(crate) fn from_batched_fst_maps_to_fst_set<P: AsRef<Path>>(
     let mut set_builder = SetBuilder::new(wtr).with_context(|| {
         "Unable to build an FST set from the temporary FST maps. Failed to produce a Set builder"
     })?;
-    // the last observed user id encoded as bytes.
-    let mut current_pid = [0u8; 16];
+    // the summary of the current user is written to our fst::Map once we have seen all of the user's sessions.
+    let mut user_summary = UserSummary::default();
+    let summary_wtr =
+        BufWriter::new(File::create(&summary_output_file_path).with_context(|| {
+            format!(
+                "Failed to create file: {:?}",
+                summary_output_file_path.as_ref().as_os_str()
+            )
+        })?);
+    let mut summary_map_builder = MapBuilder::new(summary_wtr).with_context(|| {
+        "Unable to build an FST map of the user summaries. Failed to produce a Map builder"
+    })?;
+    // the partial number of pics of the boundary sessions are written to our fst::Map in the order of the stream.
+    let boundary_wtr =
+        BufWriter::new(File::create(&boundary_output_file_path).with_context(|| {
+            format!(
+                "Failed to create file: {:?}",
+                boundary_output_file_path.as_ref().as_os_str()
+            )
+        })?);
+    let mut boundary_map_builder = MapBuilder::new(boundary_wtr).with_context(|| {
+        "Unable to build an FST map of the boundary sessions. Failed to produce a Map builder"
+    })?;
+    // the last observed user id encoded as bytes, None until the first key has been seen.
+    // Note that no user id (not even the nil UUID) can be used to mark that no key has been seen yet.
+    let mut current_pid: Option<[u8; 16]> = None;
     // the last observed session id encoded as bytes.
     let mut current_session_id = [0u8; 16];
     // we can transfomr the union of FST Maps into a stream. The items returned from this stream are of the form ([user id as bytes session_id as bytes], [IndexValue] where each IndexValue contains an index and the corresponding value.
     // The index corresponds to which FST Map the value comes from.
     while let Some((key, value)) = union.next() {
+        common_utils::cancellation::check()?;
+        progress.inc(1);
+        let mut pid = [0u8; 16];
+        pid.clone_from_slice(&key[..16]);
         // once the last observed user id changes we store the users top 10 sessions in the FST set.
-        if key[..16] != current_pid[..] {
-            if current_pid != [0u8; 16] {
-                // this means we are passed the very first iteration and the stream has finished with the last user_id
-                // each iteration of the following for loop corresponds to writing the ordered triple (user_id,u8::MAX - sum_pics, session_id)
-                // into the fst::Set. We use u8::MAX - sum pics so that we can easily retrieve the highest sums of pics when we later load
-                // this stored fst::Set.
-                for set_key in fst_set_keys_iter(&user_best_sum_pics) {
-                    set_builder.insert(&set_key).with_context(|| {
-                        format!("Failed to insert {:?} into the the FST Set", set_key)
-                    })?;
-                }
+        if current_pid != Some(pid) {
+            if let Some(previous_pid) = current_pid {
+                // this means we are passed the very first iteration and the stream has finished with the previous user_id
+                insert_user_top_10(&mut set_builder, &user_best_sum_pics)?;
+                insert_user_summary(&mut summary_map_builder, &previous_pid, &user_summary)?;
             }
+            user_summary = UserSummary::default();
             user_best_sum_pics.clear(); //todo: Consider setting user_best_sum_pics = UserBestSumPics::default() here.
-            current_pid.clone_from_slice(&key[..16]); // update current_pid to the new user id.
-            user_best_sum_pics.update_user_id(current_pid);
+            current_pid = Some(pid); // update current_pid to the new user id.
+            user_best_sum_pics.update_user_id(pid);
         }
+        current_session_id.clone_from_slice(&key[16..]);
         // This way we easily sum up all the pics a user had in the same session
-        let sum_pics = value
-            .iter()
-            .fold(0u8, |acc, index| acc + (index.value as u8));
-        if user_best_sum_pics.is_improvement(sum_pics) {
-            current_session_id.clone_from_slice(&key[16..]);
+        let sum_pics = value.iter().map(|index| index.value).sum::<u64>();
+        let sum_pics = u8::try_from(sum_pics).unwrap_or_else(|_| {
+            clamped_sessions.insert((Uuid::from_bytes(pid), Uuid::from_bytes(current_session_id)));
+            u8::MAX
+        });
+        user_summary.add_session(Uuid::from_bytes(current_session_id), sum_pics);
+        if boundary_sessions.contains(&Uuid::from_bytes(current_session_id)) {
+            boundary_map_builder
+                .insert(key, sum_pics as u64)
+                .with_context(|| {
+                    format!(
+                        "Failed to insert {:?} into the FST Map of boundary sessions",
+                        key
+                    )
+                })?;
+        } else if user_best_sum_pics.is_improvement(sum_pics) {
             user_best_sum_pics.update(&current_session_id, sum_pics as i16);
         }
     }
+    // The top 10 sessions and the summary of the last user have not been written inside the loop.
+    if let Some(last_pid) = current_pid {
+        insert_user_top_10(&mut set_builder, &user_best_sum_pics)?;
+        insert_user_summary(&mut summary_map_builder, &last_pid, &user_summary)?;
+    }
     set_builder
         .finish()
         .with_context(|| "Failed to save the built fst Set to disk")?;
-    drop(union);
-    drop(fst_maps);
-    drop(files);
-    fs::remove_dir_all(temporary_fst_dir_path.as_path()).with_context(|| {
-        format!(
-            "Failed removing the temporary directory: {:?}",
-            temporary_fst_dir_path.as_path().as_os_str()
-        )
-    })?;
+    summary_map_builder
+        .finish()
+        .with_context(|| "Failed to save the built fst Map of user summaries to disk")?;
+    boundary_map_builder
+        .finish()
+        .with_context(|| "Failed to save the built fst Map of boundary sessions to disk")?;
+    Ok(())
+}
+
+// Inserts the top 10 sessions of a user into the fst::Set. Each key corresponds to the ordered triple (user_id, u8::MAX - sum_pics, session_id).
+// We use u8::MAX - sum pics so that we can easily retrieve the highest sums of pics when we later load this stored fst::Set.
+fn insert_user_top_10<W: std::io::Write>(
+    set_builder: &mut SetBuilder<W>,
+    user_best_sum_pics: &UserBestSumPics,
+) -> Result<()> {
+    for set_key in fst_set_keys_iter(user_best_sum_pics) {
+        set_builder
+            .insert(&set_key)
+            .with_context(|| format!("Failed to insert {:?} into the the FST Set", set_key))?;
+    }
+    Ok(())
+}
+
+// Inserts the summary of the given user into the fst::Map of user summaries.
+fn insert_user_summary<W: std::io::Write>(
+    summary_map_builder: &mut MapBuilder<W>,
+    user_id: &[u8; 16],
+    user_summary: &UserSummary,
+) -> Result<()> {
+    let user_id = Uuid::from_bytes(*user_id);
+    if let Some(key) = user_summary.to_fst_key(&user_id) {
+        summary_map_builder
+            .insert(key, user_summary.to_fst_value()?)
+            .with_context(|| {
+                format!(
+                    "Failed to insert the summary of user {} into the FST Map",
+                    user_id
+                )
+            })?;
+    }
     Ok(())
 }
 
@@ -187,3 +279,63 @@ fn fst_set_keys_iter(user_best_sum_pics: &UserBestSumPics) -> impl Iterator<Item
     }
     keys.into_iter()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_utils::{
+        manifest::SourceDigest,
+        parsing_utils::{NilIdRule, RecordValidator},
+    };
+    use fst::Set;
+
+    #[test]
+    fn pics_spread_across_batches_are_clamped() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_file_path = dir.path().join("safari-sessions-20210101.log");
+        let (user_id, session_id) = (Uuid::from_u128(1), Uuid::from_u128(100));
+        // The other session separates the records of the first one, so that each batch of two pairs holds 100 of its pics.
+        let log_lines: Vec<String> = (0..5)
+            .map(|idx| match idx % 2 {
+                0 => format!("{},{},1,100", user_id, session_id),
+                _ => format!("{},{},1,1", user_id, Uuid::from_u128(200 + idx)),
+            })
+            .collect();
+        fs::write(&log_file_path, log_lines.join("\n")).unwrap();
+        let temporary_fst_dir_path = dir.path().join("temporary_fsts");
+        let (boundary_sessions, mut clamped_sessions) =
+            crate::fst_utils::batching::from_log_file_to_batched_fst_maps(
+                &log_file_path,
+                temporary_fst_dir_path.clone(),
+                2,
+                0.5,
+                0,
+                &mut RecordValidator::new(NilIdRule::Accept, dir.path().join("quarantined.log")),
+                &SourceDigest::default(),
+            )
+            .unwrap();
+        assert!(clamped_sessions.is_empty());
+        assert_eq!(3, fs::read_dir(&temporary_fst_dir_path).unwrap().count());
+
+        let set_path = dir.path().join("top-10.fst");
+        from_batched_fst_maps_to_fst_set(
+            temporary_fst_dir_path,
+            set_path.clone(),
+            dir.path().join("summary.fst"),
+            dir.path().join("boundary.fst"),
+            &boundary_sessions,
+            &mut clamped_sessions,
+        )
+        .unwrap();
+        assert_eq!(
+            ClampedSessions::from([(user_id, session_id)]),
+            clamped_sessions
+        );
+        // The best session of the user is stored with u8::MAX pics, i.e. with 0 in the byte following the user id.
+        let set = Set::new(fs::read(set_path).unwrap()).unwrap();
+        let mut best_key = user_id.as_bytes().to_vec();
+        best_key.push(0);
+        best_key.extend_from_slice(session_id.as_bytes());
+        assert_eq!(Some(&best_key), set.stream().into_bytes().first());
+    }
+}
@@ -0,0 +1,109 @@
+// This module contains the global leaderboard of the users with the most pics over the last seven days.
+// The leaderboard is filled while streaming the merged user summaries, keeping only the best `size` users in a min-heap,
+// hence its memory consumption is bounded by its size rather than the number of users.
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use uuid::Uuid;
+
+use crate::summarizing::UserSummary;
+
+// A user on the leaderboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LeaderboardEntry {
+    pub(crate) user_id: Uuid,
+    pub(crate) total_pics: u64,
+    pub(crate) sessions: u64,
+}
+
+// Entries are ordered from worst to best: by their total number of pics and, for equal totals, the smallest user id is the best.
+impl Ord for LeaderboardEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.total_pics
+            .cmp(&other.total_pics)
+            .then_with(|| other.user_id.cmp(&self.user_id))
+    }
+}
+
+impl PartialOrd for LeaderboardEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub(crate) struct UserLeaderboard {
+    size: usize,
+    // A min-heap, so that the worst user on the leaderboard can be replaced quickly.
+    heap: BinaryHeap<Reverse<LeaderboardEntry>>,
+    // The number of users that competed for the leaderboard.
+    users: u64,
+}
+
+impl UserLeaderboard {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            size,
+            heap: BinaryHeap::with_capacity(size),
+            users: 0,
+        }
+    }
+
+    // The number of users pushed so far, i.e. every user with a summary.
+    pub(crate) fn users(&self) -> u64 {
+        self.users
+    }
+
+    // Adds the user to the leaderboard if the leaderboard is not full yet or the user beats the worst user on it.
+    pub(crate) fn push(&mut self, user_id: Uuid, summary: &UserSummary) {
+        let entry = LeaderboardEntry {
+            user_id,
+            total_pics: summary.total_pics,
+            sessions: summary.sessions,
+        };
+        self.users += 1;
+        if self.heap.len() < self.size {
+            self.heap.push(Reverse(entry));
+        } else if let Some(mut worst) = self.heap.peek_mut() {
+            if entry > worst.0 {
+                *worst = Reverse(entry);
+            }
+        }
+    }
+
+    // The users on the leaderboard from best to worst.
+    pub(crate) fn into_ranked_entries(self) -> Vec<LeaderboardEntry> {
+        // Sorting the reversed entries in ascending order yields the best entry first.
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(entry)| entry)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaderboard_keeps_the_users_with_the_most_pics() {
+        let mut leaderboard = UserLeaderboard::new(2);
+        for (user_id, pics) in [(1u128, 5u8), (2, 9), (3, 7), (4, 9)] {
+            let mut summary = UserSummary::default();
+            summary.add_session(Uuid::from_u128(100 + user_id), pics);
+            leaderboard.push(Uuid::from_u128(user_id), &summary);
+        }
+        let ranked: Vec<_> = leaderboard
+            .into_ranked_entries()
+            .into_iter()
+            .map(|entry| (entry.user_id, entry.total_pics))
+            .collect();
+        // Users 2 and 4 both have 9 pics, the smallest user id ranks first.
+        assert_eq!(
+            vec![(Uuid::from_u128(2), 9), (Uuid::from_u128(4), 9)],
+            ranked
+        );
+    }
+}
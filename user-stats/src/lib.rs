@@ -1,20 +1,77 @@
 pub mod configuration;
+mod deduplicating;
 mod fst_utils;
+mod leaderboard;
 mod parsing;
 mod sorting;
+mod summarizing;
+mod verifying;
 mod writing;
 
 use anyhow::{Context, Result};
-use std::path::PathBuf;
-use std::str::FromStr;
-use uuid::Uuid;
+use common_utils::{
+    date_utils::DateStamp,
+    file_utils::{QuarantineFilesConfig, StateFilesGuard, UnprocessedSessionLogFile},
+    locking::StateDirectoryLock,
+    manifest::{RunManifest, SourceDigest, SourceLog},
+    metrics::RunMetrics,
+    parsing_utils::{BoundarySessionsTracker, NilIdRule, RecordValidator},
+    progress::ProgressBar,
+    report_utils::ReportFormat,
+};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use deduplicating::ClampedSessions;
+pub use deduplicating::DuplicateSessionPolicy;
+
+/// The options user-stats is run with. See the command line interface for their descriptions.
+pub struct RunOptions {
+    pub report_format: ReportFormat,
+    pub leaderboard_size: usize,
+    pub duplicate_session_policy: DuplicateSessionPolicy,
+    pub verify: bool,
+    pub nil_ids: NilIdRule,
+    pub wait_for_lock: bool,
+    pub metrics_file: Option<PathBuf>,
+    pub jobs: usize,
+    /// If set, only these session logs are processed, e.g. the logs a watcher found to be complete. Any other unprocessed log
+    /// is treated like a missing one (see common_utils::watching::only_completed_logs).
+    pub completed_logs: Option<HashSet<PathBuf>>,
+}
 
 /// parses session log files and prints the top 10 pics in session for each user in the course of the last seven days.
 ///
 /// This process consists of several steps. The session log files from the last seven days that have yet to be processed by this program are detected
 /// and processed in turn. For each of these we create an FST set where the keys are of the form [user_id, u8:MAX - num pics, session_id] and store this
 /// for subsequent use. After all the FST Sets have been created we take their union and use the encoded information to find the top 10 pics in session by user
-/// which we then write to file.
+/// which we then write to file in the requested report format.
+///
+/// Alongside each FST set we store an FST map of the daily summaries of the users (sessions played, total pics and best session).
+/// These are merged over the last seven days in the same way and written to the user_summary_YYYYMMDD report. While merging them,
+/// the leaderboard_size users with the most pics are kept in a heap and written to the user_leaderboard_YYYYMMDD report.
+///
+/// A session found more than once for the same user over the last seven days (e.g. because it crossed midnight or a log was delivered again)
+/// appears only once in the user's top 10, its pics are combined according to duplicate_session_policy.
+///
+/// Records whose user or session id is the nil UUID are accepted, rejected or quarantined according to nil_ids.
+///
+/// If verify is set, the top 10 and summary reports are recomputed with a naive in-memory implementation and compared with the written reports.
+/// An error describing the first difference is returned if they do not match. This loads all sessions of the last seven days into memory.
+///
+/// Only one run may use the directory of the saved FST files at a time. If it is locked by another run, we either wait for that run to finish
+/// or fail immediately depending on wait_for_lock.
+///
+/// Every run writes a manifest next to the reports, which records the configuration, the session logs the reports were
+/// computed from and the reports themselves (see common_utils::manifest). If the run fails, the manifest records the error as well.
+///
+/// If a metrics file is given, the metrics of the run are written to it in the Prometheus text format once the run has finished,
+/// whether it succeeded or not (see common_utils::metrics).
 ///
 /// In order to avoid high memory consumption we write temporary FST Maps to file in batches during construction of the FST sets.
 ///
@@ -22,64 +79,422 @@ use uuid::Uuid;
 /// thoroughout the execution of this program. Moreover despite memory maps being very fast on an SSD hard drive, it may be very slow on cheaper hard drives.
 /// Finally your operating system may decide to use a lot of memory for the page cache while reading our FST sets/maps from disk which can make it look like
 /// we are consuming an awful lot of RAM.
-pub fn run(from_path: PathBuf, to_path: PathBuf) -> Result<()> {
-    for unprocessed_log_file in
-        common_utils::file_utils::unprocessed_session_log_files(from_path, |datestamp| {
-            crate::configuration::SavedFstSetFilesConfig::file_path_from_date(datestamp).exists()
-        })
-    {
-        let unprocessed_log_file = unprocessed_log_file?;
-        println!("processing {:?}", &unprocessed_log_file.path.as_os_str());
-
-        println!("Parsing, Sorting and Collecting in batches. Encoding information in FST maps: Keys [user_id, session_id] and values are the corresponding number of pics found within the batch");
-        // Create a temporary directory to temporarily store FST maps.
-        let temp_fst_dir_path_string = format!("./temporary_fsts_{}", Uuid::new_v4());
-        let temporary_fst_dir_path = PathBuf::from_str(temp_fst_dir_path_string.as_str())
+pub fn run(from_path: PathBuf, to_path: PathBuf, options: &RunOptions) -> Result<()> {
+    let mut metrics = RunMetrics::default();
+    let result = run_collecting_metrics(from_path, to_path, options, &mut metrics);
+    if let Some(metrics_file) = options.metrics_file.as_ref() {
+        let written = metrics
+            .write_textfile(metrics_file, "user-stats", result.is_ok())
             .with_context(|| {
                 format!(
-                    "Failed to create path: {} for temporary batch storage.",
-                    temp_fst_dir_path_string
+                    "Failed to write the metrics to {:?}",
+                    metrics_file.as_os_str()
                 )
-            })?;
-        // Maximum number of (user_id, session_id, nb_pics) triples we can keep in a batch before we have to write it to memory.
-        const CAPACITY_LIMIT: usize = 3 * 10usize.pow(7);
-        // We are parsing records and summing up the number of pics for records with the same user and session ids
-        // this frees up space in our batch vector, so we do not necessarily have to write the batch to disk after the first CAPACITY_LIMIT has been reached
-        // however we also do not want to sort and collect too often.
-        // The following constant determines that whenever (number of elements in batch vector after sorting)/CAPACITY_LIMIT > MAX_CAPACITY_RATIO_AFTER_SORT_COLLECT
-        // we have to write the batch to disk.
-        const MAX_CAPACITY_RATIO_AFTER_SORT_COLLECT: f64 = 0.5;
+            });
+        if let Err(error) = written {
+            // The error of the run is more important than the missing metrics.
+            if result.is_ok() {
+                return Err(error);
+            }
+            tracing::warn!("{:#}", error);
+        }
+    }
+    result
+}
+
+// The state of a day computed from its session log.
+struct ProcessedDay {
+    date: DateStamp,
+    source_log: SourceLog,
+    time: Duration,
+    metrics: RunMetrics,
+    // The (user, session) pairs whose pics within the day were clamped to u8::MAX.
+    clamped_sessions: ClampedSessions,
+}
+
+// Parses the given session log in batches and stores the FST set and maps of its day. The memory budget of the batches
+// is shared by the given number of days that are processed concurrently.
+fn process_log_file(
+    unprocessed_log_file: UnprocessedSessionLogFile,
+    options: &RunOptions,
+    concurrent_days: usize,
+    days_progress: &ProgressBar,
+) -> Result<ProcessedDay> {
+    let RunOptions { nil_ids, .. } = *options;
+    let mut metrics = RunMetrics::default();
+    let _log_span = tracing::info_span!(
+        "process_log",
+        log = ?unprocessed_log_file.path.as_os_str()
+    )
+    .entered();
+    days_progress.set_message(
+        unprocessed_log_file
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+    );
+    // The records are parsed, sorted and collected in batches. Every batch is encoded in an FST map, where the keys
+    // are [user_id, session_id] and the values the corresponding number of pics found within the batch.
+    let parse_span = tracing::info_span!("parse").entered();
+    let day_started = Instant::now();
+    let source_digest = SourceDigest::default();
+    // Create a temporary directory to temporarily store FST maps. It is removed when the guard is dropped,
+    // that is also when an error (or a cancellation) is propagated before the log has been processed.
+    let temporary_fst_dir = tempfile::Builder::new()
+        .prefix(crate::configuration::SavedFstSetFilesConfig::TEMPORARY_DIRECTORY_PREFIX)
+        .tempdir_in(".")
+        .with_context(|| "Failed to create a temporary directory for batch storage")?;
+    let temporary_fst_dir_path = temporary_fst_dir.path().to_path_buf();
+    // Maximum number of (user_id, session_id, nb_pics) triples we can keep in a batch before we have to write it to memory.
+    const CAPACITY_LIMIT: usize = 3 * 10usize.pow(7);
+    // The memory budget is split evenly between the logs that are processed concurrently.
+    let capacity_limit = CAPACITY_LIMIT / concurrent_days;
+    // We are parsing records and summing up the number of pics for records with the same user and session ids
+    // this frees up space in our batch vector, so we do not necessarily have to write the batch to disk after the first capacity_limit has been reached
+    // however we also do not want to sort and collect too often.
+    // The following constant determines that whenever (number of elements in batch vector after sorting)/capacity_limit > MAX_CAPACITY_RATIO_AFTER_SORT_COLLECT
+    // we have to write the batch to disk.
+    const MAX_CAPACITY_RATIO_AFTER_SORT_COLLECT: f64 = 0.5;
+    let mut record_validator = RecordValidator::new(
+        nil_ids,
+        QuarantineFilesConfig::file_path("user-stats", unprocessed_log_file.date.clone()),
+    );
+    let (boundary_sessions, mut clamped_sessions) =
         crate::fst_utils::batching::from_log_file_to_batched_fst_maps(
             unprocessed_log_file.path.clone(),
             temporary_fst_dir_path.clone(),
-            CAPACITY_LIMIT,
+            capacity_limit,
             MAX_CAPACITY_RATIO_AFTER_SORT_COLLECT,
+            BoundarySessionsTracker::DEFAULT_WINDOW,
+            &mut record_validator,
+            &source_digest,
         )?;
-        println!("Constructing an FST set describing the top 10 number of pics in session per user that were found in {:?}.", &unprocessed_log_file.path.as_os_str());
-        let fst_set_storage_path =
-            crate::configuration::SavedFstSetFilesConfig::file_path_from_date(
-                unprocessed_log_file.date,
-            );
-        crate::fst_utils::storing::from_batched_fst_maps_to_fst_set(
-            temporary_fst_dir_path,
-            fst_set_storage_path,
-        )?;
-        println!(
-            "Stored FST set corresponding to {:?} for reuse. The keys are of the form [user_id, (u8::MAX - nb_pics),session_id]",
+    metrics.rows_parsed += record_validator.rows_read();
+    metrics.rows_rejected += record_validator.rows_rejected();
+    metrics.temporary_bytes_written +=
+        common_utils::file_utils::directory_size(&temporary_fst_dir_path).with_context(|| {
+            "Failed to determine the size of the batches written to the temporary directory"
+        })?;
+    let source_log = SourceLog::new(
+        &unprocessed_log_file.path,
+        &source_digest,
+        &record_validator,
+    )
+    .with_context(|| {
+        format!(
+            "Failed to describe the session log file {:?} for the run manifest",
+            unprocessed_log_file.path.as_os_str()
+        )
+    })?;
+    let quarantine_file_path = record_validator.quarantine_file_path().to_path_buf();
+    let quarantined_records = record_validator.finish().with_context(|| {
+        format!(
+            "Invalid records were found in {:?}",
             unprocessed_log_file.path.as_os_str()
+        )
+    })?;
+    if quarantined_records > 0 {
+        tracing::warn!(
+            records = quarantined_records,
+            file = ?quarantine_file_path.as_os_str(),
+            "records with a nil user or session id have been quarantined"
         );
     }
-    println!("Extracting top 10 pics in session by user over a seven day period.");
+    drop(parse_span);
+    metrics.add_stage_duration("parse", day_started.elapsed());
+    // Construct the FST set describing the top 10 number of pics in session per user that were found in the log.
+    // The keys are of the form [user_id, (u8::MAX - nb_pics), session_id]
+    let persist_span = tracing::info_span!("persist").entered();
+    let persist_started = Instant::now();
+    let fst_set_storage_path = crate::configuration::SavedFstSetFilesConfig::file_path_from_date(
+        unprocessed_log_file.date.clone(),
+    );
+    let summary_fst_map_storage_path =
+        crate::configuration::SavedFstSetFilesConfig::summary_file_path_from_date(
+            unprocessed_log_file.date.clone(),
+        );
+    let boundary_fst_map_storage_path =
+        crate::configuration::SavedFstSetFilesConfig::boundary_file_path_from_date(
+            unprocessed_log_file.date.clone(),
+        );
+    let source_log_path = SourceLog::file_path(
+        &crate::configuration::SavedFstSetFilesConfig::storage_directory(),
+        unprocessed_log_file.date.clone(),
+    );
+    // The day counts as processed once the FST files exist, hence they are written to temporary paths and
+    // renamed into place last, after the source log of the manifest.
+    let state_files_guard = StateFilesGuard::new(vec![
+        source_log_path.clone(),
+        summary_fst_map_storage_path.clone(),
+        boundary_fst_map_storage_path.clone(),
+        fst_set_storage_path.clone(),
+    ]);
+    crate::fst_utils::storing::from_batched_fst_maps_to_fst_set(
+        temporary_fst_dir_path.clone(),
+        StateFilesGuard::temporary_path(&fst_set_storage_path),
+        StateFilesGuard::temporary_path(&summary_fst_map_storage_path),
+        StateFilesGuard::temporary_path(&boundary_fst_map_storage_path),
+        &boundary_sessions,
+        &mut clamped_sessions,
+    )?;
+    source_log
+        .save(&StateFilesGuard::temporary_path(&source_log_path))
+        .with_context(|| format!("Failed to save {:?}", source_log_path.as_os_str()))?;
+    state_files_guard
+        .keep()
+        .with_context(|| "Failed to move the FST files into place".to_string())?;
+    temporary_fst_dir.close().with_context(|| {
+        format!(
+            "Failed removing the temporary directory: {:?}",
+            temporary_fst_dir_path.as_os_str()
+        )
+    })?;
+    metrics.add_stage_duration("persist", persist_started.elapsed());
+    metrics.days_processed += 1;
+    drop(persist_span);
+    days_progress.inc(1);
+    Ok(ProcessedDay {
+        date: unprocessed_log_file.date,
+        source_log,
+        time: day_started.elapsed(),
+        metrics,
+        clamped_sessions,
+    })
+}
 
-    let output_file_path = crate::configuration::todays_users_stats_file_path(to_path);
+fn run_collecting_metrics(
+    from_path: PathBuf,
+    to_path: PathBuf,
+    options: &RunOptions,
+    metrics: &mut RunMetrics,
+) -> Result<()> {
+    let RunOptions {
+        report_format,
+        leaderboard_size,
+        duplicate_session_policy,
+        verify,
+        nil_ids,
+        wait_for_lock,
+        metrics_file: _,
+        jobs,
+        completed_logs: _,
+    } = *options;
+    if jobs == 0 {
+        anyhow::bail!("At least one job is needed to process the session logs");
+    }
+    // Another run processing the same days would write the same FST files, hence only one run may use the storage directory at a time.
+    let _lock = StateDirectoryLock::acquire(
+        &crate::configuration::SavedFstSetFilesConfig::storage_directory(),
+        wait_for_lock,
+    )
+    .with_context(|| "Could not lock the directory of the saved FST files")?;
+    let mut manifest = RunManifest::new(
+        "user-stats",
+        env!("CARGO_PKG_VERSION"),
+        serde_json::json!({
+            "session-logs-directory": from_path,
+            "output-directory": to_path,
+            "format": report_format.to_string(),
+            "leaderboard-size": leaderboard_size,
+            "duplicate-sessions": duplicate_session_policy.to_string(),
+            "verify": verify,
+            "nil-ids": nil_ids.to_string(),
+            "jobs": jobs,
+        }),
+    );
+    let result =
+        process_logs_and_write_reports(from_path, to_path.clone(), options, metrics, &mut manifest);
+    if let Err(error) = result.as_ref() {
+        manifest.record_failure(format!("{:#}", error));
+    }
+    let manifest_path = crate::configuration::todays_manifest_file_path(to_path);
+    let written = manifest.write(&manifest_path).with_context(|| {
+        format!(
+            "Failed to write the run manifest: {:?}",
+            manifest_path.as_os_str()
+        )
+    });
+    match written {
+        Ok(()) => {
+            tracing::info!(manifest = ?manifest_path.as_os_str(), "the run manifest has been saved")
+        }
+        // The error of the run is more important than the missing manifest.
+        Err(error) if result.is_err() => tracing::warn!("{:#}", error),
+        Err(error) => return Err(error),
+    }
+    result
+}
 
-    crate::fst_utils::finalizing::from_fst_sets_to_stats_file(
+// Processes the session logs that have not been processed yet and writes the reports, recording both in the given manifest.
+fn process_logs_and_write_reports(
+    from_path: PathBuf,
+    to_path: PathBuf,
+    options: &RunOptions,
+    metrics: &mut RunMetrics,
+    manifest: &mut RunManifest,
+) -> Result<()> {
+    let RunOptions {
+        report_format,
+        leaderboard_size,
+        duplicate_session_policy,
+        verify,
+        nil_ids,
+        wait_for_lock: _,
+        metrics_file: _,
+        jobs,
+        completed_logs: _,
+    } = *options;
+    let processing_started = Instant::now();
+    let unprocessed_log_files = common_utils::watching::only_completed_logs(
+        common_utils::file_utils::unprocessed_session_log_files(from_path.clone(), |datestamp| {
+            crate::configuration::SavedFstSetFilesConfig::is_processed(datestamp)
+        }),
+        options.completed_logs.as_ref(),
+    );
+    metrics.days_reused = common_utils::date_utils::NUMBER_OF_DAYS_IN_WINDOW
+        .saturating_sub(unprocessed_log_files.len()) as u64;
+    // The days are independent of each other, hence up to jobs of them are processed concurrently. The final merge below
+    // only starts once all of them have been processed. The days before a missing session log are processed before failing.
+    let mut unprocessed_days = Vec::new();
+    let mut missing_log_file = None;
+    for unprocessed_log_file in unprocessed_log_files {
+        match unprocessed_log_file {
+            Ok(unprocessed_log_file) => unprocessed_days.push(unprocessed_log_file),
+            Err(error) => {
+                missing_log_file = Some(error);
+                break;
+            }
+        }
+    }
+    // The memory budget is only split between the days that are actually processed at the same time.
+    let concurrent_days = jobs.min(unprocessed_days.len());
+    let days_progress = common_utils::progress::days(unprocessed_days.len());
+    let processed_days =
+        common_utils::parallel::map_in_parallel(unprocessed_days, jobs, |unprocessed_log_file| {
+            process_log_file(
+                unprocessed_log_file,
+                options,
+                concurrent_days,
+                &days_progress,
+            )
+        })?;
+    drop(days_progress);
+    // The days reused from earlier runs are not included, their clamped sessions were reported by the run processing them.
+    let mut clamped_sessions = ClampedSessions::new();
+    for processed_day in processed_days {
+        metrics.merge(processed_day.metrics);
+        clamped_sessions.extend(processed_day.clamped_sessions);
+        manifest.add_recomputed_day(
+            processed_day.date,
+            processed_day.source_log,
+            processed_day.time,
+        );
+    }
+    if let Some(error) = missing_log_file {
+        return Err(error.into());
+    }
+    manifest.add_timing("process_logs", processing_started.elapsed());
+    manifest
+        .add_reused_days(&crate::configuration::SavedFstSetFilesConfig::storage_directory())
+        .with_context(|| {
+            "Failed to load the source logs of the days reused from the saved FST files"
+        })?;
+    let merging_started = Instant::now();
+
+    // The reports are written while the daily FST sets and maps are merged, hence writing them is part of these stages.
+    let output_file_path =
+        crate::configuration::todays_users_stats_file_path(to_path.clone(), report_format);
+    let merge_span = tracing::info_span!("merge", report = ?output_file_path.as_os_str()).entered();
+    let merge_started = Instant::now();
+
+    clamped_sessions.extend(crate::fst_utils::finalizing::from_fst_sets_to_stats_file(
         crate::configuration::SavedFstSetFilesConfig::file_paths_last_seven_days(),
+        crate::configuration::SavedFstSetFilesConfig::boundary_file_paths_last_seven_days(),
         output_file_path.clone(),
+        report_format,
+        duplicate_session_policy,
+    )?);
+    if !clamped_sessions.is_empty() {
+        tracing::warn!(
+            sessions = clamped_sessions.len(),
+            "the summed pics of some sessions exceed {} and are reported as {}",
+            u8::MAX,
+            u8::MAX
+        );
+    }
+    tracing::info!("the top 10 pics in session by user over the last seven days have been saved");
+    drop(merge_span);
+    metrics.add_stage_duration("merge", merge_started.elapsed());
+
+    let summary_file_path =
+        crate::configuration::todays_users_summary_file_path(to_path.clone(), report_format);
+    let merge_span =
+        tracing::info_span!("merge", report = ?summary_file_path.as_os_str()).entered();
+    let merge_started = Instant::now();
+    let leaderboard = crate::fst_utils::finalizing::from_summary_fst_maps_to_summary_file(
+        crate::configuration::SavedFstSetFilesConfig::summary_file_paths_last_seven_days(),
+        summary_file_path.clone(),
+        report_format,
+        leaderboard_size,
     )?;
-    println!(
-        "The results have been saved as {:?}",
-        output_file_path.as_os_str()
+    tracing::info!("the user summaries over the last seven days have been saved");
+    drop(merge_span);
+    metrics.add_stage_duration("merge", merge_started.elapsed());
+    metrics.set_output_entities("users", leaderboard.users());
+
+    let leaderboard_file_path =
+        crate::configuration::todays_users_leaderboard_file_path(to_path.clone(), report_format);
+    let write_span =
+        tracing::info_span!("write", report = ?leaderboard_file_path.as_os_str()).entered();
+    let write_started = Instant::now();
+    let outfile = File::create(leaderboard_file_path.as_path()).with_context(|| {
+        format!(
+            "Failed to create file: {:?}",
+            leaderboard_file_path.as_os_str()
+        )
+    })?;
+    crate::writing::write_leaderboard(BufWriter::new(outfile), leaderboard, report_format)?;
+    tracing::info!(
+        "the leaderboard of the top {} users has been saved",
+        leaderboard_size
     );
+    drop(write_span);
+    metrics.add_stage_duration("write", write_started.elapsed());
+    manifest.add_timing("merge_and_write", merging_started.elapsed());
+
+    if verify {
+        let _verify_span = tracing::info_span!("verify").entered();
+        let verifying_started = Instant::now();
+        let log_file_paths = common_utils::date_utils::last_seven_days_ymd()
+            .into_iter()
+            .map(|datestamp| {
+                common_utils::file_utils::session_log_file_path(from_path.clone(), datestamp)
+            })
+            .collect();
+        let reference = crate::verifying::reference_reports(
+            log_file_paths,
+            report_format,
+            duplicate_session_policy,
+            BoundarySessionsTracker::DEFAULT_WINDOW,
+            nil_ids,
+        )?;
+        crate::verifying::compare_with_reference(&output_file_path, &reference.top_10)?;
+        crate::verifying::compare_with_reference(&summary_file_path, &reference.summaries)?;
+        tracing::info!("the reports match the in-memory reference implementation");
+        manifest.add_timing("verify", verifying_started.elapsed());
+        metrics.add_stage_duration("verify", verifying_started.elapsed());
+    }
+
+    for report_path in [
+        &output_file_path,
+        &summary_file_path,
+        &leaderboard_file_path,
+    ] {
+        manifest
+            .add_output(report_path)
+            .with_context(|| format!("Failed to hash the report {:?}", report_path.as_os_str()))?;
+    }
     Ok(())
 }
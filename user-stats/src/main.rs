@@ -1,4 +1,7 @@
 use anyhow::{Context, Result};
+use common_utils::{
+    logging::LogFormat, parsing_utils::NilIdRule, report_utils::ReportFormat,
+};
 use structopt::StructOpt;
 
 /// Produces a text file containing the top 10 number of pics per user in sessions over the last seven days.
@@ -12,10 +15,81 @@ struct Cli {
     /// We will attempt to write this directory if it does not already exist.
     #[structopt(parse(from_os_str))]
     to_path: std::path::PathBuf,
+
+    /// The format of the resulting file: "pipe" writes user_top_10_YYYYMMDD.txt, "jsonl" writes user_top_10_YYYYMMDD.jsonl and "parquet" writes user_top_10_YYYYMMDD.parquet
+    #[structopt(long, default_value = "pipe", possible_values = &["pipe", "jsonl", "parquet"])]
+    format: ReportFormat,
+
+    /// The number of users with the most pics over the last seven days to write to user_leaderboard_YYYYMMDD.txt
+    #[structopt(long, default_value = "1000")]
+    leaderboard_size: usize,
+
+    /// How the pics of a session found more than once for the same user over the last seven days are combined: "sum" treats the
+    /// occurrences as parts of a session that crossed midnight and "max" as copies of the same session, e.g. from a log that was delivered again
+    #[structopt(long, default_value = "sum", possible_values = &["sum", "max"])]
+    duplicate_sessions: user_stats::DuplicateSessionPolicy,
+
+    /// Recompute the top 10 and summary reports with a naive in-memory implementation and fail if they differ from the written reports.
+    /// All sessions of the last seven days are loaded into memory, hence this is only meant for inputs that fit in memory
+    #[structopt(long)]
+    verify: bool,
+
+    /// What to do with records whose user or session id is the nil UUID: "accept" processes them like any other record,
+    /// "reject" fails on the first log containing them and "quarantine" skips them and writes them to ./quarantined_records
+    #[structopt(long, default_value = "accept", possible_values = &["accept", "reject", "quarantine"])]
+    nil_ids: NilIdRule,
+
+    /// Wait for another run using the same state directory to finish instead of failing immediately
+    #[structopt(long, conflicts_with = "no-wait")]
+    wait: bool,
+
+    /// Fail immediately if another run is using the same state directory. This is the default
+    #[structopt(long)]
+    no_wait: bool,
+
+    /// Write the metrics of the run to this file in the Prometheus text format, e.g. to the textfile directory of the node_exporter.
+    /// The file is written whether the run succeeds or fails
+    #[structopt(long, parse(from_os_str))]
+    metrics_file: Option<std::path::PathBuf>,
+
+    /// The number of unprocessed session logs that are processed concurrently, e.g. to catch up after an outage.
+    /// The memory budget of the run is split between them
+    #[structopt(long, default_value = "1")]
+    jobs: usize,
+
+    /// Keep running and regenerate the reports whenever a session log of the last seven days has been completed, i.e. once a
+    /// safari-sessions-YYYYMMDD.log.done marker has been written next to it or once it has stopped growing. Stop with Ctrl-C
+    #[structopt(long)]
+    watch: bool,
+
+    /// With --watch, the number of seconds a session log without a marker must not grow for to be considered complete
+    #[structopt(long, default_value = "60")]
+    settle_seconds: u64,
+
+    /// Log more details, e.g. the batches written to disk. Can be repeated
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Log less, i.e. only warnings. Can be repeated to only log errors or nothing at all
+    #[structopt(short, long, parse(from_occurrences))]
+    quiet: u8,
+
+    /// The format of the logs written to stderr: "text" is human-readable and "json" writes one JSON object per line.
+    /// Every stage of a run (parse, which also sorts the records into batches, persist, merge, write and verify with --verify) is logged
+    /// with its duration when it ends
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    log_format: LogFormat,
 }
 
 fn main() -> Result<()> {
     let args = Cli::from_args();
+    common_utils::logging::init(
+        common_utils::logging::level_filter(args.verbose, args.quiet),
+        args.log_format,
+    );
+    // Ctrl-C (or SIGTERM) cancels the run gracefully, removing temporary and partially written files.
+    common_utils::cancellation::install_handler()
+        .with_context(|| "Failed to install the signal handler")?;
     let (from_path, to_path) = (args.from_path, args.to_path);
     if !to_path.exists() {
         std::fs::create_dir_all(to_path.as_path()).with_context(|| {
@@ -37,5 +111,33 @@ fn main() -> Result<()> {
             )
         })?;
     }
-    user_stats::run(from_path, to_path)
+    let mut options = user_stats::RunOptions {
+        report_format: args.format,
+        leaderboard_size: args.leaderboard_size,
+        duplicate_session_policy: args.duplicate_sessions,
+        verify: args.verify,
+        nil_ids: args.nil_ids,
+        wait_for_lock: args.wait && !args.no_wait,
+        metrics_file: args.metrics_file,
+        jobs: args.jobs,
+        completed_logs: None,
+    };
+    if args.watch {
+        common_utils::watching::watch(
+            from_path.clone(),
+            std::time::Duration::from_secs(args.settle_seconds),
+            |completed_logs| {
+                options.completed_logs = Some(completed_logs.clone());
+                user_stats::run(from_path.clone(), to_path.clone(), &options)
+            },
+        )
+        .with_context(|| {
+            format!(
+                "Failed to watch the session log directory: {:?}",
+                from_path.as_os_str()
+            )
+        })
+    } else {
+        user_stats::run(from_path, to_path, &options)
+    }
 }
@@ -1,20 +1,27 @@
-use crate::parsing::UserSessionRecord;
+use crate::{deduplicating::ClampedSessions, parsing::UserSessionRecord};
 use rayon::prelude::*;
-use std::ops::AddAssign;
 
+// Sums of pics that exceed u8::MAX are clamped, and the pairs this happened to are added to the given clamped sessions.
 pub(crate) fn sort_collect_splitted_user_records(
     record_pairs: &mut Vec<(UserSessionRecord, u8)>,
+    clamped_sessions: &mut ClampedSessions,
 ) {
     record_pairs.par_sort_unstable_by(|(record_x, _num_pics_x), (record_y, _num_pics_y)| {
         record_x.cmp(record_y)
     });
-    collect_sorted_splitted_user_records(record_pairs);
+    collect_sorted_splitted_user_records(record_pairs, clamped_sessions);
 }
 
-fn collect_sorted_splitted_user_records(record_pairs: &mut Vec<(UserSessionRecord, u8)>) {
+fn collect_sorted_splitted_user_records(
+    record_pairs: &mut Vec<(UserSessionRecord, u8)>,
+    clamped_sessions: &mut ClampedSessions,
+) {
     record_pairs.dedup_by(|(record_x, num_pics_x), (record_y, num_pics_y)| {
         if record_y == record_x {
-            num_pics_y.add_assign(*num_pics_x);
+            *num_pics_y = num_pics_y.checked_add(*num_pics_x).unwrap_or_else(|| {
+                clamped_sessions.insert((record_y.user_id, record_y.session_id));
+                u8::MAX
+            });
             true
         } else {
             false
@@ -51,10 +58,29 @@ mod tests {
             (UserSessionRecord::new(2, 200), 3u8),
             (UserSessionRecord::new(3, 100), 0u8),
         ];
-        sort_collect_splitted_user_records(&mut user_session_records_nb_pics_pairs);
+        let mut clamped_sessions = ClampedSessions::new();
+        sort_collect_splitted_user_records(
+            &mut user_session_records_nb_pics_pairs,
+            &mut clamped_sessions,
+        );
         assert_eq!(
             sorted_and_collected_pairs,
             user_session_records_nb_pics_pairs
         );
+        assert!(clamped_sessions.is_empty());
+
+        let mut overflowing_pairs = vec![
+            (UserSessionRecord::new(1, 100), 200u8),
+            (UserSessionRecord::new(1, 100), 100u8),
+        ];
+        sort_collect_splitted_user_records(&mut overflowing_pairs, &mut clamped_sessions);
+        assert_eq!(
+            vec![(UserSessionRecord::new(1, 100), u8::MAX)],
+            overflowing_pairs
+        );
+        assert_eq!(
+            ClampedSessions::from([(Uuid::from_u128(1), Uuid::from_u128(100))]),
+            clamped_sessions
+        );
     }
 }
@@ -0,0 +1,134 @@
+// This module contains the summary metrics of a user over a number of days: the number of sessions played, the total number of pics
+// and the best session. These are mergeable, hence we can compute them per day and merge the daily summaries over the last seven days.
+//
+// The daily summaries are stored as FST maps (next to the daily FST sets of the top 10 sessions). Since the values of an FST map are
+// restricted to u64, the best session id is part of the key: the keys are of the form [user_id, best_session_id] and the values
+// encode the number of sessions, the total number of pics and the number of pics in the best session (see UserSummary::to_fst_value).
+// The keys of a user hence all share the user id as a prefix, so the union of the daily maps yields all the daily summaries of a user
+// one after the other.
+use std::ops::AddAssign;
+
+use anyhow::{ensure, Result};
+use uuid::Uuid;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct UserSummary {
+    // The number of session-days the user played: a session is counted once for every day it appears in,
+    // hence a session spanning midnight counts twice.
+    pub(crate) sessions: u64,
+    // The total number of pics taken by the user.
+    pub(crate) total_pics: u64,
+    // The session in which the user took the most pics together with that number of pics.
+    // Ties are broken in favour of the smallest session id, just like in the top 10 sessions.
+    pub(crate) best_session: Option<(Uuid, u8)>,
+}
+
+impl UserSummary {
+    // The number of bits the number of sessions, the total number of pics and the best number of pics take up in the FST value.
+    const SESSIONS_BITS: u32 = 24;
+    const TOTAL_PICS_BITS: u32 = 32;
+    const BEST_PICS_BITS: u32 = 8;
+
+    pub(crate) fn add_session(&mut self, session_id: Uuid, sum_pics: u8) {
+        self.sessions += 1;
+        self.total_pics += sum_pics as u64;
+        self.update_best_session(session_id, sum_pics);
+    }
+
+    pub(crate) fn mean_pics_per_session(&self) -> Option<f64> {
+        if self.sessions == 0 {
+            None
+        } else {
+            Some(self.total_pics as f64 / self.sessions as f64)
+        }
+    }
+
+    fn update_best_session(&mut self, session_id: Uuid, sum_pics: u8) {
+        let is_improvement = match self.best_session {
+            None => true,
+            Some((best_session_id, best_pics)) => {
+                sum_pics > best_pics || (sum_pics == best_pics && session_id < best_session_id)
+            }
+        };
+        if is_improvement {
+            self.best_session = Some((session_id, sum_pics));
+        }
+    }
+
+    // The key of the summary of the given user in the daily FST map: [user_id, best_session_id].
+    // Returns None if the user has not played any sessions.
+    pub(crate) fn to_fst_key(self, user_id: &Uuid) -> Option<[u8; 32]> {
+        self.best_session.map(|(best_session_id, _)| {
+            let mut key = [0u8; 32];
+            key[..16].copy_from_slice(user_id.as_bytes());
+            key[16..].copy_from_slice(best_session_id.as_bytes());
+            key
+        })
+    }
+
+    // Encodes the summary as [sessions (24 bits), total_pics (32 bits), best pics (8 bits)]. The best session id is part of the key.
+    // This is only meant for daily summaries, where the number of sessions and pics of a single user are far below these limits.
+    pub(crate) fn to_fst_value(self) -> Result<u64> {
+        ensure!(
+            self.sessions < 1 << Self::SESSIONS_BITS
+                && self.total_pics < 1 << Self::TOTAL_PICS_BITS,
+            "The summary {:?} is too large to be stored in an FST map",
+            self
+        );
+        let best_pics = self.best_session.map_or(0, |(_, best_pics)| best_pics);
+        Ok(
+            self.sessions << (Self::TOTAL_PICS_BITS + Self::BEST_PICS_BITS)
+                | self.total_pics << Self::BEST_PICS_BITS
+                | best_pics as u64,
+        )
+    }
+
+    // Decodes a summary from an entry of a daily FST map, i.e. the inverse of to_fst_key (without the user id) and to_fst_value.
+    pub(crate) fn from_fst_entry(key: &[u8], value: u64) -> Self {
+        let mut best_session_id = [0u8; 16];
+        best_session_id.copy_from_slice(&key[16..32]);
+        Self {
+            sessions: value >> (Self::TOTAL_PICS_BITS + Self::BEST_PICS_BITS),
+            total_pics: (value >> Self::BEST_PICS_BITS) & ((1 << Self::TOTAL_PICS_BITS) - 1),
+            best_session: Some((Uuid::from_bytes(best_session_id), value as u8)),
+        }
+    }
+}
+
+impl AddAssign for UserSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.sessions += other.sessions;
+        self.total_pics += other.total_pics;
+        if let Some((session_id, sum_pics)) = other.best_session {
+            self.update_best_session(session_id, sum_pics);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summaries_round_trip_through_fst_entries_and_merge() {
+        let user_id = Uuid::from_u128(1);
+        let mut monday = UserSummary::default();
+        monday.add_session(Uuid::from_u128(30), 4);
+        monday.add_session(Uuid::from_u128(20), 7);
+        let mut tuesday = UserSummary::default();
+        tuesday.add_session(Uuid::from_u128(10), 7);
+
+        let key = monday.to_fst_key(&user_id).unwrap();
+        assert_eq!(user_id.as_bytes(), &key[..16]);
+        let decoded = UserSummary::from_fst_entry(&key, monday.to_fst_value().unwrap());
+        assert_eq!(monday, decoded);
+
+        let mut merged = decoded;
+        merged += tuesday;
+        assert_eq!(3, merged.sessions);
+        assert_eq!(18, merged.total_pics);
+        assert_eq!(Some(6.0), merged.mean_pics_per_session());
+        // Both days have a session with 7 pics, the smallest session id wins.
+        assert_eq!(Some((Uuid::from_u128(10), 7)), merged.best_session);
+    }
+}
@@ -0,0 +1,432 @@
+// This module contains a naive in-memory implementation of the top 10 and summary reports, which serves as a reference for the
+// FST based implementation in crate::fst_utils (see --verify). Every (user, session) pair of the last seven days is loaded into
+// HashMaps, hence this is only meant for inputs that fit in memory.
+//
+// The reference is computed straight from the records of the session logs, but follows the documented semantics of the reports rather
+// than their exact definitions over seven days: the pics of a session are summed per day and capped at u8::MAX, only the daily top 10
+// of every user is kept, except for the boundary sessions of a day (see BoundarySessionsTracker), which are kept in full so that the
+// parts of a session crossing midnight can be stitched together. The days a session is kept for are combined according to the
+// duplicate session policy, capping sums at u8::MAX again. Apart from the formatting of the lines, nothing is shared with the FST
+// based implementation.
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use common_utils::{
+    parquet_utils::ParquetReportWriter,
+    parsing_utils::{BoundarySessionsTracker, NilIdRule, Record},
+    report_utils::{JsonLine, ReportFormat},
+};
+use safari_stats_reports::{UserReportLine, UserSession};
+use uuid::Uuid;
+
+use crate::{
+    deduplicating::DuplicateSessionPolicy,
+    summarizing::UserSummary,
+    writing::{UserSummaryRecord, UserSummaryWriter},
+};
+
+// The reports produced by the reference implementation, in the same format as the files written by crate::fst_utils::finalizing.
+pub(crate) struct ReferenceReports {
+    pub(crate) top_10: Vec<u8>,
+    pub(crate) summaries: Vec<u8>,
+}
+
+// The summary of a user over the session-days of the last seven days.
+#[derive(Default)]
+struct NaiveSummary {
+    sessions: u64,
+    total_pics: u64,
+    // The session-day with the most pics, ties go to the smallest session id.
+    best_session: Option<(Uuid, u8)>,
+}
+
+// Computes the reports from the given session log files with the reference implementation. The sessions among the first or the
+// last boundary_window records of a log are its boundary sessions.
+pub(crate) fn reference_reports(
+    log_file_paths: Vec<PathBuf>,
+    report_format: ReportFormat,
+    duplicate_session_policy: DuplicateSessionPolicy,
+    boundary_window: usize,
+    nil_ids: NilIdRule,
+) -> Result<ReferenceReports> {
+    // The pics of every session of a user on each day it is kept for, i.e. the days it made the daily top 10 or was a boundary session.
+    let mut kept_pics_by_user: HashMap<Uuid, HashMap<Uuid, Vec<u8>>> = HashMap::new();
+    let mut summary_by_user: HashMap<Uuid, NaiveSummary> = HashMap::new();
+    for log_file_path in log_file_paths {
+        let (pics_of_the_day, boundary_sessions) =
+            pics_of_the_day(&log_file_path, boundary_window, nil_ids)?;
+        let mut sessions_by_user: HashMap<Uuid, Vec<(Uuid, u8)>> = HashMap::new();
+        for ((user_id, session_id), nb_pics) in pics_of_the_day {
+            let nb_pics = u8::try_from(nb_pics).unwrap_or(u8::MAX);
+            let summary = summary_by_user.entry(user_id).or_default();
+            summary.sessions += 1;
+            summary.total_pics += nb_pics as u64;
+            let is_best = match summary.best_session {
+                None => true,
+                Some((best_id, best_pics)) => {
+                    nb_pics > best_pics || (nb_pics == best_pics && session_id < best_id)
+                }
+            };
+            if is_best {
+                summary.best_session = Some((session_id, nb_pics));
+            }
+            sessions_by_user
+                .entry(user_id)
+                .or_default()
+                .push((session_id, nb_pics));
+        }
+        for (user_id, sessions) in sessions_by_user {
+            let (boundary, mut top_10_of_the_day): (Vec<_>, Vec<_>) = sessions
+                .into_iter()
+                .partition(|(session_id, _)| boundary_sessions.contains(session_id));
+            top_10_of_the_day
+                .sort_by(|(id_a, pics_a), (id_b, pics_b)| pics_b.cmp(pics_a).then(id_a.cmp(id_b)));
+            top_10_of_the_day.truncate(10);
+            let kept_pics = kept_pics_by_user.entry(user_id).or_default();
+            for (session_id, nb_pics) in top_10_of_the_day.into_iter().chain(boundary) {
+                kept_pics.entry(session_id).or_default().push(nb_pics);
+            }
+        }
+    }
+    let mut user_ids: Vec<Uuid> = summary_by_user.keys().copied().collect();
+    user_ids.sort_unstable();
+
+    let mut top_10_lines = Vec::with_capacity(user_ids.len());
+    for user_id in user_ids.iter() {
+        let mut sessions: Vec<(Uuid, u8)> = kept_pics_by_user[user_id]
+            .iter()
+            .map(|(session_id, kept_pics)| {
+                let nb_pics = match duplicate_session_policy {
+                    DuplicateSessionPolicy::Sum => {
+                        let sum = kept_pics.iter().map(|nb_pics| *nb_pics as u64).sum::<u64>();
+                        u8::try_from(sum).unwrap_or(u8::MAX)
+                    }
+                    DuplicateSessionPolicy::Max => kept_pics.iter().copied().max().unwrap_or(0),
+                };
+                (*session_id, nb_pics)
+            })
+            .collect();
+        sessions.sort_by(|(id_a, pics_a), (id_b, pics_b)| pics_b.cmp(pics_a).then(id_a.cmp(id_b)));
+        sessions.truncate(10);
+        top_10_lines.push(UserReportLine {
+            user_id: *user_id,
+            sessions: sessions
+                .into_iter()
+                .map(|(session_id, nb_pics)| UserSession {
+                    session_id,
+                    nb_pics,
+                })
+                .collect(),
+        });
+    }
+    let top_10 = write_top_10(&top_10_lines, report_format)?;
+
+    let mut summaries = Vec::new();
+    let mut summary_writer = UserSummaryWriter::new(&mut summaries, report_format)?;
+    for user_id in user_ids.iter() {
+        let summary = &summary_by_user[user_id];
+        let summary = UserSummary {
+            sessions: summary.sessions,
+            total_pics: summary.total_pics,
+            best_session: summary.best_session,
+        };
+        summary_writer.write(&UserSummaryRecord::new(*user_id, summary))?;
+    }
+    summary_writer.finish()?;
+    Ok(ReferenceReports { top_10, summaries })
+}
+
+// Writes the top 10 sessions of every user in the given report format, one line (or one row per session in the Parquet case) per user.
+fn write_top_10(lines: &[UserReportLine], report_format: ReportFormat) -> Result<Vec<u8>> {
+    let mut top_10 = Vec::new();
+    match report_format {
+        ReportFormat::Parquet => {
+            let mut parquet_writer = ParquetReportWriter::new(
+                &mut top_10,
+                common_utils::date_utils::today_ymd(),
+                common_utils::date_utils::NUMBER_OF_DAYS_IN_WINDOW,
+            )?;
+            for line in lines {
+                let user_id = line.user_id.to_string();
+                for (idx, session) in line.sessions.iter().enumerate() {
+                    parquet_writer.write_row(
+                        user_id.as_str(),
+                        idx + 1,
+                        &session.session_id,
+                        session.nb_pics as f64,
+                    )?;
+                }
+            }
+            parquet_writer.close()?;
+        }
+        ReportFormat::JsonLines => {
+            for line in lines {
+                writeln!(top_10, "{}", JsonLine(line))?;
+            }
+        }
+        ReportFormat::Pipe => {
+            for line in lines {
+                writeln!(top_10, "{}", line)?;
+            }
+        }
+    }
+    Ok(top_10)
+}
+
+// The number of pics of every (user, session) pair in a session log file.
+type PicsByUserSession = HashMap<(Uuid, Uuid), u64>;
+
+// Sums up the pics of every (user, session) pair in the given session log file and finds its boundary sessions.
+// Records with a nil id are skipped unless they are accepted, a rejected log has already failed the run before we get here.
+fn pics_of_the_day(
+    log_file_path: &Path,
+    boundary_window: usize,
+    nil_ids: NilIdRule,
+) -> Result<(PicsByUserSession, HashSet<Uuid>)> {
+    const BUFFER_CAPACITY: usize = 8 * 2usize.pow(10);
+    let reader = common_utils::parsing_utils::customised_csv_reader(log_file_path, BUFFER_CAPACITY)
+        .with_context(|| {
+            format!(
+                "Failed to create a csv reader for the session log file: {:?}",
+                log_file_path.as_os_str()
+            )
+        })?;
+    let mut boundary_sessions_tracker = BoundarySessionsTracker::new(boundary_window);
+    let mut pics_by_user_session = PicsByUserSession::new();
+    for record in reader
+        .into_deserialize::<Record>()
+        .filter_map(Result::ok)
+        .filter(|record| nil_ids == NilIdRule::Accept || !record.has_nil_id())
+    {
+        boundary_sessions_tracker.observe(record.session_id);
+        *pics_by_user_session
+            .entry((record.user_id, record.session_id))
+            .or_default() += record.nb_pics as u64;
+    }
+    Ok((pics_by_user_session, boundary_sessions_tracker.finish()))
+}
+
+// Compares the report written to the given file with the report of the reference implementation.
+// Reports in a text format are compared line by line, so that the first differing line can be reported.
+pub(crate) fn compare_with_reference(report_file_path: &Path, expected: &[u8]) -> Result<()> {
+    let actual = std::fs::read(report_file_path).with_context(|| {
+        format!(
+            "Could not read the report: {:?}",
+            report_file_path.as_os_str()
+        )
+    })?;
+    if actual == expected {
+        return Ok(());
+    }
+    if let (Ok(actual), Ok(expected)) =
+        (std::str::from_utf8(&actual), std::str::from_utf8(expected))
+    {
+        let (actual_lines, expected_lines): (Vec<_>, Vec<_>) =
+            (actual.lines().collect(), expected.lines().collect());
+        for (idx, (actual_line, expected_line)) in
+            actual_lines.iter().zip(expected_lines.iter()).enumerate()
+        {
+            if actual_line != expected_line {
+                bail!(
+                    "{:?} differs from the reference implementation on line {}. Expected: {} Found: {}",
+                    report_file_path.as_os_str(),
+                    idx + 1,
+                    expected_line,
+                    actual_line
+                );
+            }
+        }
+        bail!(
+            "{:?} differs from the reference implementation. Expected {} lines, found {}",
+            report_file_path.as_os_str(),
+            expected_lines.len(),
+            actual_lines.len()
+        );
+    }
+    bail!(
+        "{:?} differs from the reference implementation",
+        report_file_path.as_os_str()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_utils::{manifest::SourceDigest, parsing_utils::RecordValidator};
+    use std::io::Write;
+
+    // Writes the given days of log lines to session log files in the given directory, the oldest first.
+    fn write_logs(dir: &Path, days: &[Vec<String>]) -> Result<Vec<PathBuf>> {
+        let mut log_file_paths = Vec::new();
+        for (day, log_lines) in days.iter().enumerate() {
+            let log_file_path = dir.join(format!("safari-sessions-2021010{}.log", day + 1));
+            let mut log_file = std::fs::File::create(&log_file_path)?;
+            for line in log_lines {
+                writeln!(log_file, "{}", line)?;
+            }
+            log_file_paths.push(log_file_path);
+        }
+        Ok(log_file_paths)
+    }
+
+    // Runs the FST based implementation on the given days of log lines and compares its reports with the reference implementation.
+    fn verify_logs(
+        days: &[Vec<String>],
+        boundary_window: usize,
+        duplicate_session_policy: DuplicateSessionPolicy,
+        nil_ids: NilIdRule,
+    ) -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log_file_paths = write_logs(dir.path(), days)?;
+        let (mut set_paths, mut summary_paths, mut boundary_paths) =
+            (Vec::new(), Vec::new(), Vec::new());
+        for (day, log_file_path) in log_file_paths.iter().enumerate() {
+            let (set_path, summary_path, boundary_path) = (
+                dir.path().join(format!("top-10-{}.fst", day)),
+                dir.path().join(format!("summary-{}.fst", day)),
+                dir.path().join(format!("boundary-{}.fst", day)),
+            );
+            let temporary_fst_dir_path = dir.path().join(format!("temporary_fsts_{}", day));
+            let (boundary_sessions, mut clamped_sessions) =
+                crate::fst_utils::batching::from_log_file_to_batched_fst_maps(
+                    log_file_path,
+                    temporary_fst_dir_path.clone(),
+                    4,
+                    0.5,
+                    boundary_window,
+                    &mut RecordValidator::new(nil_ids, dir.path().join("quarantined.log")),
+                    &SourceDigest::default(),
+                )?;
+            crate::fst_utils::storing::from_batched_fst_maps_to_fst_set(
+                temporary_fst_dir_path,
+                set_path.clone(),
+                summary_path.clone(),
+                boundary_path.clone(),
+                &boundary_sessions,
+                &mut clamped_sessions,
+            )?;
+            set_paths.push(set_path);
+            summary_paths.push(summary_path);
+            boundary_paths.push(boundary_path);
+        }
+        let (top_10_report_path, summary_report_path) = (
+            dir.path().join("top_10.txt"),
+            dir.path().join("summary.txt"),
+        );
+        crate::fst_utils::finalizing::from_fst_sets_to_stats_file(
+            set_paths,
+            boundary_paths,
+            top_10_report_path.clone(),
+            ReportFormat::Pipe,
+            duplicate_session_policy,
+        )?;
+        crate::fst_utils::finalizing::from_summary_fst_maps_to_summary_file(
+            summary_paths,
+            summary_report_path.clone(),
+            ReportFormat::Pipe,
+            10,
+        )?;
+        let reference = reference_reports(
+            log_file_paths,
+            ReportFormat::Pipe,
+            duplicate_session_policy,
+            boundary_window,
+            nil_ids,
+        )?;
+        compare_with_reference(&top_10_report_path, &reference.top_10)?;
+        compare_with_reference(&summary_report_path, &reference.summaries)
+    }
+
+    // Two days of a user with more than 10 sessions, one of which crosses midnight, and a user exceeding u8::MAX pics in a day.
+    fn two_days() -> Vec<Vec<String>> {
+        let record = |user_id: u128, session_id: u128, nb_pics: u128| {
+            format!(
+                "{},{},1,{}",
+                Uuid::from_u128(user_id),
+                Uuid::from_u128(session_id),
+                nb_pics
+            )
+        };
+        let mut first_day = vec![record(2, 300, 1)];
+        // The session 110 only comes 11th on the first day.
+        first_day.extend((0..11u128).map(|idx| record(1, 100 + idx, 20 - idx)));
+        first_day.push(record(3, 500, 200));
+        first_day.push(record(3, 500, 200));
+        first_day.push(record(1, 200, 30));
+        let second_day = vec![
+            record(1, 200, 5),
+            record(1, 110, 15),
+            record(3, 500, 100),
+            record(2, 400, 1),
+        ];
+        vec![first_day, second_day]
+    }
+
+    #[test]
+    fn fst_reports_match_the_reference() {
+        // user_id,session_id,camera_id,nb_pics for 3 users with 12 sessions each, the last user included.
+        let log_lines: Vec<String> = (0..36u128)
+            .map(|idx| {
+                format!(
+                    "{},{},1,{}",
+                    Uuid::from_u128(1 + idx % 3),
+                    Uuid::from_u128(100 + idx),
+                    idx % 7
+                )
+            })
+            .collect();
+        // Without boundary sessions every session competes for the daily top 10.
+        let days = [log_lines];
+        verify_logs(&days, 0, DuplicateSessionPolicy::Sum, NilIdRule::Accept).unwrap();
+        verify_logs(&days, 5, DuplicateSessionPolicy::Sum, NilIdRule::Accept).unwrap();
+        for policy in [DuplicateSessionPolicy::Sum, DuplicateSessionPolicy::Max] {
+            verify_logs(&two_days(), 1, policy, NilIdRule::Accept).unwrap();
+        }
+    }
+
+    #[test]
+    fn reference_keeps_the_daily_top_10_and_stitches_boundary_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let reference = reference_reports(
+            write_logs(dir.path(), &two_days()).unwrap(),
+            ReportFormat::Pipe,
+            DuplicateSessionPolicy::Sum,
+            1,
+            NilIdRule::Accept,
+        )
+        .unwrap();
+        let top_10 = String::from_utf8(reference.top_10).unwrap();
+        // The pics of the session 110 on the first day did not make the daily top 10, while both parts of the session 200 are kept.
+        assert!(top_10.contains(&format!("{}:15,", Uuid::from_u128(110))));
+        assert!(top_10.contains(&format!("{}:35,", Uuid::from_u128(200))));
+        // The session 500 has 400 pics on the first day, which are capped like the sum over both days.
+        assert!(top_10.contains(&format!(
+            "{}|{}:255,\n",
+            Uuid::from_u128(3),
+            Uuid::from_u128(500)
+        )));
+    }
+
+    #[test]
+    fn nil_user_ids_are_real_users_unless_quarantined() {
+        // the nil user id sorts before every other user id, hence it is the very first user seen by the FST loops.
+        let log_lines: Vec<String> = (0..24u128)
+            .map(|idx| {
+                format!(
+                    "{},{},1,{}",
+                    Uuid::from_u128(idx % 2),
+                    Uuid::from_u128(100 + idx),
+                    1 + idx % 5
+                )
+            })
+            .collect();
+        let days = [log_lines];
+        verify_logs(&days, 0, DuplicateSessionPolicy::Sum, NilIdRule::Accept).unwrap();
+        verify_logs(&days, 3, DuplicateSessionPolicy::Sum, NilIdRule::Quarantine).unwrap();
+    }
+}
@@ -1,23 +1,54 @@
-use itertools::Itertools;
-use std::fmt::{Display, Formatter};
+use anyhow::{Context, Result};
+use common_utils::{
+    parquet_utils::{ParquetReportWriter, ParquetTableWriter, ParquetValue},
+    report_utils::{JsonLine, ReportFormat},
+};
+use safari_stats_reports::{UserReportLine, UserSession};
+use serde::{Serialize, Serializer};
+use std::{
+    fmt::{Display, Formatter},
+    io::{BufWriter, Write},
+};
 use uuid::Uuid;
 
+use crate::{
+    leaderboard::{LeaderboardEntry, UserLeaderboard},
+    summarizing::UserSummary,
+};
+
 #[derive(PartialEq, Debug)]
 pub(crate) struct UserBestStats {
     pub(crate) user_id: Uuid,
     pub(crate) session_id_num_pics_pairs: Vec<(Uuid, u8)>,
 }
 
+// The formatting of the user stats (in both the pipe and the JSON Lines format) is delegated to the safari-stats-reports crate,
+// so that consumers of "user_top_10_YYYYMMDD.txt" can read back exactly what we write.
+impl From<&UserBestStats> for UserReportLine {
+    fn from(user_best_stats: &UserBestStats) -> Self {
+        Self {
+            user_id: user_best_stats.user_id,
+            sessions: user_best_stats
+                .session_id_num_pics_pairs
+                .iter()
+                .map(|(session_id, nb_pics)| UserSession {
+                    session_id: *session_id,
+                    nb_pics: *nb_pics,
+                })
+                .collect(),
+        }
+    }
+}
+
 impl Display for UserBestStats {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let write_string = std::iter::once(format!("{}|", self.user_id))
-            .chain(
-                self.session_id_num_pics_pairs
-                    .iter()
-                    .map(|(session_id, nb_pics)| format!("{}:{},", session_id, nb_pics)),
-            )
-            .join("");
-        write!(f, "{}", write_string)
+        write!(f, "{}", UserReportLine::from(self))
+    }
+}
+
+impl Serialize for UserBestStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        UserReportLine::from(self).serialize(serializer)
     }
 }
 
@@ -29,3 +60,458 @@ impl Default for UserBestStats {
         }
     }
 }
+
+// Writes UserBestStats to the "user_top_10_YYYYMMDD" file in one of the supported report formats.
+pub(crate) enum UserStatsWriter<W: Write + Send> {
+    // One line per user, either in the pipe or the JSON Lines format.
+    Lines(BufWriter<W>, ReportFormat),
+    // One row per (user, session) pair.
+    Parquet(Box<ParquetReportWriter<BufWriter<W>>>),
+}
+
+impl<W: Write + Send> UserStatsWriter<W> {
+    pub(crate) fn new(writer: W, report_format: ReportFormat) -> Result<Self> {
+        let buf_writer = BufWriter::new(writer);
+        match report_format {
+            ReportFormat::Parquet => {
+                let parquet_writer = ParquetReportWriter::new(
+                    buf_writer,
+                    common_utils::date_utils::today_ymd(),
+                    common_utils::date_utils::NUMBER_OF_DAYS_IN_WINDOW,
+                )
+                .with_context(|| "Failed to create a Parquet writer")?;
+                Ok(UserStatsWriter::Parquet(Box::new(parquet_writer)))
+            }
+            _ => Ok(UserStatsWriter::Lines(buf_writer, report_format)),
+        }
+    }
+
+    pub(crate) fn write(&mut self, user_best_stats: &UserBestStats) -> Result<()> {
+        match self {
+            UserStatsWriter::Lines(buf_writer, ReportFormat::JsonLines) => {
+                writeln!(buf_writer, "{}", JsonLine(user_best_stats))
+                    .with_context(|| format!("Failed writing {} to file", user_best_stats))
+            }
+            UserStatsWriter::Lines(buf_writer, _) => writeln!(buf_writer, "{}", user_best_stats)
+                .with_context(|| format!("Failed writing {} to file", user_best_stats)),
+            UserStatsWriter::Parquet(parquet_writer) => {
+                let user_id = user_best_stats.user_id.to_string();
+                for (idx, (session_id, nb_pics)) in
+                    user_best_stats.session_id_num_pics_pairs.iter().enumerate()
+                {
+                    parquet_writer
+                        .write_row(user_id.as_str(), idx + 1, session_id, *nb_pics as f64)
+                        .with_context(|| format!("Failed writing {} to file", user_best_stats))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Flushes all remaining bytes (and the footer in the Parquet case) to the underlying writer.
+    pub(crate) fn finish(self) -> Result<()> {
+        match self {
+            UserStatsWriter::Lines(mut buf_writer, _) => buf_writer
+                .flush()
+                .with_context(|| "Failed flushing all bytes".to_string()),
+            UserStatsWriter::Parquet(parquet_writer) => parquet_writer
+                .close()
+                .with_context(|| "Failed to finish writing the Parquet file".to_string()),
+        }
+    }
+}
+
+// This struct corresponds to a line in the file "user_summary_YYYYMMDD.txt" file.
+// In the pipe format a line is of the form
+// user_id|sessions|total_pics|mean_pics_per_session|best_session_id:best_nb_pics
+// The sessions are session-days: a session spanning midnight is counted once for each day it appears in.
+// In the Parquet format every line is a row with the columns of USER_SUMMARY_PARQUET_SCHEMA.
+#[derive(PartialEq, Debug)]
+pub(crate) struct UserSummaryRecord {
+    user_id: Uuid,
+    summary: UserSummary,
+}
+
+// The schema of the user summary report in the Parquet format. A user without sessions has no mean and no best session.
+const USER_SUMMARY_PARQUET_SCHEMA: &str = "
+message user_summary {
+    REQUIRED BYTE_ARRAY user_id (UTF8);
+    REQUIRED INT64 sessions;
+    REQUIRED INT64 total_pics;
+    OPTIONAL DOUBLE mean_pics_per_session;
+    OPTIONAL BYTE_ARRAY best_session_id (UTF8);
+    OPTIONAL INT32 best_nb_pics;
+    REQUIRED BYTE_ARRAY report_date (UTF8);
+    REQUIRED INT32 window_days;
+}
+";
+
+impl UserSummaryRecord {
+    pub(crate) fn new(user_id: Uuid, summary: UserSummary) -> Self {
+        Self { user_id, summary }
+    }
+
+    // The row of this record in the Parquet format (see USER_SUMMARY_PARQUET_SCHEMA).
+    fn parquet_row(&self, report_date: &str, window_days: usize) -> Vec<ParquetValue> {
+        let (best_session_id, best_nb_pics) = match self.summary.best_session {
+            Some((session_id, nb_pics)) => (
+                ParquetValue::Utf8(session_id.to_string()),
+                ParquetValue::Int32(nb_pics as i32),
+            ),
+            None => (ParquetValue::Null, ParquetValue::Null),
+        };
+        vec![
+            ParquetValue::Utf8(self.user_id.to_string()),
+            ParquetValue::Int64(self.summary.sessions as i64),
+            ParquetValue::Int64(self.summary.total_pics as i64),
+            self.summary
+                .mean_pics_per_session()
+                .map_or(ParquetValue::Null, ParquetValue::Double),
+            best_session_id,
+            best_nb_pics,
+            ParquetValue::Utf8(report_date.to_string()),
+            ParquetValue::Int32(window_days as i32),
+        ]
+    }
+}
+
+impl Display for UserSummaryRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}|{}|{}|{}|",
+            self.user_id,
+            self.summary.sessions,
+            self.summary.total_pics,
+            self.summary.mean_pics_per_session().unwrap_or(0.0)
+        )?;
+        if let Some((session_id, nb_pics)) = self.summary.best_session {
+            write!(f, "{}:{}", session_id, nb_pics)?;
+        }
+        Ok(())
+    }
+}
+
+// The shape of the best session in the JSON Lines format.
+#[derive(Serialize)]
+struct BestSession {
+    session_id: Uuid,
+    nb_pics: u8,
+}
+
+// The shape of a line in the JSON Lines format.
+#[derive(Serialize)]
+struct UserSummaryLine {
+    user_id: Uuid,
+    sessions: u64,
+    total_pics: u64,
+    mean_pics_per_session: Option<f64>,
+    best_session: Option<BestSession>,
+}
+
+impl Serialize for UserSummaryRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        UserSummaryLine {
+            user_id: self.user_id,
+            sessions: self.summary.sessions,
+            total_pics: self.summary.total_pics,
+            mean_pics_per_session: self.summary.mean_pics_per_session(),
+            best_session: self
+                .summary
+                .best_session
+                .map(|(session_id, nb_pics)| BestSession {
+                    session_id,
+                    nb_pics,
+                }),
+        }
+        .serialize(serializer)
+    }
+}
+
+// Writes UserSummaryRecords to the "user_summary_YYYYMMDD" file in one of the supported report formats.
+pub(crate) enum UserSummaryWriter<W: Write + Send> {
+    // One line per user, either in the pipe or the JSON Lines format.
+    Lines(BufWriter<W>, ReportFormat),
+    // One row per user, together with the date of the report.
+    Parquet(Box<ParquetTableWriter<BufWriter<W>>>, String),
+}
+
+impl<W: Write + Send> UserSummaryWriter<W> {
+    pub(crate) fn new(writer: W, report_format: ReportFormat) -> Result<Self> {
+        let buf_writer = BufWriter::new(writer);
+        match report_format {
+            ReportFormat::Parquet => {
+                let parquet_writer =
+                    ParquetTableWriter::new(buf_writer, USER_SUMMARY_PARQUET_SCHEMA)
+                        .with_context(|| "Failed to create a Parquet writer")?;
+                let report_date = common_utils::date_utils::today_ymd().into_string();
+                Ok(UserSummaryWriter::Parquet(
+                    Box::new(parquet_writer),
+                    report_date,
+                ))
+            }
+            _ => Ok(UserSummaryWriter::Lines(buf_writer, report_format)),
+        }
+    }
+
+    pub(crate) fn write(&mut self, record: &UserSummaryRecord) -> Result<()> {
+        match self {
+            UserSummaryWriter::Lines(buf_writer, ReportFormat::JsonLines) => {
+                writeln!(buf_writer, "{}", JsonLine(record))
+                    .with_context(|| format!("Failed writing {} to file", record))
+            }
+            UserSummaryWriter::Lines(buf_writer, _) => writeln!(buf_writer, "{}", record)
+                .with_context(|| format!("Failed writing {} to file", record)),
+            UserSummaryWriter::Parquet(parquet_writer, report_date) => parquet_writer
+                .write_row(record.parquet_row(
+                    report_date,
+                    common_utils::date_utils::NUMBER_OF_DAYS_IN_WINDOW,
+                ))
+                .with_context(|| format!("Failed writing {} to file", record)),
+        }
+    }
+
+    // Flushes all remaining bytes (and the footer in the Parquet case) to the underlying writer.
+    pub(crate) fn finish(self) -> Result<()> {
+        match self {
+            UserSummaryWriter::Lines(mut buf_writer, _) => buf_writer
+                .flush()
+                .with_context(|| "Failed flushing all bytes".to_string()),
+            UserSummaryWriter::Parquet(parquet_writer, _) => parquet_writer
+                .close()
+                .with_context(|| "Failed to finish writing the Parquet file".to_string()),
+        }
+    }
+}
+
+// This struct corresponds to a line in the file "user_leaderboard_YYYYMMDD.txt" file.
+// In the pipe format a line is of the form
+// rank|user_id|total_pics|sessions
+// In the Parquet format every line is a row with the columns of USER_LEADERBOARD_PARQUET_SCHEMA.
+#[derive(PartialEq, Debug, Serialize)]
+pub(crate) struct LeaderboardRecord {
+    rank: usize,
+    user_id: Uuid,
+    total_pics: u64,
+    sessions: u64,
+}
+
+// The schema of the user leaderboard in the Parquet format.
+const USER_LEADERBOARD_PARQUET_SCHEMA: &str = "
+message user_leaderboard {
+    REQUIRED INT32 rank;
+    REQUIRED BYTE_ARRAY user_id (UTF8);
+    REQUIRED INT64 total_pics;
+    REQUIRED INT64 sessions;
+    REQUIRED BYTE_ARRAY report_date (UTF8);
+    REQUIRED INT32 window_days;
+}
+";
+
+impl LeaderboardRecord {
+    pub(crate) fn new(rank: usize, entry: LeaderboardEntry) -> Self {
+        Self {
+            rank,
+            user_id: entry.user_id,
+            total_pics: entry.total_pics,
+            sessions: entry.sessions,
+        }
+    }
+
+    // The row of this record in the Parquet format (see USER_LEADERBOARD_PARQUET_SCHEMA).
+    fn parquet_row(&self, report_date: &str, window_days: usize) -> Vec<ParquetValue> {
+        vec![
+            ParquetValue::Int32(self.rank as i32),
+            ParquetValue::Utf8(self.user_id.to_string()),
+            ParquetValue::Int64(self.total_pics as i64),
+            ParquetValue::Int64(self.sessions as i64),
+            ParquetValue::Utf8(report_date.to_string()),
+            ParquetValue::Int32(window_days as i32),
+        ]
+    }
+}
+
+impl Display for LeaderboardRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}|{}|{}|{}",
+            self.rank, self.user_id, self.total_pics, self.sessions
+        )
+    }
+}
+
+// Writes the leaderboard, best user first, to the "user_leaderboard_YYYYMMDD" file in the given report format.
+pub(crate) fn write_leaderboard<W: Write + Send>(
+    mut buf_writer: BufWriter<W>,
+    leaderboard: UserLeaderboard,
+    report_format: ReportFormat,
+) -> Result<()> {
+    let records = leaderboard
+        .into_ranked_entries()
+        .into_iter()
+        .enumerate()
+        .map(|(idx, entry)| LeaderboardRecord::new(idx + 1, entry));
+    if report_format == ReportFormat::Parquet {
+        let mut parquet_writer =
+            ParquetTableWriter::new(buf_writer, USER_LEADERBOARD_PARQUET_SCHEMA)
+                .with_context(|| "Failed to create a Parquet writer")?;
+        let report_date = common_utils::date_utils::today_ymd().into_string();
+        for record in records {
+            parquet_writer
+                .write_row(record.parquet_row(
+                    &report_date,
+                    common_utils::date_utils::NUMBER_OF_DAYS_IN_WINDOW,
+                ))
+                .with_context(|| format!("Failed writing {} to file", record))?;
+        }
+        return parquet_writer
+            .close()
+            .with_context(|| "Failed to finish writing the Parquet file".to_string());
+    }
+    for record in records {
+        match report_format {
+            ReportFormat::JsonLines => writeln!(buf_writer, "{}", JsonLine(&record)),
+            _ => writeln!(buf_writer, "{}", record),
+        }
+        .with_context(|| format!("Failed writing {} to file", record))?;
+    }
+    buf_writer
+        .flush()
+        .with_context(|| "Failed flushing all bytes".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_utils::report_utils::JsonLine;
+
+    #[test]
+    fn pipe_and_json_line_agree() {
+        let user_best_stats = UserBestStats {
+            user_id: Uuid::from_u128(1),
+            session_id_num_pics_pairs: vec![(Uuid::from_u128(20), 4), (Uuid::from_u128(10), 2)],
+        };
+        assert_eq!(
+            format!(
+                "{}|{}:4,{}:2,",
+                Uuid::from_u128(1),
+                Uuid::from_u128(20),
+                Uuid::from_u128(10)
+            ),
+            user_best_stats.to_string()
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&JsonLine(&user_best_stats).to_string()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "user_id": Uuid::from_u128(1),
+                "sessions": [
+                    {"session_id": Uuid::from_u128(20), "nb_pics": 4, "rank": 1},
+                    {"session_id": Uuid::from_u128(10), "nb_pics": 2, "rank": 2},
+                ]
+            }),
+            json
+        );
+    }
+
+    #[test]
+    fn summary_record_is_formatted() {
+        let mut summary = UserSummary::default();
+        summary.add_session(Uuid::from_u128(20), 4);
+        summary.add_session(Uuid::from_u128(10), 1);
+        let record = UserSummaryRecord::new(Uuid::from_u128(1), summary);
+        assert_eq!(
+            format!("{}|2|5|2.5|{}:4", Uuid::from_u128(1), Uuid::from_u128(20)),
+            record.to_string()
+        );
+        let json: serde_json::Value = serde_json::from_str(&JsonLine(&record).to_string()).unwrap();
+        assert_eq!(
+            serde_json::json!({"session_id": Uuid::from_u128(20), "nb_pics": 4}),
+            json["best_session"]
+        );
+        assert_eq!(2.5, json["mean_pics_per_session"]);
+    }
+
+    #[test]
+    fn summary_records_are_written_as_parquet_rows() {
+        use parquet::{
+            file::reader::{FileReader, SerializedFileReader},
+            record::RowAccessor,
+        };
+
+        let mut summary = UserSummary::default();
+        summary.add_session(Uuid::from_u128(20), 4);
+        let mut file = tempfile::tempfile().unwrap();
+        let mut writer =
+            UserSummaryWriter::new(file.try_clone().unwrap(), ReportFormat::Parquet).unwrap();
+        writer
+            .write(&UserSummaryRecord::new(Uuid::from_u128(1), summary))
+            .unwrap();
+        writer
+            .write(&UserSummaryRecord::new(
+                Uuid::from_u128(2),
+                UserSummary::default(),
+            ))
+            .unwrap();
+        writer.finish().unwrap();
+
+        std::io::Seek::rewind(&mut file).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(2, rows.len());
+        assert_eq!(
+            Uuid::from_u128(1).to_string(),
+            *rows[0].get_string(0).unwrap()
+        );
+        assert_eq!(1, rows[0].get_long(1).unwrap());
+        assert_eq!(
+            Uuid::from_u128(20).to_string(),
+            *rows[0].get_string(4).unwrap()
+        );
+        assert_eq!(4, rows[0].get_int(5).unwrap());
+        // A user without sessions has no best session.
+        assert!(rows[1].get_string(4).is_err());
+    }
+
+    #[test]
+    fn leaderboard_is_written_as_parquet_rows() {
+        use parquet::{
+            file::reader::{FileReader, SerializedFileReader},
+            record::RowAccessor,
+        };
+
+        let mut leaderboard = UserLeaderboard::new(10);
+        for (user_id, nb_pics) in [(1u128, 3u8), (2, 7)] {
+            let mut summary = UserSummary::default();
+            summary.add_session(Uuid::from_u128(20), nb_pics);
+            leaderboard.push(Uuid::from_u128(user_id), &summary);
+        }
+        let mut file = tempfile::tempfile().unwrap();
+        write_leaderboard(
+            BufWriter::new(file.try_clone().unwrap()),
+            leaderboard,
+            ReportFormat::Parquet,
+        )
+        .unwrap();
+
+        std::io::Seek::rewind(&mut file).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(2, rows.len());
+        assert_eq!(1, rows[0].get_int(0).unwrap());
+        assert_eq!(
+            Uuid::from_u128(2).to_string(),
+            *rows[0].get_string(1).unwrap()
+        );
+        assert_eq!(7, rows[0].get_long(2).unwrap());
+    }
+}